@@ -0,0 +1,64 @@
+// times the interpreter on small programs that stress one part of it each;
+// run with cargo bench, optionally naming the benchmarks to run
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+const RUNS: usize = 5;
+
+const BENCHMARKS: &[(&str, &str)] = &[
+    (
+        "numeric-loop",
+        "(define (count-to n acc) (if (= n 0) acc (count-to (- n 1) (+ acc 1))))
+         (count-to 300000 0)",
+    ),
+    (
+        "calls",
+        "(define (add3 a b c) (+ a (+ b c)))
+         (define (fib n) (if (< n 2) n (add3 0 (fib (- n 1)) (fib (- n 2)))))
+         (fib 22)",
+    ),
+    (
+        "closures",
+        "(define (make-adder n) (lambda (x) (+ x n)))
+         (define (sum-adders i acc)
+           (cond ((= i 0) acc) (else (sum-adders (- i 1) ((make-adder i) acc)))))
+         (sum-adders 100000 0)",
+    ),
+];
+
+// the fastest of several runs of a program read from stdin
+fn time(source: &str) -> Duration {
+    (0..RUNS)
+        .map(|_| {
+            let start = Instant::now();
+            let mut child = Command::new(env!("CARGO_BIN_EXE_scheme"))
+                .arg("-")
+                .stdin(Stdio::piped())
+                .stdout(Stdio::null())
+                .spawn()
+                .unwrap();
+            child
+                .stdin
+                .take()
+                .unwrap()
+                .write_all(source.as_bytes())
+                .unwrap();
+            assert!(child.wait().unwrap().success());
+            start.elapsed()
+        })
+        .min()
+        .unwrap()
+}
+
+fn main() {
+    let filters: Vec<String> = std::env::args()
+        .skip(1)
+        .filter(|a| !a.starts_with('-'))
+        .collect();
+    for (name, source) in BENCHMARKS {
+        if filters.is_empty() || filters.iter().any(|f| name.contains(f.as_str())) {
+            println!("{:<16} {:?}", name, time(source));
+        }
+    }
+}
@@ -0,0 +1,165 @@
+use std::rc::Rc;
+
+use crate::{syntax, Expr, Heap, SpecialForm};
+
+// a closure's body is analysed once, when the closure is made, into nodes that
+// record what each form is, so that calling the closure doesn't classify its
+// forms all over again every time. anything the analysis doesn't cover, or can
+// tell is malformed, stays a form for the evaluator, so it goes wrong in the
+// same way and at the same time as it would have without analysis.
+// a macro use looks like a call until its operator has been evaluated, so a
+// call keeps its form to expand if the operator turns out to be a macro
+
+#[derive(Debug)]
+pub(crate) struct Code {
+    // the body the nodes were made from, which keeps the data in them alive
+    pub(crate) body: Expr,
+    pub(crate) nodes: Vec<Rc<Node>>,
+}
+
+// analysed code is only ever the same as itself
+impl PartialEq for Code {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+impl Eq for Code {}
+
+#[derive(Debug)]
+pub(crate) enum Node {
+    // a self-evaluating value or a quoted datum
+    Constant(Expr),
+    Variable(Expr),
+    If(Rc<Node>, Rc<Node>, Rc<Node>),
+    And(Vec<Rc<Node>>),
+    Or(Vec<Rc<Node>>),
+    Define(Expr, Rc<Node>),
+    Set(Expr, Rc<Node>),
+    // the parameters of a lambda and its body, analysed along with the body around it
+    Lambda(Expr, Rc<Code>),
+    Cond(Vec<Clause>),
+    // the operator, the arguments and the whole form
+    Call(Rc<Node>, Vec<Rc<Node>>, Expr),
+    Form(Expr),
+}
+
+#[derive(Debug)]
+pub(crate) struct Clause {
+    // the test, or None for an else clause
+    pub(crate) test: Option<Rc<Node>>,
+    pub(crate) body: Vec<Rc<Node>>,
+    // whether the body has definitions, and so needs an environment of its own
+    pub(crate) scoped: bool,
+}
+
+pub(crate) fn analyse_body(heap: &Heap, body: &Expr) -> Code {
+    let nodes = list_items(heap, body)
+        .unwrap_or_default()
+        .iter()
+        .map(|form| analyse(heap, form))
+        .collect();
+    Code {
+        body: body.clone(),
+        nodes,
+    }
+}
+
+fn analyse(heap: &Heap, form: &Expr) -> Rc<Node> {
+    Rc::new(match form {
+        Expr::Symbol(_) | Expr::Alias(_) => Node::Variable(form.clone()),
+        Expr::Pair(_) => analyse_form(heap, form).unwrap_or_else(|| Node::Form(form.clone())),
+        _ => Node::Constant(form.clone()),
+    })
+}
+
+// the elements of a proper list, or None for anything else
+fn list_items(heap: &Heap, list: &Expr) -> Option<Vec<Expr>> {
+    if !heap.is_proper_list(list).ok()? {
+        return None;
+    }
+    let mut items = Vec::new();
+    let mut v = list;
+    while v.is_pair() {
+        let (first, rest) = heap.first_rest_ref(v).ok()?;
+        items.push(first.clone());
+        v = rest;
+    }
+    Some(items)
+}
+
+fn analyse_all(heap: &Heap, forms: &[Expr]) -> Vec<Rc<Node>> {
+    forms.iter().map(|form| analyse(heap, form)).collect()
+}
+
+// the node for a compound form, or None to leave it to the evaluator
+fn analyse_form(heap: &Heap, form: &Expr) -> Option<Node> {
+    let items = list_items(heap, form)?;
+    let (head, args) = items.split_first()?;
+    let rest = heap.get_rest(form).ok()?;
+    Some(match (SpecialForm::of(head), args) {
+        // a quoted alias becomes a symbol each time the quote is evaluated
+        (Some(SpecialForm::Quote), [datum]) if !syntax::has_aliases(heap, datum).ok()? => {
+            Node::Constant(datum.clone())
+        }
+        (Some(SpecialForm::If), [test, consequent, alternative]) => Node::If(
+            analyse(heap, test),
+            analyse(heap, consequent),
+            analyse(heap, alternative),
+        ),
+        (Some(SpecialForm::And), _) => Node::And(analyse_all(heap, args)),
+        (Some(SpecialForm::Or), _) => Node::Or(analyse_all(heap, args)),
+        (Some(SpecialForm::Define), [name, value]) if name.is_identifier() => {
+            Node::Define(name.clone(), analyse(heap, value))
+        }
+        (Some(SpecialForm::Define), [signature @ Expr::Pair(_), _]) => {
+            // (define (name . params) body) defines name as a lambda
+            let (name, params) = heap.get_first_rest(signature).ok()?;
+            if !name.is_identifier() {
+                return None;
+            }
+            let body = heap.get_rest(&rest).ok()?;
+            let lambda = Node::Lambda(params, Rc::new(analyse_body(heap, &body)));
+            Node::Define(name, Rc::new(lambda))
+        }
+        (Some(SpecialForm::Set), [name, value]) if name.is_identifier() => {
+            Node::Set(name.clone(), analyse(heap, value))
+        }
+        (Some(SpecialForm::Lambda), [params, _]) => {
+            let body = heap.get_rest(&rest).ok()?;
+            Node::Lambda(params.clone(), Rc::new(analyse_body(heap, &body)))
+        }
+        (Some(SpecialForm::Cond), _) => Node::Cond(analyse_clauses(heap, args)?),
+        (Some(_), _) => return None,
+        (None, _) => Node::Call(analyse(heap, head), analyse_all(heap, args), form.clone()),
+    })
+}
+
+// cond clauses, unless any of them uses => or is malformed
+fn analyse_clauses(heap: &Heap, clauses: &[Expr]) -> Option<Vec<Clause>> {
+    let mut analysed = Vec::new();
+    for (i, clause) in clauses.iter().enumerate() {
+        let items = list_items(heap, clause)?;
+        let (test, body) = items.split_first()?;
+        if body
+            .first()
+            .is_some_and(|form| form.is_specific_symbol("=>"))
+        {
+            return None;
+        }
+        let test = if test.is_specific_symbol("ELSE") {
+            if body.is_empty() || i + 1 < clauses.len() {
+                return None;
+            }
+            None
+        } else {
+            Some(analyse(heap, test))
+        };
+        analysed.push(Clause {
+            test,
+            body: analyse_all(heap, body),
+            scoped: heap.has_definitions(&heap.get_rest(clause).ok()?).ok()?,
+        });
+    }
+    Some(analysed)
+}
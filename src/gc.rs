@@ -0,0 +1,256 @@
+use std::collections::HashSet;
+use std::rc::Rc;
+
+use crate::{ConsCellKey, Expr, Heap, SResult};
+
+// the least work an incremental step does, in cells marked or swept
+const MIN_STEP: usize = 1000;
+
+// a tri-colour collector: a cell is white while its mark is clear, grey once it
+// is marked and waiting in the grey list, and black after its children have been
+// shaded. cells stored into while marking are shaded straight away, so a black
+// cell never points at a white one. hash tables and vectors have no such barrier,
+// so every one found is scanned again, along with the roots, before sweeping
+pub(crate) enum Phase {
+    Idle,
+    Mark,
+    // cells below this index have been swept
+    Sweep(usize),
+}
+
+pub(crate) struct Gc {
+    pub(crate) phase: Phase,
+    // collect_garbage does a bounded step at a time unless this is false
+    pub(crate) incremental: bool,
+    grey: Vec<Expr>,
+    // the hash tables and vectors scanned so far in this cycle
+    containers: Vec<Expr>,
+    seen_tables: HashSet<usize>,
+    seen_vectors: HashSet<usize>,
+    // the allocation count when the last step finished
+    allocations: usize,
+}
+
+impl Gc {
+    pub(crate) fn new() -> Self {
+        Gc {
+            phase: Phase::Idle,
+            incremental: true,
+            grey: Vec::new(),
+            containers: Vec::new(),
+            seen_tables: HashSet::new(),
+            seen_vectors: HashSet::new(),
+            allocations: 0,
+        }
+    }
+
+    fn abandon_cycle(&mut self) {
+        self.phase = Phase::Idle;
+        self.grey.clear();
+        self.containers.clear();
+        self.seen_tables.clear();
+        self.seen_vectors.clear();
+    }
+}
+
+impl Heap {
+    fn roots(&self) -> Vec<Expr> {
+        let mut roots = vec![
+            self.symbols.clone(),
+            self.root_env.clone(),
+            self.current_input_port.clone(),
+            self.current_output_port.clone(),
+        ];
+        roots.extend(self.winders.iter().cloned());
+        roots.extend(self.handlers.iter().cloned());
+        roots.extend(self.temp_roots.iter().cloned());
+        for (param, old) in &self.dynamic_bindings {
+            roots.push(param.clone());
+            roots.push(old.clone());
+        }
+        roots
+    }
+
+    // whether a new cell should start out marked: always while marking, and
+    // during sweeping only if the sweep has yet to reach it
+    pub(crate) fn allocate_marked(&self) -> bool {
+        match self.gc.phase {
+            Phase::Idle => false,
+            Phase::Mark => true,
+            Phase::Sweep(swept) => self.cells.vacant_key() >= swept,
+        }
+    }
+
+    // the write barrier: anything stored into a cell while marking turns grey
+    pub(crate) fn write_barrier(&mut self, value: &Expr) {
+        if let Phase::Mark = self.gc.phase {
+            self.shade(value);
+        }
+    }
+
+    fn shade(&mut self, expr: &Expr) {
+        match expr {
+            Expr::Pair(n)
+            | Expr::Closure(n)
+            | Expr::Promise(n)
+            | Expr::Parameter(n)
+            | Expr::Environment(n)
+            | Expr::Record(n)
+            | Expr::Values(n)
+            | Expr::Condition(n)
+            | Expr::Macro(n) => {
+                let cell = self.cells.get_mut(n.0).unwrap();
+                if !cell.2 {
+                    cell.2 = true;
+                    self.gc.grey.push(expr.clone());
+                }
+            }
+            Expr::HashTable(_) | Expr::Vector(_) => self.gc.grey.push(expr.clone()),
+            Expr::Code(code) => self.shade(&code.body),
+            Expr::Alias(alias) => {
+                self.shade(&alias.original);
+                self.shade(&alias.env);
+            }
+            _ => (),
+        }
+    }
+
+    fn shade_contents(&mut self, container: &Expr) {
+        match container {
+            Expr::HashTable(t) => {
+                let entries: Vec<_> = t.borrow().entries().cloned().collect();
+                for (k, v) in entries {
+                    self.shade(&k);
+                    self.shade(&v);
+                }
+            }
+            Expr::Vector(v) => {
+                let items = v.borrow().clone();
+                for item in &items {
+                    self.shade(item);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    // blackens up to budget grey values, or all of them, returning true when
+    // none are left
+    fn mark(&mut self, budget: Option<usize>) -> bool {
+        let mut done = 0;
+        while budget.is_none_or(|b| done < b) {
+            let Some(expr) = self.gc.grey.pop() else {
+                return true;
+            };
+            done += 1;
+            match &expr {
+                Expr::Pair(n)
+                | Expr::Closure(n)
+                | Expr::Promise(n)
+                | Expr::Parameter(n)
+                | Expr::Environment(n)
+                | Expr::Record(n)
+                | Expr::Values(n)
+                | Expr::Condition(n)
+                | Expr::Macro(n) => {
+                    let (first, rest, _) = self.cells.get(n.0).unwrap().clone();
+                    self.shade(&first);
+                    self.shade(&rest);
+                }
+                Expr::HashTable(t) if self.gc.seen_tables.insert(Rc::as_ptr(t) as usize) => {
+                    self.gc.containers.push(expr.clone());
+                    self.shade_contents(&expr);
+                }
+                Expr::Vector(v) if self.gc.seen_vectors.insert(Rc::as_ptr(v) as usize) => {
+                    self.gc.containers.push(expr.clone());
+                    self.shade_contents(&expr);
+                }
+                _ => (),
+            }
+        }
+        self.gc.grey.is_empty()
+    }
+
+    // frees the unmarked cells from start up to end and clears the marks of the rest
+    fn sweep(&mut self, start: usize, end: usize) {
+        for i in start..end {
+            if let Some(cell) = self.cells.get_mut(i) {
+                if cell.2 {
+                    cell.2 = false;
+                } else {
+                    self.cells.remove(i);
+                }
+            }
+        }
+    }
+
+    // the collection the REPL does after each line: a step of an incremental
+    // collection, doing more work the more was allocated, or a full collection
+    pub(crate) fn collect_garbage(&mut self) {
+        if !self.gc.incremental {
+            self.collect();
+            return;
+        }
+        let budget = MIN_STEP.max(2 * (self.allocations - self.gc.allocations));
+        match self.gc.phase {
+            Phase::Idle => {
+                self.gc.phase = Phase::Mark;
+                for root in self.roots() {
+                    self.shade(&root);
+                }
+            }
+            Phase::Mark => {
+                if self.mark(Some(budget)) {
+                    // the roots and containers may have changed since they were
+                    // scanned, so they are scanned again before finishing
+                    for root in self.roots() {
+                        self.shade(&root);
+                    }
+                    for container in std::mem::take(&mut self.gc.containers) {
+                        self.shade_contents(&container);
+                    }
+                    self.mark(None);
+                    self.gc.abandon_cycle();
+                    self.gc.phase = Phase::Sweep(0);
+                }
+            }
+            Phase::Sweep(swept) => {
+                let end = (swept + budget).min(self.cells.capacity());
+                self.sweep(swept, end);
+                self.gc.phase = if end == self.cells.capacity() {
+                    Phase::Idle
+                } else {
+                    Phase::Sweep(end)
+                };
+            }
+        }
+        self.gc.allocations = self.allocations;
+    }
+
+    // a full collection, which abandons any incremental one in progress
+    pub(crate) fn collect(&mut self) {
+        self.gc.abandon_cycle();
+        for (_, c) in self.cells.iter_mut() {
+            c.2 = false;
+        }
+        for root in self.roots() {
+            self.shade(&root);
+        }
+        self.mark(None);
+        self.gc.abandon_cycle();
+        self.sweep(0, self.cells.capacity());
+        self.gc.allocations = self.allocations;
+    }
+
+    #[allow(dead_code)]
+    pub(crate) fn dump(&self) -> SResult<()> {
+        for (k, _) in self.cells.iter() {
+            println!(
+                "cell {}: {}",
+                k,
+                self.format_expr(&Expr::Pair(ConsCellKey(k)))?
+            )
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,105 @@
+use std::collections::HashMap;
+
+use crate::{Expr, Heap, SResult};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Equality {
+    Eq,
+    Equal,
+}
+
+#[derive(Debug)]
+pub(crate) struct HashTable {
+    equality: Equality,
+    buckets: HashMap<u64, Vec<(Expr, Expr)>>,
+}
+
+// tables are mutable objects, so two tables are only the same if they are the same object
+impl PartialEq for HashTable {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+impl Eq for HashTable {}
+
+impl HashTable {
+    pub(crate) fn new(equality: Equality) -> Self {
+        Self {
+            equality,
+            buckets: HashMap::new(),
+        }
+    }
+
+    fn hash(&self, heap: &Heap, key: &Expr) -> u64 {
+        match self.equality {
+            Equality::Eq => key.eq_hash(),
+            Equality::Equal => heap.equal_hash(key),
+        }
+    }
+
+    fn matches(&self, heap: &Heap, a: &Expr, b: &Expr) -> SResult<bool> {
+        match self.equality {
+            Equality::Eq => Ok(a.is_eq(b)),
+            Equality::Equal => heap.is_equal(a, b),
+        }
+    }
+
+    fn position(&self, heap: &Heap, bucket: &[(Expr, Expr)], key: &Expr) -> SResult<Option<usize>> {
+        for (i, (k, _)) in bucket.iter().enumerate() {
+            if self.matches(heap, k, key)? {
+                return Ok(Some(i));
+            }
+        }
+        Ok(None)
+    }
+
+    pub(crate) fn get(&self, heap: &Heap, key: &Expr) -> SResult<Option<Expr>> {
+        let h = self.hash(heap, key);
+        match self.buckets.get(&h) {
+            Some(bucket) => {
+                let pos = self.position(heap, bucket, key)?;
+                Ok(pos.map(|i| bucket[i].1.clone()))
+            }
+            None => Ok(None),
+        }
+    }
+
+    pub(crate) fn insert(&mut self, heap: &Heap, key: Expr, value: Expr) -> SResult<()> {
+        let h = self.hash(heap, &key);
+        let pos = match self.buckets.get(&h) {
+            Some(bucket) => self.position(heap, bucket, &key)?,
+            None => None,
+        };
+        let bucket = self.buckets.entry(h).or_default();
+        match pos {
+            Some(i) => bucket[i].1 = value,
+            None => bucket.push((key, value)),
+        }
+        Ok(())
+    }
+
+    pub(crate) fn remove(&mut self, heap: &Heap, key: &Expr) -> SResult<()> {
+        let h = self.hash(heap, key);
+        let pos = match self.buckets.get(&h) {
+            Some(bucket) => self.position(heap, bucket, key)?,
+            None => None,
+        };
+        if let Some(i) = pos {
+            let bucket = self.buckets.get_mut(&h).unwrap();
+            bucket.remove(i);
+            if bucket.is_empty() {
+                self.buckets.remove(&h);
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.buckets.values().map(Vec::len).sum()
+    }
+
+    pub(crate) fn entries(&self) -> impl Iterator<Item = &(Expr, Expr)> {
+        self.buckets.values().flatten()
+    }
+}
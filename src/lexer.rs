@@ -1,35 +1,94 @@
+use std::iter::Peekable;
+
+use crate::parser::ParseError;
+
 pub(crate) enum Token {
     LBracket,
     RBracket,
     Dot,
     Tick,
+    Str(String),
+    Symbol(String),
     Value(String),
 }
 
-pub(crate) fn tokenize(input: &str) -> Vec<Token> {
+pub(crate) fn tokenize(input: &str) -> Result<Vec<Token>, ParseError> {
     let mut result = Vec::new();
     let mut iter = input.chars().peekable();
+    while let Some(token) = next_token(&mut iter)? {
+        result.push(token);
+    }
+    Ok(result)
+}
 
+// reads one token, leaving any delimiter that ended it unconsumed
+pub(crate) fn next_token(
+    iter: &mut Peekable<impl Iterator<Item = char>>,
+) -> Result<Option<Token>, ParseError> {
     while let Some(ch) = iter.next() {
-        match ch {
+        let token = match ch {
             c if c.is_whitespace() => continue,
-            '(' => result.push(Token::LBracket),
-            ')' => result.push(Token::RBracket),
-            '.' => result.push(Token::Dot),
-            '\'' => result.push(Token::Tick),
+            '(' => Token::LBracket,
+            ')' => Token::RBracket,
+            // a dot only stands alone between delimiters, so ... and .5 are values
+            '.' if iter.peek().is_none_or(|&c| is_delimiter(c)) => Token::Dot,
+            '\'' => Token::Tick,
+            '"' => Token::Str(read_delimited(iter, '"')?),
+            '|' => Token::Symbol(read_delimited(iter, '|')?),
             _ => {
                 let mut s = String::new();
                 s.push(ch);
-                while iter.peek().is_some()
-                    && !iter.peek().unwrap().is_whitespace()
-                    && *iter.peek().unwrap() != '('
-                    && *iter.peek().unwrap() != ')'
-                {
+                if ch == '#' && iter.peek() == Some(&'\\') {
+                    // character literals like #\( may contain a delimiter
+                    s.push(iter.next().unwrap());
+                    if let Some(c) = iter.next() {
+                        s.push(c);
+                    }
+                }
+                while iter.peek().is_some_and(|&c| !is_delimiter(c)) && !is_label_definition(&s) {
                     s.push(iter.next().unwrap())
                 }
-                result.push(Token::Value(s))
+                Token::Value(s)
             }
+        };
+        return Ok(Some(token));
+    }
+    Ok(None)
+}
+
+// reads the rest of a string or |symbol| up to the closing delimiter, handling escapes
+fn read_delimited(
+    iter: &mut impl Iterator<Item = char>,
+    delimiter: char,
+) -> Result<String, ParseError> {
+    let unterminated = if delimiter == '"' {
+        ParseError::UnterminatedString
+    } else {
+        ParseError::UnterminatedSymbol
+    };
+    let mut s = String::new();
+    loop {
+        match iter.next() {
+            None => return Err(unterminated),
+            Some(c) if c == delimiter => return Ok(s),
+            Some('\\') => match iter.next() {
+                None => return Err(unterminated),
+                Some('n') => s.push('\n'),
+                Some('t') => s.push('\t'),
+                Some(c) => s.push(c),
+            },
+            Some(c) => s.push(c),
         }
     }
-    result
+}
+
+fn is_delimiter(c: char) -> bool {
+    c.is_whitespace() || c == '(' || c == ')'
+}
+
+fn is_label_definition(s: &str) -> bool {
+    // a datum label like #0= ends the token even if the datum follows directly
+    s.strip_prefix('#')
+        .and_then(|s| s.strip_suffix('='))
+        .is_some_and(|n| !n.is_empty() && n.chars().all(|c| c.is_ascii_digit()))
 }
@@ -1,26 +1,136 @@
-use std::{io::BufRead, ops::Deref, rc::Rc};
+use std::{
+    cell::RefCell,
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
+    fmt,
+    hash::{Hash, Hasher},
+    io::{IsTerminal, Write},
+    ops::Deref,
+    rc::Rc,
+    time::Instant,
+};
 
+use analyse::{Code, Node};
+use gc::Gc;
+use hashtable::HashTable;
 use lexer::tokenize;
-use parser::parse_expr;
+use parser::{parse_expr, ParseError};
+use port::Port;
 use primitive::add_primitives;
 use slab::Slab;
+use syntax::Alias;
 
+mod analyse;
+mod gc;
+mod hashtable;
 mod lexer;
 mod parser;
+mod port;
 mod primitive;
+mod record;
+mod syntax;
+#[cfg(test)]
+mod tests;
 
 #[derive(Debug)]
 enum SError {
+    ArityMismatch {
+        expected: Arity,
+        got: usize,
+    },
+    AssertionFailed(String),
     ImproperLambda,
     ImproperList,
     ImproperSymbol,
     ImproperEnvironment,
-    NotCallable,
-    TypeError,
-    UnboundSymbol,
+    ImproperSyntaxRules,
+    DivisionByZero,
+    // exit and emergency-exit unwind to the REPL or script runner as errors
+    EmergencyExit(i32),
+    Exit(i32),
+    // a handler returned from a raise that can't continue
+    HandlerReturned,
+    IndexOutOfRange,
+    // an arithmetic result too big for an integer
+    IntegerOverflow,
+    IoError,
+    // an arithmetic result that would need a fraction
+    NonIntegerResult,
+    // a macro use that none of its syntax-rules patterns match
+    NoMatchingSyntax(String),
+    NotCallable(Expr),
+    // a raised object on its way to a handler, and how to report it if none takes it
+    Raise(Expr, String),
+    ReadError(ParseError),
+    TypeError {
+        expected: &'static str,
+        got: &'static str,
+    },
+    UnboundSymbol(Rc<str>),
     WrongNumberOfArgs,
 }
 
+impl SError {
+    fn type_error(expected: &'static str, got: &Expr) -> Self {
+        SError::TypeError {
+            expected,
+            got: got.type_name(),
+        }
+    }
+}
+
+impl fmt::Display for SError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SError::ArityMismatch { expected, got } => write!(
+                f,
+                "wrong number of arguments: expected {}, got {}",
+                expected, got
+            ),
+            SError::AssertionFailed(s) => write!(f, "assertion failed: {}", s),
+            SError::ImproperLambda => write!(f, "malformed lambda"),
+            SError::ImproperList => write!(f, "improper list"),
+            SError::ImproperSymbol => write!(f, "expected a symbol"),
+            SError::ImproperEnvironment => write!(f, "malformed environment"),
+            SError::ImproperSyntaxRules => write!(f, "malformed syntax-rules"),
+            SError::DivisionByZero => write!(f, "division by zero"),
+            SError::EmergencyExit(status) | SError::Exit(status) => {
+                write!(f, "exit with status {}", status)
+            }
+            SError::HandlerReturned => write!(f, "exception handler returned"),
+            SError::IndexOutOfRange => write!(f, "index out of range"),
+            SError::IntegerOverflow => write!(f, "integer overflow"),
+            SError::IoError => write!(f, "i/o error"),
+            SError::NonIntegerResult => write!(f, "result is not an integer"),
+            SError::NoMatchingSyntax(s) => write!(f, "no syntax rule matches {}", s),
+            SError::NotCallable(e) => write!(f, "not callable: {}", e.type_name()),
+            SError::Raise(_, text) => write!(f, "{}", text),
+            SError::ReadError(e) => write!(f, "read error: {}", e),
+            SError::TypeError { expected, got } => {
+                write!(f, "type error: expected {}, got {}", expected, got)
+            }
+            SError::UnboundSymbol(s) => write!(f, "unbound symbol: {}", s),
+            SError::WrongNumberOfArgs => write!(f, "wrong number of arguments"),
+        }
+    }
+}
+
+impl std::error::Error for SError {}
+
+impl SError {
+    // the status to exit with, if this error is really a request to exit
+    fn exit_status(&self) -> Option<i32> {
+        match self {
+            SError::EmergencyExit(status) | SError::Exit(status) => Some(*status),
+            _ => None,
+        }
+    }
+
+    // whether this is an error that hasn't been through raise yet
+    fn is_unraised(&self) -> bool {
+        !matches!(self, SError::Raise(..)) && self.exit_status().is_none()
+    }
+}
+
 type SResult<T> = Result<T, SError>;
 
 type ConsCell = (Expr, Expr, bool);
@@ -30,21 +140,97 @@ type Native = fn(&Expr, &mut Heap) -> SResult<Expr>;
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 struct ConsCellKey(usize);
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Arity {
+    min: usize,
+    max: Option<usize>,
+}
+
+impl Arity {
+    const fn exact(n: usize) -> Self {
+        Self {
+            min: n,
+            max: Some(n),
+        }
+    }
+
+    const fn at_least(n: usize) -> Self {
+        Self { min: n, max: None }
+    }
+
+    const fn range(min: usize, max: usize) -> Self {
+        Self {
+            min,
+            max: Some(max),
+        }
+    }
+
+    fn check(&self, got: usize) -> SResult<()> {
+        if got < self.min || self.max.is_some_and(|max| got > max) {
+            Err(SError::ArityMismatch {
+                expected: *self,
+                got,
+            })
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl fmt::Display for Arity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.max {
+            Some(max) if max == self.min => write!(f, "{}", max),
+            Some(max) => write!(f, "{} to {}", self.min, max),
+            None => write!(f, "at least {}", self.min),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 struct PrimitiveDef {
     name: String,
+    arity: Arity,
     func: Native,
 }
 
+impl PartialEq for PrimitiveDef {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name && std::ptr::fn_addr_eq(self.func, other.func)
+    }
+}
+
+impl Eq for PrimitiveDef {}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 enum Expr {
     Nil,
+    Unspecified,
     Boolean(bool),
     Integer(i64),
+    Char(char),
+    String(Rc<RefCell<String>>),
     Symbol(Rc<str>),
     Pair(ConsCellKey),
     Closure(ConsCellKey),
+    Promise(ConsCellKey),
+    Parameter(ConsCellKey),
+    Environment(ConsCellKey),
+    Record(ConsCellKey),
+    Values(ConsCellKey),
+    // an error object, whose cell holds (message . irritants)
+    Condition(ConsCellKey),
+    // a syntax-rules macro, whose cell holds (env ellipsis literals rule ...)
+    Macro(ConsCellKey),
+    // an identifier renamed by a macro expansion, which only appears in code
+    Alias(Rc<Alias>),
+    // a closure's analysed body, which only ever appears in the closure's own cell
+    Code(Rc<Code>),
     Primitive(Rc<PrimitiveDef>),
+    HashTable(Rc<RefCell<HashTable>>),
+    Vector(Rc<RefCell<Vec<Expr>>>),
+    Port(Rc<RefCell<Port>>),
+    Eof,
 }
 
 impl Expr {
@@ -60,6 +246,11 @@ impl Expr {
         matches!(self, Self::Symbol(_))
     }
 
+    // a symbol or an alias for one, either of which can name a variable
+    fn is_identifier(&self) -> bool {
+        matches!(self, Self::Symbol(_) | Self::Alias(_))
+    }
+
     fn is_truthy(&self) -> bool {
         // #f is false
         // everything else including 0 and () are true
@@ -67,26 +258,303 @@ impl Expr {
     }
 
     fn is_specific_symbol(&self, s: &str) -> bool {
-        if let Expr::Symbol(sym) = self {
-            sym.as_ref() == s
-        } else {
-            false
+        syntax::base_name(self) == Some(s)
+    }
+
+    fn with_heap<'a>(&'a self, heap: &'a Heap) -> HeapDisplay<'a> {
+        HeapDisplay { heap, expr: self }
+    }
+
+    fn type_name(&self) -> &'static str {
+        match self {
+            Expr::Nil => "empty list",
+            Expr::Unspecified => "unspecified value",
+            Expr::Boolean(_) => "boolean",
+            Expr::Integer(_) => "integer",
+            Expr::Char(_) => "char",
+            Expr::String(_) => "string",
+            Expr::Symbol(_) | Expr::Alias(_) => "symbol",
+            Expr::Pair(_) => "pair",
+            Expr::Closure(_) | Expr::Primitive(_) => "procedure",
+            Expr::Promise(_) => "promise",
+            Expr::Parameter(_) => "parameter",
+            Expr::Environment(_) => "environment",
+            Expr::Record(_) => "record",
+            Expr::Values(_) => "multiple values",
+            Expr::Condition(_) => "error object",
+            Expr::Macro(_) => "macro",
+            Expr::Code(_) => "code",
+            Expr::HashTable(_) => "hash table",
+            Expr::Vector(_) => "vector",
+            Expr::Port(_) => "port",
+            Expr::Eof => "eof object",
+        }
+    }
+
+    fn is_eq(&self, other: &Expr) -> bool {
+        // strings and other mutable objects are only eq? to themselves
+        match (self, other) {
+            (Expr::String(a), Expr::String(b)) => Rc::ptr_eq(a, b),
+            (Expr::Primitive(a), Expr::Primitive(b)) => Rc::ptr_eq(a, b),
+            (Expr::HashTable(a), Expr::HashTable(b)) => Rc::ptr_eq(a, b),
+            (Expr::Vector(a), Expr::Vector(b)) => Rc::ptr_eq(a, b),
+            (Expr::Port(a), Expr::Port(b)) => Rc::ptr_eq(a, b),
+            _ => self == other,
+        }
+    }
+
+    fn eq_hash(&self) -> u64 {
+        let mut h = DefaultHasher::new();
+        std::mem::discriminant(self).hash(&mut h);
+        match self {
+            Expr::Nil | Expr::Unspecified | Expr::Eof => (),
+            Expr::Boolean(b) => b.hash(&mut h),
+            Expr::Integer(n) => n.hash(&mut h),
+            Expr::Char(c) => c.hash(&mut h),
+            Expr::String(s) => Rc::as_ptr(s).hash(&mut h),
+            Expr::Symbol(s) => s.hash(&mut h),
+            Expr::Pair(k)
+            | Expr::Closure(k)
+            | Expr::Promise(k)
+            | Expr::Parameter(k)
+            | Expr::Environment(k)
+            | Expr::Record(k)
+            | Expr::Values(k)
+            | Expr::Condition(k)
+            | Expr::Macro(k) => k.0.hash(&mut h),
+            Expr::Alias(a) => Rc::as_ptr(a).hash(&mut h),
+            Expr::Code(c) => Rc::as_ptr(c).hash(&mut h),
+            Expr::Primitive(p) => Rc::as_ptr(p).hash(&mut h),
+            Expr::HashTable(t) => Rc::as_ptr(t).hash(&mut h),
+            Expr::Vector(v) => Rc::as_ptr(v).hash(&mut h),
+            Expr::Port(p) => Rc::as_ptr(p).hash(&mut h),
+        }
+        h.finish()
+    }
+
+    fn label_key(&self) -> Option<LabelKey> {
+        match self {
+            Expr::Pair(k) => Some(LabelKey::Cell(k.0)),
+            Expr::Vector(v) => Some(LabelKey::Vector(Rc::as_ptr(v))),
+            _ => None,
+        }
+    }
+}
+
+// printing an expression needs the heap its cells live in
+struct HeapDisplay<'a> {
+    heap: &'a Heap,
+    expr: &'a Expr,
+}
+
+impl fmt::Display for HeapDisplay<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = self.heap.format_expr(self.expr).map_err(|_| fmt::Error)?;
+        f.write_str(&s)
+    }
+}
+
+// pairs and vectors are the objects that can be shared, and so need datum labels
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum LabelKey {
+    Cell(usize),
+    Vector(*const RefCell<Vec<Expr>>),
+}
+
+#[derive(Default)]
+struct Labels {
+    shared: HashSet<LabelKey>,
+    assigned: HashMap<LabelKey, usize>,
+    // how many more pairs to print before giving up, if unlabelled cycles are possible
+    limit: Option<usize>,
+    // how many lists and vectors we are inside, which the limit also caps
+    nesting: usize,
+}
+
+impl Labels {
+    fn is_shared(&self, expr: &Expr) -> bool {
+        expr.label_key().is_some_and(|k| self.shared.contains(&k))
+    }
+
+    // writes #n= before the first occurrence of a shared object or #n# in place of
+    // a later one, returning true if the object has been written already
+    fn write_label(&mut self, key: LabelKey, acc: &mut String) -> bool {
+        if !self.shared.contains(&key) {
+            return false;
+        }
+        if let Some(n) = self.assigned.get(&key) {
+            acc.push_str(&format!("#{}#", n));
+            return true;
+        }
+        let n = self.assigned.len();
+        self.assigned.insert(key, n);
+        acc.push_str(&format!("#{}=", n));
+        false
+    }
+
+    fn too_deep(&self) -> bool {
+        self.limit.is_some() && self.nesting >= WRITE_SIMPLE_NESTING
+    }
+
+    fn exhausted(&mut self) -> bool {
+        match &mut self.limit {
+            Some(0) => true,
+            Some(n) => {
+                *n -= 1;
+                false
+            }
+            None => false,
         }
     }
 }
 
+// which pairs write, write-shared and write-simple give datum labels to
+#[derive(Clone, Copy)]
+enum WriteMode {
+    Cycles,
+    Shared,
+    Simple,
+}
+
+// write-simple gives up after this many pairs rather than looping forever on a cycle,
+// or once it is this deep inside nested lists and vectors
+const WRITE_SIMPLE_LIMIT: usize = 1000;
+const WRITE_SIMPLE_NESTING: usize = 100;
+
+// what (features) returns and cond-expand tests against
+const FEATURES: [&str; 2] = ["R7RS", "SCHEME"];
+
+// the special forms, recognised by the symbol at the head of a form with a
+// single match rather than comparing it against each name in turn
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum SpecialForm {
+    Quote,
+    Define,
+    Set,
+    DefineValues,
+    LetValues,
+    If,
+    And,
+    Or,
+    Cond,
+    CondExpand,
+    While,
+    Dotimes,
+    Lambda,
+    Delay,
+    DelayForce,
+    ConsStream,
+    TheEnvironment,
+    Parameterize,
+    Assert,
+    Time,
+    CheckExpect,
+    CheckWithin,
+    DefineRecordType,
+    DefineSyntax,
+}
+
+impl SpecialForm {
+    fn of(head: &Expr) -> Option<SpecialForm> {
+        Some(match syntax::base_name(head)? {
+            "QUOTE" => SpecialForm::Quote,
+            "DEFINE" => SpecialForm::Define,
+            "SET!" => SpecialForm::Set,
+            "DEFINE-VALUES" => SpecialForm::DefineValues,
+            "LET-VALUES" => SpecialForm::LetValues,
+            "IF" => SpecialForm::If,
+            "AND" => SpecialForm::And,
+            "OR" => SpecialForm::Or,
+            "COND" => SpecialForm::Cond,
+            "COND-EXPAND" => SpecialForm::CondExpand,
+            "WHILE" => SpecialForm::While,
+            "DOTIMES" => SpecialForm::Dotimes,
+            "LAMBDA" => SpecialForm::Lambda,
+            "DELAY" => SpecialForm::Delay,
+            "DELAY-FORCE" => SpecialForm::DelayForce,
+            "CONS-STREAM" => SpecialForm::ConsStream,
+            "THE-ENVIRONMENT" => SpecialForm::TheEnvironment,
+            "PARAMETERIZE" => SpecialForm::Parameterize,
+            "ASSERT" => SpecialForm::Assert,
+            "TIME" => SpecialForm::Time,
+            "CHECK-EXPECT" => SpecialForm::CheckExpect,
+            "CHECK-WITHIN" => SpecialForm::CheckWithin,
+            "DEFINE-RECORD-TYPE" => SpecialForm::DefineRecordType,
+            "DEFINE-SYNTAX" => SpecialForm::DefineSyntax,
+            _ => return None,
+        })
+    }
+}
+
+// the result of a cond: either a value or a form still to evaluate in tail
+// position, along with the environment to evaluate it in
+enum CondBranch {
+    Done(Expr),
+    Tail(Expr, Expr),
+}
+
+// the outcome of a check-expect or check-within, kept until run-tests reports it
+struct CheckResult {
+    source: String,
+    failure: Option<String>,
+}
+
 struct Heap {
     symbols: Expr,
     root_env: Expr,
     cells: Slab<ConsCell>,
+    output: Box<dyn Write>,
+    dynamic_bindings: Vec<(Expr, Expr)>,
+    winders: Vec<Expr>,
+    // the handlers installed by with-exception-handler, innermost last
+    handlers: Vec<Expr>,
+    checks: Vec<CheckResult>,
+    // total number of cells ever allocated, which collection doesn't reduce
+    allocations: usize,
+    // integers are printed in this base, set with the :radix command
+    radix: u32,
+    // what command-line returns: the program or script name and its arguments
+    command_line: Vec<String>,
+    // when the interpreter started, which runtime measures from
+    start: Instant,
+    // how many symbols gensym has made, so that each gets a new name
+    gensym_count: u64,
+    // xorshift state for random, which must never be zero
+    random_state: u64,
+    // the parameters behind current-input-port and current-output-port
+    current_input_port: Expr,
+    current_output_port: Expr,
+    gc: Gc,
+    // values held by the evaluator and primitives in Rust variables, which
+    // collection must keep even though nothing else may refer to them yet
+    temp_roots: Vec<Expr>,
 }
 
 impl Heap {
     fn new() -> Self {
+        Self::with_output(Box::new(std::io::stdout()))
+    }
+
+    fn with_output(output: Box<dyn Write>) -> Self {
         let mut me = Self {
             symbols: Expr::Nil,
             root_env: Expr::Nil,
             cells: Slab::new(),
+            output,
+            dynamic_bindings: Vec::new(),
+            winders: Vec::new(),
+            handlers: Vec::new(),
+            checks: Vec::new(),
+            allocations: 0,
+            radix: 10,
+            command_line: Vec::new(),
+            start: Instant::now(),
+            gensym_count: 0,
+            random_state: seed_from_clock(),
+            current_input_port: Expr::Nil,
+            current_output_port: Expr::Nil,
+            gc: Gc::new(),
+            temp_roots: Vec::new(),
         };
         let env = me.make_env(&Expr::Nil).unwrap();
         me.root_env = env;
@@ -94,10 +562,26 @@ impl Heap {
         me
     }
 
+    fn reset(&mut self) {
+        self.root_env = self.make_env(&Expr::Nil).unwrap();
+        self.dynamic_bindings.clear();
+        self.winders.clear();
+        self.handlers.clear();
+        self.checks.clear();
+        add_primitives(self).unwrap();
+        self.collect();
+    }
+
     fn get_first_rest(&self, expr: &Expr) -> SResult<(Expr, Expr)> {
+        let (first, rest) = self.first_rest_ref(expr)?;
+        Ok((first.clone(), rest.clone()))
+    }
+
+    // borrows the contents of a pair, for walking structure without cloning it
+    fn first_rest_ref(&self, expr: &Expr) -> SResult<(&Expr, &Expr)> {
         if let Expr::Pair(k) = expr {
-            let cell = self.cells.get((k).0).unwrap().clone();
-            Ok((cell.0, cell.1))
+            let cell = self.cells.get((k).0).unwrap();
+            Ok((&cell.0, &cell.1))
         } else {
             Err(SError::ImproperList)
         }
@@ -113,6 +597,7 @@ impl Heap {
 
     fn set_first(&mut self, expr: &Expr, v: Expr) -> SResult<()> {
         if let Expr::Pair(k) = expr {
+            self.write_barrier(&v);
             self.cells.get_mut((k).0).unwrap().0 = v;
             Ok(())
         } else {
@@ -130,6 +615,7 @@ impl Heap {
 
     fn set_rest(&mut self, expr: &Expr, v: Expr) -> SResult<()> {
         if let Expr::Pair(k) = expr {
+            self.write_barrier(&v);
             self.cells.get_mut((k).0).unwrap().1 = v;
             Ok(())
         } else {
@@ -154,20 +640,57 @@ impl Heap {
         }
     }
 
+    fn get_lambda_arity(&self, expr: &Expr) -> SResult<Arity> {
+        self.params_arity(&self.get_lambda_args(expr)?)
+    }
+
+    fn params_arity(&self, params: &Expr) -> SResult<Arity> {
+        let mut v = params.clone();
+        let mut n = 0;
+        while let Expr::Pair(_) = v {
+            n += 1;
+            v = self.get_rest(&v)?;
+        }
+        Ok(if v.is_nil() {
+            Arity::exact(n)
+        } else {
+            Arity::at_least(n)
+        })
+    }
+
     fn get_lambda_body(&self, expr: &Expr) -> SResult<Expr> {
+        Ok(self.get_lambda_code(expr)?.body.clone())
+    }
+
+    fn get_lambda_code(&self, expr: &Expr) -> SResult<Rc<Code>> {
         if let Expr::Closure(k) = expr {
             let rest = self.cells.get((k).0).unwrap().1.clone();
-            self.get_rest(&rest)
+            match self.get_rest(&rest)? {
+                Expr::Code(code) => Ok(code),
+                _ => Err(SError::ImproperLambda),
+            }
         } else {
             Err(SError::ImproperLambda)
         }
     }
 
     fn make_cons(&mut self, first: Expr, rest: Expr) -> SResult<Expr> {
-        let key = ConsCellKey(self.cells.insert((first, rest, false)));
+        self.write_barrier(&first);
+        self.write_barrier(&rest);
+        let marked = self.allocate_marked();
+        let key = ConsCellKey(self.cells.insert((first, rest, marked)));
+        self.allocations += 1;
         Ok(Expr::Pair(key))
     }
 
+    fn make_list(&mut self, items: Vec<Expr>) -> SResult<Expr> {
+        let mut result = Expr::Nil;
+        for item in items.into_iter().rev() {
+            result = self.make_cons(item, result)?;
+        }
+        Ok(result)
+    }
+
     fn map_list(
         &mut self,
         list: &Expr,
@@ -179,6 +702,7 @@ impl Heap {
         let (mut first, mut rest) = self.get_first_rest(list)?;
         let val = func(self, &first)?;
         let result = self.make_cons(val, Expr::Nil).unwrap();
+        self.root(&result);
         let mut result_tail = result.clone();
         while !rest.is_nil() {
             if rest.is_pair() {
@@ -195,14 +719,34 @@ impl Heap {
     }
 
     fn is_proper_list(&self, expr: &Expr) -> SResult<bool> {
-        if expr.is_nil() {
-            return Ok(true);
+        // the slow pointer catches up with the fast one if the list is cyclic
+        let mut slow = expr.clone();
+        let mut fast = expr.clone();
+        loop {
+            for _ in 0..2 {
+                if fast.is_nil() {
+                    return Ok(true);
+                }
+                if !fast.is_pair() {
+                    return Ok(false);
+                }
+                fast = self.get_rest(&fast)?;
+            }
+            slow = self.get_rest(&slow)?;
+            if fast == slow {
+                return Ok(false);
+            }
         }
-        if !expr.is_pair() {
-            return Ok(false);
+    }
+
+    fn list_length(&self, expr: &Expr) -> SResult<usize> {
+        let mut n = 0;
+        let mut v = expr.clone();
+        while !v.is_nil() {
+            v = self.get_rest(&v)?;
+            n += 1;
         }
-        let rest = self.get_rest(expr)?;
-        self.is_proper_list(&rest)
+        Ok(n)
     }
 
     fn test_length(&self, expr: &Expr, n: usize) -> SResult<bool> {
@@ -215,13 +759,92 @@ impl Heap {
         self.test_length(&rest, n - 1)
     }
 
+    fn is_equal(&self, a: &Expr, b: &Expr) -> SResult<bool> {
+        // each pair of cells is compared once, so shared structure isn't compared
+        // over and over; a pair seen again is either being compared already (a cycle,
+        // where assuming it is equal is right) or was found equal before
+        let mut compared = HashSet::new();
+        let mut compared_vectors = HashSet::new();
+        let mut worklist = vec![(a.clone(), b.clone())];
+        while let Some((a, b)) = worklist.pop() {
+            match (&a, &b) {
+                (Expr::Pair(x), Expr::Pair(y)) => {
+                    if !compared.insert((x.0, y.0)) {
+                        continue;
+                    }
+                    let (a_first, a_rest) = self.get_first_rest(&a)?;
+                    let (b_first, b_rest) = self.get_first_rest(&b)?;
+                    worklist.push((a_rest, b_rest));
+                    worklist.push((a_first, b_first));
+                }
+                (Expr::String(x), Expr::String(y)) => {
+                    if *x.borrow() != *y.borrow() {
+                        return Ok(false);
+                    }
+                }
+                (Expr::Vector(x), Expr::Vector(y)) => {
+                    if x.borrow().len() != y.borrow().len() {
+                        return Ok(false);
+                    }
+                    if !compared_vectors.insert((Rc::as_ptr(x), Rc::as_ptr(y))) {
+                        continue;
+                    }
+                    worklist.extend(x.borrow().iter().cloned().zip(y.borrow().iter().cloned()));
+                }
+                _ => {
+                    if !a.is_eq(&b) {
+                        return Ok(false);
+                    }
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    fn equal_hash(&self, expr: &Expr) -> u64 {
+        // only hash a bounded prefix of the structure so that cycles terminate;
+        // equal? structures are visited in the same order so still hash equally
+        let mut h = DefaultHasher::new();
+        let mut worklist = vec![expr.clone()];
+        let mut budget = 64;
+        while let Some(e) = worklist.pop() {
+            if budget == 0 {
+                break;
+            }
+            budget -= 1;
+            match &e {
+                Expr::Pair(_) => {
+                    std::mem::discriminant(&e).hash(&mut h);
+                    let (first, rest) = self.get_first_rest(&e).unwrap();
+                    worklist.push(rest);
+                    worklist.push(first);
+                }
+                Expr::String(s) => {
+                    std::mem::discriminant(&e).hash(&mut h);
+                    s.borrow().hash(&mut h);
+                }
+                Expr::Vector(v) => {
+                    std::mem::discriminant(&e).hash(&mut h);
+                    v.borrow().len().hash(&mut h);
+                    worklist.extend(v.borrow().iter().rev().cloned());
+                }
+                _ => e.eq_hash().hash(&mut h),
+            }
+        }
+        h.finish()
+    }
+
     fn make_symbol(&mut self, name: &str) -> SResult<Expr> {
-        let name = name.to_ascii_uppercase();
+        self.intern_symbol(&name.to_ascii_uppercase())
+    }
+
+    // unlike make_symbol this keeps the case of the name, as for |pipe quoted| symbols
+    fn intern_symbol(&mut self, name: &str) -> SResult<Expr> {
         let mut s = self.symbols.clone();
         while !s.is_nil() {
             let (first, rest) = self.get_first_rest(&s)?;
             if let Expr::Symbol(r) = first {
-                if Rc::deref(&r).eq(&name) {
+                if Rc::deref(&r).eq(name) {
                     return Ok(Expr::Symbol(Rc::clone(&r)));
                 }
             }
@@ -234,15 +857,43 @@ impl Heap {
         Ok(Expr::Symbol(new_symbol))
     }
 
-    fn make_closure(&mut self, env: Expr, arg_list: Expr, body: Expr) -> SResult<Expr> {
-        let mut v = arg_list.clone();
-        while !v.is_nil() {
-            if !self.get_first(&v)?.is_symbol() {
+    // makes a symbol whose name no other gensym has had, and which isn't interned,
+    // so the reader only gives the same symbol for a name written between pipes
+    fn gensym(&mut self, prefix: &str) -> Expr {
+        self.gensym_count += 1;
+        Expr::Symbol(Rc::from(format!("{}{}", prefix, self.gensym_count)))
+    }
+
+    fn validate_params(&self, params: &Expr) -> SResult<()> {
+        // (a b), (a b . rest) or just rest, where rest collects any remaining arguments
+        let mut v = params.clone();
+        while let Expr::Pair(_) = v {
+            if !self.get_first(&v)?.is_identifier() {
                 return Err(SError::ImproperSymbol);
             }
-            v = self.get_rest(&v)?.clone();
+            v = self.get_rest(&v)?;
+        }
+        if !v.is_nil() && !v.is_identifier() {
+            return Err(SError::ImproperSymbol);
         }
-        let tail = self.make_cons(arg_list, body)?;
+        Ok(())
+    }
+
+    fn make_closure(&mut self, env: Expr, arg_list: Expr, body: Expr) -> SResult<Expr> {
+        let code = Rc::new(analyse::analyse_body(self, &body));
+        self.make_analysed_closure(env, arg_list, code)
+    }
+
+    // a closure cell holds (env . (params . code)), where the code holds the body
+    // it was analysed from
+    fn make_analysed_closure(
+        &mut self,
+        env: Expr,
+        arg_list: Expr,
+        code: Rc<Code>,
+    ) -> SResult<Expr> {
+        self.validate_params(&arg_list)?;
+        let tail = self.make_cons(arg_list, Expr::Code(code))?;
         if let Expr::Pair(key) = self.make_cons(env, tail)? {
             Ok(Expr::Closure(key))
         } else {
@@ -250,256 +901,1839 @@ impl Heap {
         }
     }
 
-    fn make_env(&mut self, parent: &Expr) -> SResult<Expr> {
-        self.make_cons(parent.clone(), Expr::Nil)
+    // a promise cell holds a box (state . contents), where the state is #t once
+    // the promise has a value, #f for a delay whose thunk gives the value and ()
+    // for a delay-force whose thunk gives another promise. boxes are shared so
+    // that forcing a chain of delay-forces updates every promise in it at once
+    fn make_promise(&mut self, env: Expr, body: Expr, lazy: bool) -> SResult<Expr> {
+        let thunk = self.make_closure(env, Expr::Nil, body)?;
+        let state = if lazy {
+            Expr::Nil
+        } else {
+            Expr::Boolean(false)
+        };
+        self.make_promise_box(state, thunk)
     }
 
-    fn env_get(&self, env: &Expr, name: &Expr) -> SResult<Expr> {
-        if !env.is_pair() {
-            return Err(SError::ImproperEnvironment);
+    fn make_promise_box(&mut self, state: Expr, contents: Expr) -> SResult<Expr> {
+        let promise_box = self.make_cons(state, contents)?;
+        if let Expr::Pair(key) = self.make_cons(promise_box, Expr::Nil)? {
+            Ok(Expr::Promise(key))
+        } else {
+            unreachable!()
         }
-        let (parent, bindings) = self.get_first_rest(env)?;
-        if let Expr::Symbol(_) = name {
-            let mut e = bindings.clone();
-            while !e.is_nil() {
-                let (first, rest) = self.get_first_rest(&e)?;
-                if first.is_pair() {
-                    let (key, _) = self.get_first_rest(&first)?;
-                    if key == *name {
-                        return self.get_rest(&first);
-                    }
-                }
-                e = rest;
+    }
+
+    fn force(&mut self, promise: &Expr) -> SResult<Expr> {
+        let Expr::Promise(k) = promise else {
+            return Ok(promise.clone());
+        };
+        let cell = Expr::Pair(*k);
+        // a delay-force chain is followed iteratively rather than by recursion
+        loop {
+            let promise_box = self.get_first(&cell)?;
+            let (state, contents) = self.get_first_rest(&promise_box)?;
+            if state == Expr::Boolean(true) {
+                return Ok(contents);
             }
-            if parent.is_nil() {
-                Err(SError::UnboundSymbol)
-            } else if parent.is_pair() {
-                self.env_get(&parent, name)
-            } else {
-                Err(SError::ImproperEnvironment)
+            let value = self.apply(&contents, &Expr::Nil)?;
+            // forcing the thunk may itself have forced this promise
+            let promise_box = self.get_first(&cell)?;
+            let (forced, contents) = self.get_first_rest(&promise_box)?;
+            if forced == Expr::Boolean(true) {
+                return Ok(contents);
+            }
+            match value {
+                Expr::Promise(inner) if state.is_nil() => {
+                    // take over the inner promise's state and make it share our box
+                    let inner = Expr::Pair(inner);
+                    let (state, contents) = self.get_first_rest(&self.get_first(&inner)?)?;
+                    self.set_first(&promise_box, state)?;
+                    self.set_rest(&promise_box, contents)?;
+                    self.set_first(&inner, promise_box)?;
+                }
+                _ => {
+                    self.set_first(&promise_box, Expr::Boolean(true))?;
+                    self.set_rest(&promise_box, value.clone())?;
+                    return Ok(value);
+                }
             }
+        }
+    }
+
+    // the next number from a xorshift64* generator
+    fn next_random(&mut self) -> u64 {
+        let mut x = self.random_state;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.random_state = x;
+        x.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    fn make_values(&mut self, values: Expr) -> SResult<Expr> {
+        if let Expr::Pair(key) = self.make_cons(values, Expr::Nil)? {
+            Ok(Expr::Values(key))
         } else {
-            Err(SError::ImproperSymbol)
+            unreachable!()
         }
     }
 
-    fn env_set(&mut self, env: &Expr, name: &Expr, val: Expr) -> SResult<()> {
-        if !env.is_pair() {
-            return Err(SError::ImproperEnvironment);
+    fn make_condition(&mut self, message: Expr, irritants: Expr) -> SResult<Expr> {
+        if let Expr::Pair(key) = self.make_cons(message, irritants)? {
+            Ok(Expr::Condition(key))
+        } else {
+            unreachable!()
         }
-        let (_parent, bindings) = self.get_first_rest(env)?;
-        if let Expr::Symbol(_) = name {
-            let mut e = bindings.clone();
-            while !e.is_nil() {
-                let (first, rest) = self.get_first_rest(&e)?;
-                if first.is_pair() {
-                    let (key, _) = self.get_first_rest(&first)?;
-                    if key == *name {
-                        self.set_rest(&first, val)?;
-                        return Ok(());
-                    }
-                }
-                e = rest;
+    }
+
+    // what a handler receives for an error: the object itself if it was raised,
+    // or else an error object with the error's message and no irritants
+    fn condition_object(&mut self, error: SError) -> SResult<Expr> {
+        match error {
+            SError::Raise(obj, _) => Ok(obj),
+            e => {
+                let message = primitive::make_string_expr(e.to_string());
+                self.make_condition(message, Expr::Nil)
             }
-            let new_pair = self.make_cons(name.clone(), val)?;
-            let new_bindings = self.make_cons(new_pair, bindings)?;
-            self.set_rest(env, new_bindings)?;
-            Ok(())
+        }
+    }
+
+    fn make_parameter(&mut self, value: Expr, converter: Expr) -> SResult<Expr> {
+        // a parameter cell holds (value . converter), with () for no converter
+        let value = self.convert_parameter_value(&converter, value)?;
+        if let Expr::Pair(key) = self.make_cons(value, converter)? {
+            Ok(Expr::Parameter(key))
         } else {
-            Err(SError::ImproperSymbol)
+            unreachable!()
         }
     }
 
-    fn apply(&mut self, op: &Expr, args: &Expr) -> SResult<Expr> {
-        if let Expr::Primitive(p) = op {
-            (p.func)(args, self)
-        } else if let Expr::Closure(_) = op {
-            let env = self.make_env(&self.get_lambda_env(op)?)?;
-            let mut param_list = self.get_lambda_args(op)?;
-            let mut arg_list = args.clone();
-            while !param_list.is_nil() {
-                if arg_list.is_nil() {
-                    return Err(SError::WrongNumberOfArgs);
-                }
-                let param = self.get_first(&param_list)?;
-                let arg = self.get_first(&arg_list)?;
-                self.env_set(&env, &param, arg)?;
-                param_list = self.get_rest(&param_list)?;
-                arg_list = self.get_rest(&arg_list)?;
-            }
-            if !arg_list.is_nil() {
-                return Err(SError::WrongNumberOfArgs);
-            }
-            let mut body = self.get_lambda_body(op)?;
-            let mut result = Expr::Nil;
-            while !body.is_nil() {
-                let form = self.get_first(&body)?;
-                result = self.eval_in(&env, &form)?;
-                body = self.get_rest(&body)?;
-            }
-            Ok(result)
+    fn convert_parameter_value(&mut self, converter: &Expr, value: Expr) -> SResult<Expr> {
+        if converter.is_nil() {
+            Ok(value)
         } else {
-            Err(SError::NotCallable)
+            let args = self.make_cons(value, Expr::Nil)?;
+            self.apply(converter, &args)
         }
     }
 
-    fn eval(&mut self, expr: &Expr) -> SResult<Expr> {
-        let env = self.root_env.clone();
-        self.eval_in(&env, expr)
+    fn parameter_value(&self, param: &Expr) -> SResult<Expr> {
+        if let Expr::Parameter(k) = param {
+            self.get_first(&Expr::Pair(*k))
+        } else {
+            Err(SError::type_error("parameter", param))
+        }
     }
 
-    fn eval_in(&mut self, env: &Expr, expr: &Expr) -> SResult<Expr> {
-        match expr {
-            Expr::Nil
-            | Expr::Boolean(_)
-            | Expr::Integer(_)
-            | Expr::Closure(_)
-            | Expr::Primitive(_) => Ok(expr.clone()),
-            Expr::Symbol(_) => self.env_get(env, expr),
-            Expr::Pair(_) => {
-                let (first, rest) = self.get_first_rest(expr)?;
-                if first.is_specific_symbol("QUOTE") {
-                    let args = rest;
-                    if !self.test_length(&args, 1)? {
-                        return Err(SError::WrongNumberOfArgs);
-                    }
-                    self.get_first(&args)
-                } else if first.is_specific_symbol("DEFINE") {
-                    let args = rest;
-                    if !self.test_length(&args, 2)? {
-                        return Err(SError::WrongNumberOfArgs);
-                    }
-                    let sym = self.get_first(&args)?;
-                    let rexpr = self.get_first(&self.get_rest(&args)?)?;
-                    if sym.is_symbol() {
-                        let val = self.eval_in(env, &rexpr)?;
-                        self.env_set(env, &sym, val)?;
-                        Ok(sym)
-                    } else if sym.is_pair() {
-                        // (define (sum a b) (+ a b)) shorthand
-                        let name = self.get_first(&sym)?;
-                        let arg_list = self.get_rest(&sym)?;
-                        let body = self.get_rest(&args)?;
-                        let clo = self.make_closure(env.clone(), arg_list, body)?;
-                        self.env_set(env, &name, clo)?;
-                        Ok(name)
-                    } else {
-                        return Err(SError::ImproperSymbol);
-                    }
-                } else if first.is_specific_symbol("IF") {
-                    let args = rest;
-                    if !self.test_length(&args, 3)? {
-                        return Err(SError::WrongNumberOfArgs);
-                    }
-                    let test_expr = self.get_first(&args)?;
-                    let true_expr = self.get_first(&self.get_rest(&args)?)?;
-                    let false_expr = self.get_first(&self.get_rest(&self.get_rest(&args)?)?)?;
-                    let t = self.eval_in(env, &test_expr)?;
-                    if t.is_truthy() {
-                        self.eval_in(env, &true_expr)
-                    } else {
-                        self.eval_in(env, &false_expr)
-                    }
-                } else if first.is_specific_symbol("LAMBDA") {
-                    let args = rest;
-                    if !self.test_length(&args, 2)? {
-                        return Err(SError::WrongNumberOfArgs);
-                    }
-                    let arg_list = self.get_first(&args)?;
-                    let body = self.get_rest(&args)?;
-                    Ok(self.make_closure(env.clone(), arg_list, body)?)
-                } else {
-                    let op = self.eval_in(env, &first)?;
-                    let args = self.map_list(&rest, |h, e| h.eval_in(env, e))?;
-                    self.apply(&op, &args)
-                }
+    fn swap_parameter_value(&mut self, param: &Expr, value: Expr) -> SResult<Expr> {
+        if let Expr::Parameter(k) = param {
+            let cell = Expr::Pair(*k);
+            let old = self.get_first(&cell)?;
+            self.set_first(&cell, value)?;
+            Ok(old)
+        } else {
+            Err(SError::type_error("parameter", param))
+        }
+    }
+
+    fn unwind_dynamic_bindings(&mut self, depth: usize) -> SResult<()> {
+        while self.dynamic_bindings.len() > depth {
+            let (param, old) = self.dynamic_bindings.pop().unwrap();
+            self.swap_parameter_value(&param, old)?;
+        }
+        Ok(())
+    }
+
+    fn dynamic_wind(&mut self, before: &Expr, thunk: &Expr, after: &Expr) -> SResult<Expr> {
+        self.apply(before, &Expr::Nil)?;
+        self.winders.push(after.clone());
+        // after runs whether the thunk returns normally or with an error, unless
+        // the error is an emergency exit
+        let result = self.apply(thunk, &Expr::Nil);
+        if let Ok(value) | Err(SError::Raise(value, _)) = &result {
+            self.root(value);
+        }
+        let after = self.winders.pop().unwrap();
+        if let Err(SError::EmergencyExit(_)) = result {
+            return result;
+        }
+        self.apply(&after, &Expr::Nil)?;
+        result
+    }
+
+    // the handler is installed while the thunk runs, and raise calls it from
+    // wherever an exception happens
+    fn with_exception_handler(&mut self, handler: &Expr, thunk: &Expr) -> SResult<Expr> {
+        let depth = self.handlers.len();
+        self.handlers.push(handler.clone());
+        let result = self.apply(thunk, &Expr::Nil);
+        self.handlers.truncate(depth);
+        result
+    }
+
+    // calls the innermost handler right where the exception happened, with the
+    // outer handlers in effect while it runs. raise-continuable returns whatever
+    // the handler does, but a handler can't return to a raise, so if it does that
+    // is raised in turn from where the handler ran. nothing is unwound until an
+    // error escapes a handler, or there is no handler and the error is reported
+    fn raise(&mut self, obj: Expr, continuable: bool) -> SResult<Expr> {
+        let Some(handler) = self.handlers.pop() else {
+            return Err(self.uncaught(obj)?);
+        };
+        self.root(&handler);
+        let args = self.make_list(vec![obj])?;
+        let mut result = self.apply(&handler, &args);
+        if result.is_ok() && !continuable {
+            let obj = self.condition_object(SError::HandlerReturned)?;
+            result = self.raise(obj, false);
+        }
+        self.handlers.push(handler);
+        match result {
+            // an error escaping the handler has already been past the outer
+            // handlers, so this one mustn't see it on its way out either
+            Err(e) if e.is_unraised() => {
+                let obj = self.condition_object(e)?;
+                Err(self.uncaught(obj)?)
             }
+            result => result,
         }
     }
 
-    fn format_expr_inner(&self, expr: &Expr, acc: &mut String) -> SResult<()> {
-        match expr {
+    // an error from the evaluator or a primitive is raised like any other
+    // exception, if there are handlers to raise it to
+    fn raise_error(&mut self, result: SResult<Expr>) -> SResult<Expr> {
+        match result {
+            Err(e) if e.is_unraised() && !self.handlers.is_empty() => {
+                let obj = self.condition_object(e)?;
+                self.raise(obj, false)
+            }
+            result => result,
+        }
+    }
+
+    // the error that reports an exception nothing handled
+    fn uncaught(&mut self, obj: Expr) -> SResult<SError> {
+        let text = match obj {
+            Expr::Condition(_) => self.condition_text(&obj)?,
+            _ => format!("uncaught exception: {}", self.format_expr(&obj)?),
+        };
+        Ok(SError::Raise(obj, text))
+    }
+
+    // an error object's message followed by its irritants
+    fn condition_text(&self, condition: &Expr) -> SResult<String> {
+        let Expr::Condition(k) = condition else {
+            unreachable!()
+        };
+        let (message, mut irritants) = self.get_first_rest(&Expr::Pair(*k))?;
+        let mut text = self.display_expr(&message)?;
+        while !irritants.is_nil() {
+            let (irritant, rest) = self.get_first_rest(&irritants)?;
+            text.push(' ');
+            text.push_str(&self.format_expr(&irritant)?);
+            irritants = rest;
+        }
+        Ok(text)
+    }
+
+    fn make_env(&mut self, parent: &Expr) -> SResult<Expr> {
+        self.make_cons(parent.clone(), Expr::Nil)
+    }
+
+    fn env_get(&self, env: &Expr, name: &Expr) -> SResult<Expr> {
+        if !name.is_identifier() {
+            return Err(SError::ImproperSymbol);
+        }
+        let mut frame = env;
+        loop {
+            if !frame.is_pair() {
+                return Err(SError::ImproperEnvironment);
+            }
+            let (parent, bindings) = self.first_rest_ref(frame)?;
+            if let Some(binding) = self.find_binding(bindings, name)? {
+                return Ok(self.first_rest_ref(binding)?.1.clone());
+            }
+            if parent.is_nil() {
+                // an alias that nothing in its expansion bound means what
+                // the original does where the macro was defined
+                return match name {
+                    Expr::Alias(alias) => self.env_get(&alias.env, &alias.original),
+                    _ => Err(self.unbound(name)),
+                };
+            }
+            frame = parent;
+        }
+    }
+
+    // rebinds a name in the innermost environment that has it, which unlike
+    // env_set never creates a binding, so assigning to a misspelt name fails
+    fn env_update(&mut self, env: &Expr, name: &Expr, val: Expr) -> SResult<()> {
+        if !name.is_identifier() {
+            return Err(SError::ImproperSymbol);
+        }
+        let mut frame = env.clone();
+        loop {
+            if !frame.is_pair() {
+                return Err(SError::ImproperEnvironment);
+            }
+            let (parent, bindings) = self.get_first_rest(&frame)?;
+            if let Some(binding) = self.find_binding(&bindings, name)?.cloned() {
+                return self.set_rest(&binding, val);
+            }
+            if parent.is_nil() {
+                return match name {
+                    Expr::Alias(alias) => {
+                        let (env, original) = (alias.env.clone(), alias.original.clone());
+                        self.env_update(&env, &original, val)
+                    }
+                    _ => Err(self.unbound(name)),
+                };
+            }
+            frame = parent;
+        }
+    }
+
+    fn unbound(&self, name: &Expr) -> SError {
+        match syntax::base_symbol(name) {
+            Some(Expr::Symbol(sym)) => SError::UnboundSymbol(Rc::clone(sym)),
+            _ => SError::ImproperSymbol,
+        }
+    }
+
+    // the (name . value) pair for a name in one frame's bindings
+    fn find_binding<'a>(&'a self, bindings: &'a Expr, name: &Expr) -> SResult<Option<&'a Expr>> {
+        let mut e = bindings;
+        while !e.is_nil() {
+            let (binding, rest) = self.first_rest_ref(e)?;
+            if binding.is_pair() && self.first_rest_ref(binding)?.0 == name {
+                return Ok(Some(binding));
+            }
+            e = rest;
+        }
+        Ok(None)
+    }
+
+    fn env_set(&mut self, env: &Expr, name: &Expr, val: Expr) -> SResult<()> {
+        if !env.is_pair() {
+            return Err(SError::ImproperEnvironment);
+        }
+        let (_parent, bindings) = self.get_first_rest(env)?;
+        if name.is_identifier() {
+            if let Some(binding) = self.find_binding(&bindings, name)?.cloned() {
+                return self.set_rest(&binding, val);
+            }
+            let new_pair = self.make_cons(name.clone(), val)?;
+            let new_bindings = self.make_cons(new_pair, bindings)?;
+            self.set_rest(env, new_bindings)?;
+            Ok(())
+        } else {
+            Err(SError::ImproperSymbol)
+        }
+    }
+
+    // keeps a value alive until the evaluation or primitive call that rooted it returns
+    fn root(&mut self, expr: &Expr) {
+        self.temp_roots.push(expr.clone());
+    }
+
+    fn apply(&mut self, op: &Expr, args: &Expr) -> SResult<Expr> {
+        let depth = self.temp_roots.len();
+        self.root(op);
+        self.root(args);
+        let result = self.apply_rooted(op, args);
+        self.temp_roots.truncate(depth);
+        self.raise_error(result)
+    }
+
+    fn apply_rooted(&mut self, op: &Expr, args: &Expr) -> SResult<Expr> {
+        if let Expr::Primitive(p) = op {
+            p.arity.check(self.list_length(args)?)?;
+            (p.func)(args, self)
+        } else if let Expr::Closure(_) = op {
+            let env = self.make_env(&self.get_lambda_env(op)?)?;
+            self.bind_params(&env, &self.get_lambda_args(op)?, args)?;
+            let last = self.eval_code_init(&env, op)?;
+            self.eval_node(&env, &last)
+        } else if let Expr::Parameter(_) = op {
+            Arity::exact(0).check(self.list_length(args)?)?;
+            self.parameter_value(op)
+        } else {
+            Err(SError::NotCallable(op.clone()))
+        }
+    }
+
+    // binds each parameter to an argument, with any rest parameter taking a list
+    // of the leftover arguments
+    fn bind_params(&mut self, env: &Expr, params: &Expr, args: &Expr) -> SResult<()> {
+        self.params_arity(params)?.check(self.list_length(args)?)?;
+        let mut param_list = params.clone();
+        let mut arg_list = args.clone();
+        while let Expr::Pair(_) = param_list {
+            let param = self.get_first(&param_list)?;
+            let arg = self.get_first(&arg_list)?;
+            self.env_set(env, &param, arg)?;
+            param_list = self.get_rest(&param_list)?;
+            arg_list = self.get_rest(&arg_list)?;
+        }
+        if param_list.is_identifier() {
+            self.env_set(env, &param_list, arg_list)?;
+        }
+        Ok(())
+    }
+
+    // like bind_params, but evaluates each argument straight into its binding
+    // rather than building a list of them first
+    fn eval_params(
+        &mut self,
+        env: &Expr,
+        new_env: &Expr,
+        params: &Expr,
+        arg_exprs: &Expr,
+    ) -> SResult<()> {
+        self.params_arity(params)?
+            .check(self.list_length(arg_exprs)?)?;
+        let mut param_list = params.clone();
+        let mut arg_list = arg_exprs.clone();
+        while let Expr::Pair(_) = param_list {
+            let (param, next_param) = self.get_first_rest(&param_list)?;
+            let (arg_expr, next_arg) = self.get_first_rest(&arg_list)?;
+            let arg = self.eval_in(env, &arg_expr)?;
+            self.env_set(new_env, &param, arg)?;
+            param_list = next_param;
+            arg_list = next_arg;
+        }
+        if param_list.is_identifier() {
+            let rest = self.map_list(&arg_list, |h, e| h.eval_in(env, e))?;
+            self.env_set(new_env, &param_list, rest)?;
+        }
+        Ok(())
+    }
+
+    // like eval_params, for arguments that have been analysed
+    fn eval_analysed_params(
+        &mut self,
+        env: &Expr,
+        new_env: &Expr,
+        params: &Expr,
+        arg_nodes: &[Rc<Node>],
+    ) -> SResult<()> {
+        self.params_arity(params)?.check(arg_nodes.len())?;
+        let mut param_list = params.clone();
+        let mut arg_nodes = arg_nodes.iter();
+        while let Expr::Pair(_) = param_list {
+            let (param, next_param) = self.get_first_rest(&param_list)?;
+            let arg = self.eval_node(env, arg_nodes.next().unwrap())?;
+            self.env_set(new_env, &param, arg)?;
+            param_list = next_param;
+        }
+        if param_list.is_identifier() {
+            let rest = self.eval_nodes(env, arg_nodes)?;
+            self.env_set(new_env, &param_list, rest)?;
+        }
+        Ok(())
+    }
+
+    // the values of some analysed nodes as a list
+    fn eval_nodes<'a>(
+        &mut self,
+        env: &Expr,
+        nodes: impl Iterator<Item = &'a Rc<Node>>,
+    ) -> SResult<Expr> {
+        let mut values = Vec::new();
+        for node in nodes {
+            let value = self.eval_node(env, node)?;
+            self.root(&value);
+            values.push(value);
+        }
+        self.make_list(values)
+    }
+
+    // evaluates all but the last node of a closure's body, which is returned so
+    // that the caller can evaluate it in tail position
+    fn eval_code_init(&mut self, env: &Expr, closure: &Expr) -> SResult<Rc<Node>> {
+        let code = self.get_lambda_code(closure)?;
+        self.eval_nodes_init(env, &code.nodes)
+    }
+
+    fn eval_nodes_init(&mut self, env: &Expr, nodes: &[Rc<Node>]) -> SResult<Rc<Node>> {
+        let Some((last, init)) = nodes.split_last() else {
+            return Err(SError::ImproperList);
+        };
+        for node in init {
+            self.eval_node(env, node)?;
+        }
+        Ok(last.clone())
+    }
+
+    // the values of an expression as a list, which has one element unless it
+    // returned multiple values
+    fn values_list(&mut self, value: Expr) -> SResult<Expr> {
+        match value {
+            Expr::Values(k) => self.get_first(&Expr::Pair(k)),
+            v => self.make_cons(v, Expr::Nil),
+        }
+    }
+
+    fn eval_sequence(&mut self, env: &Expr, body: &Expr) -> SResult<Expr> {
+        let mut body = body.clone();
+        let mut result = Expr::Nil;
+        while !body.is_nil() {
+            let form = self.get_first(&body)?;
+            result = self.eval_in(env, &form)?;
+            body = self.get_rest(&body)?;
+        }
+        Ok(result)
+    }
+
+    // whether a cond-expand requirement holds: a feature symbol, or (and req ...),
+    // (or req ...) or (not req)
+    fn has_features(&self, requirement: &Expr) -> SResult<bool> {
+        if let Some(name) = syntax::base_name(requirement) {
+            return Ok(FEATURES.contains(&name));
+        }
+        let (op, mut args) = self.get_first_rest(requirement)?;
+        if op.is_specific_symbol("NOT") {
+            if !self.test_length(&args, 1)? {
+                return Err(SError::WrongNumberOfArgs);
+            }
+            return Ok(!self.has_features(&self.get_first(&args)?)?);
+        }
+        let is_and = if op.is_specific_symbol("AND") {
+            true
+        } else if op.is_specific_symbol("OR") {
+            false
+        } else {
+            return Err(SError::type_error("feature requirement", requirement));
+        };
+        while !args.is_nil() {
+            let (arg, next) = self.get_first_rest(&args)?;
+            if self.has_features(&arg)? != is_and {
+                return Ok(!is_and);
+            }
+            args = next;
+        }
+        Ok(is_and)
+    }
+
+    // evaluates every form of a body except the last, which is returned unevaluated
+    // so that the caller can evaluate it in tail position
+    fn eval_body_init(&mut self, env: &Expr, body: &Expr) -> SResult<Expr> {
+        let (mut form, mut rest) = self.get_first_rest(body)?;
+        while !rest.is_nil() {
+            self.eval_in(env, &form)?;
+            (form, rest) = self.get_first_rest(&rest)?;
+        }
+        Ok(form)
+    }
+
+    // whether any form in a body is a definition
+    fn has_definitions(&self, body: &Expr) -> SResult<bool> {
+        let mut body = body;
+        while !body.is_nil() {
+            let (form, rest) = self.first_rest_ref(body)?;
+            if form.is_pair() {
+                let head = SpecialForm::of(self.first_rest_ref(form)?.0);
+                if matches!(
+                    head,
+                    Some(
+                        SpecialForm::Define
+                            | SpecialForm::DefineValues
+                            | SpecialForm::DefineRecordType
+                            | SpecialForm::DefineSyntax
+                    )
+                ) {
+                    return Ok(true);
+                }
+            }
+            body = rest;
+        }
+        Ok(false)
+    }
+
+    // finds the first cond clause whose test is true, returning either its value
+    // or the form to evaluate in tail position
+    fn select_cond_clause(&mut self, env: &Expr, clauses: &Expr) -> SResult<CondBranch> {
+        let mut clauses = clauses.clone();
+        while !clauses.is_nil() {
+            let (clause, next) = self.get_first_rest(&clauses)?;
+            let (test, body) = self.get_first_rest(&clause)?;
+            let value = if test.is_specific_symbol("ELSE") {
+                if body.is_nil() || !next.is_nil() {
+                    return Err(SError::WrongNumberOfArgs);
+                }
+                Expr::Boolean(true)
+            } else {
+                self.eval_in(env, &test)?
+            };
+            if value.is_truthy() {
+                if body.is_nil() {
+                    return Ok(CondBranch::Done(value));
+                }
+                let (arrow, receiver) = self.get_first_rest(&body)?;
+                if arrow.is_specific_symbol("=>") {
+                    // (test => proc) calls proc with the value of test
+                    if !self.test_length(&receiver, 1)? {
+                        return Err(SError::WrongNumberOfArgs);
+                    }
+                    self.root(&value);
+                    let proc = self.eval_in(env, &self.get_first(&receiver)?)?;
+                    let args = self.make_list(vec![value])?;
+                    return Ok(CondBranch::Done(self.apply(&proc, &args)?));
+                }
+                // a clause body is a scope of its own, so that definitions in it
+                // stay local, but it only needs an environment if it has any
+                let clause_env = if self.has_definitions(&body)? {
+                    let clause_env = self.make_env(env)?;
+                    self.root(&clause_env);
+                    clause_env
+                } else {
+                    env.clone()
+                };
+                let form = self.eval_body_init(&clause_env, &body)?;
+                return Ok(CondBranch::Tail(clause_env, form));
+            }
+            clauses = next;
+        }
+        Ok(CondBranch::Done(Expr::Unspecified))
+    }
+
+    // evaluates (check-expect actual expected) or (check-within actual expected tolerance)
+    // and describes why the check failed, if it did; errors count as failures
+    fn check_failure(&mut self, env: &Expr, args: &Expr) -> SResult<Option<String>> {
+        let (actual, rest) = self.get_first_rest(args)?;
+        let (expected, rest) = self.get_first_rest(&rest)?;
+        let actual = self.eval_in(env, &actual)?;
+        self.root(&actual);
+        let expected = self.eval_in(env, &expected)?;
+        self.root(&expected);
+        let passed = if rest.is_nil() {
+            self.is_equal(&actual, &expected)?
+        } else {
+            let tolerance = self.eval_in(env, &self.get_first(&rest)?)?;
+            match (&actual, &expected, &tolerance) {
+                (Expr::Integer(a), Expr::Integer(e), Expr::Integer(t)) => {
+                    a.abs_diff(*e) <= t.unsigned_abs()
+                }
+                (Expr::Integer(_), Expr::Integer(_), t) => {
+                    return Err(SError::type_error("integer", t))
+                }
+                (Expr::Integer(_), e, _) => return Err(SError::type_error("integer", e)),
+                (a, _, _) => return Err(SError::type_error("integer", a)),
+            }
+        };
+        if passed {
+            Ok(None)
+        } else {
+            Ok(Some(format!(
+                "expected {}, got {}",
+                expected.with_heap(self),
+                actual.with_heap(self)
+            )))
+        }
+    }
+
+    fn eval(&mut self, expr: &Expr) -> SResult<Expr> {
+        let env = self.root_env.clone();
+        self.eval_in(&env, expr)
+    }
+
+    // evaluates every expression in a whole program, stopping at the first error,
+    // and returns the value of the last one
+    fn eval_source(&mut self, source: &str) -> SResult<Expr> {
+        let tokens = tokenize(source).map_err(SError::ReadError)?;
+        let mut token_stream = tokens.into_iter().peekable();
+        let mut result = Expr::Unspecified;
+        while token_stream.peek().is_some() {
+            let expr = parse_expr(&mut token_stream, self).map_err(SError::ReadError)?;
+            result = self.eval(&expr)?;
+        }
+        Ok(result)
+    }
+
+    fn eval_in(&mut self, env: &Expr, expr: &Expr) -> SResult<Expr> {
+        let depth = self.temp_roots.len();
+        let result = self.eval_rooted(env, expr, None, depth);
+        self.temp_roots.truncate(depth);
+        self.raise_error(result)
+    }
+
+    // evaluates an analysed node, whose closure the caller keeps alive
+    fn eval_node(&mut self, env: &Expr, node: &Rc<Node>) -> SResult<Expr> {
+        // constants and variables don't need the evaluator loop at all
+        match &**node {
+            Node::Constant(value) => Ok(value.clone()),
+            Node::Variable(sym) => self.env_get(env, sym),
+            _ => {
+                let depth = self.temp_roots.len();
+                let result = self.eval_rooted(env, &Expr::Unspecified, Some(node.clone()), depth);
+                self.temp_roots.truncate(depth);
+                result
+            }
+        }
+    }
+
+    fn eval_rooted(
+        &mut self,
+        env: &Expr,
+        expr: &Expr,
+        node: Option<Rc<Node>>,
+        depth: usize,
+    ) -> SResult<Expr> {
+        // expressions in tail position replace the current expression and loop
+        // instead of recursing, so tail calls don't grow the stack. while there
+        // is an analysed node to evaluate, it takes the place of the expression,
+        // which is left holding the closure it came from to keep it alive
+        let mut tail_env = env.clone();
+        let mut tail_expr = expr.clone();
+        let mut tail_node = node;
+        loop {
+            // nothing rooted by the last iteration is needed any more
+            self.temp_roots.truncate(depth);
+            self.root(&tail_env);
+            self.root(&tail_expr);
+            let env = &tail_env.clone();
+            if let Some(node) = tail_node.take() {
+                match &*node {
+                    Node::Constant(value) => return Ok(value.clone()),
+                    Node::Variable(sym) => return self.env_get(env, sym),
+                    Node::If(test, consequent, alternative) => {
+                        let t = self.eval_node(env, test)?;
+                        let branch = if t.is_truthy() {
+                            consequent
+                        } else {
+                            alternative
+                        };
+                        tail_node = Some(branch.clone());
+                    }
+                    Node::And(nodes) | Node::Or(nodes) => {
+                        let is_and = matches!(*node, Node::And(_));
+                        let Some((last, init)) = nodes.split_last() else {
+                            return Ok(Expr::Boolean(is_and));
+                        };
+                        for n in init {
+                            let value = self.eval_node(env, n)?;
+                            if value.is_truthy() != is_and {
+                                return Ok(value);
+                            }
+                        }
+                        tail_node = Some(last.clone());
+                    }
+                    Node::Define(sym, value) => {
+                        let val = self.eval_node(env, value)?;
+                        self.env_set(env, sym, val)?;
+                        return Ok(Expr::Unspecified);
+                    }
+                    Node::Set(sym, value) => {
+                        let val = self.eval_node(env, value)?;
+                        self.env_update(env, sym, val)?;
+                        return Ok(Expr::Unspecified);
+                    }
+                    Node::Lambda(params, code) => {
+                        return self.make_analysed_closure(
+                            env.clone(),
+                            params.clone(),
+                            code.clone(),
+                        )
+                    }
+                    Node::Cond(clauses) => {
+                        let mut chosen = None;
+                        for clause in clauses {
+                            let value = match &clause.test {
+                                Some(test) => self.eval_node(env, test)?,
+                                None => Expr::Boolean(true),
+                            };
+                            if value.is_truthy() {
+                                chosen = Some((clause, value));
+                                break;
+                            }
+                        }
+                        let Some((clause, value)) = chosen else {
+                            return Ok(Expr::Unspecified);
+                        };
+                        if clause.body.is_empty() {
+                            return Ok(value);
+                        }
+                        let clause_env = if clause.scoped {
+                            let clause_env = self.make_env(env)?;
+                            self.root(&clause_env);
+                            clause_env
+                        } else {
+                            env.clone()
+                        };
+                        tail_node = Some(self.eval_nodes_init(&clause_env, &clause.body)?);
+                        tail_env = clause_env;
+                    }
+                    Node::Call(operator, operands, form) => {
+                        let op = self.eval_node(env, operator)?;
+                        self.root(&op);
+                        match op {
+                            Expr::Macro(_) => tail_expr = syntax::expand(self, &op, form)?,
+                            Expr::Closure(_) => {
+                                let new_env = self.make_env(&self.get_lambda_env(&op)?)?;
+                                self.root(&new_env);
+                                let params = self.get_lambda_args(&op)?;
+                                self.eval_analysed_params(env, &new_env, &params, operands)?;
+                                tail_node = Some(self.eval_code_init(&new_env, &op)?);
+                                tail_env = new_env;
+                                tail_expr = op;
+                            }
+                            _ => {
+                                let args = self.eval_nodes(env, operands.iter())?;
+                                return self.apply(&op, &args);
+                            }
+                        }
+                    }
+                    Node::Form(form) => tail_expr = form.clone(),
+                }
+                continue;
+            }
+            let expr = &tail_expr.clone();
+            let result = match expr {
+                Expr::Nil
+                | Expr::Unspecified
+                | Expr::Boolean(_)
+                | Expr::Integer(_)
+                | Expr::Char(_)
+                | Expr::String(_)
+                | Expr::Closure(_)
+                | Expr::Promise(_)
+                | Expr::Parameter(_)
+                | Expr::Environment(_)
+                | Expr::Record(_)
+                | Expr::Values(_)
+                | Expr::Condition(_)
+                | Expr::Macro(_)
+                | Expr::Code(_)
+                | Expr::Primitive(_)
+                | Expr::HashTable(_)
+                | Expr::Vector(_)
+                | Expr::Port(_)
+                | Expr::Eof => Ok(expr.clone()),
+                Expr::Symbol(_) | Expr::Alias(_) => self.env_get(env, expr),
+                Expr::Pair(_) => {
+                    let (first, rest) = self.get_first_rest(expr)?;
+                    let form = SpecialForm::of(&first);
+                    match form {
+                        Some(SpecialForm::Quote) => {
+                            let args = rest;
+                            if !self.test_length(&args, 1)? {
+                                return Err(SError::WrongNumberOfArgs);
+                            }
+                            syntax::strip_aliases(self, &self.get_first(&args)?)
+                        }
+                        Some(SpecialForm::Define) => {
+                            let args = rest;
+                            if !self.test_length(&args, 2)? {
+                                return Err(SError::WrongNumberOfArgs);
+                            }
+                            let sym = self.get_first(&args)?;
+                            let rexpr = self.get_first(&self.get_rest(&args)?)?;
+                            if sym.is_identifier() {
+                                let val = self.eval_in(env, &rexpr)?;
+                                self.env_set(env, &sym, val)?;
+                                Ok(Expr::Unspecified)
+                            } else if sym.is_pair() {
+                                // (define (sum a b) (+ a b)) shorthand
+                                let name = self.get_first(&sym)?;
+                                let arg_list = self.get_rest(&sym)?;
+                                let body = self.get_rest(&args)?;
+                                let clo = self.make_closure(env.clone(), arg_list, body)?;
+                                self.env_set(env, &name, clo)?;
+                                Ok(Expr::Unspecified)
+                            } else {
+                                Err(SError::ImproperSymbol)
+                            }
+                        }
+                        Some(SpecialForm::Set) => {
+                            // (set! x value) changes the nearest existing binding of x
+                            let args = rest;
+                            if !self.test_length(&args, 2)? {
+                                return Err(SError::WrongNumberOfArgs);
+                            }
+                            let (sym, rest) = self.get_first_rest(&args)?;
+                            if !sym.is_identifier() {
+                                return Err(SError::ImproperSymbol);
+                            }
+                            let val = self.eval_in(env, &self.get_first(&rest)?)?;
+                            self.env_update(env, &sym, val)?;
+                            Ok(Expr::Unspecified)
+                        }
+                        Some(SpecialForm::DefineValues) => {
+                            // (define-values (a b . rest) expr) binds names like a lambda's parameters
+                            let args = rest;
+                            if !self.test_length(&args, 2)? {
+                                return Err(SError::WrongNumberOfArgs);
+                            }
+                            let (formals, rest) = self.get_first_rest(&args)?;
+                            self.validate_params(&formals)?;
+                            let value = self.eval_in(env, &self.get_first(&rest)?)?;
+                            let values = self.values_list(value)?;
+                            self.bind_params(env, &formals, &values)?;
+                            Ok(Expr::Unspecified)
+                        }
+                        Some(SpecialForm::LetValues) => {
+                            // (let-values (((a b) expr) ...) body ...), where every expr is
+                            // evaluated in the outer environment
+                            let (mut bindings, body) = self.get_first_rest(&rest)?;
+                            if body.is_nil() {
+                                return Err(SError::WrongNumberOfArgs);
+                            }
+                            let new_env = self.make_env(env)?;
+                            self.root(&new_env);
+                            while !bindings.is_nil() {
+                                let binding = self.get_first(&bindings)?;
+                                if !self.test_length(&binding, 2)? {
+                                    return Err(SError::WrongNumberOfArgs);
+                                }
+                                let (formals, rest) = self.get_first_rest(&binding)?;
+                                self.validate_params(&formals)?;
+                                let value = self.eval_in(env, &self.get_first(&rest)?)?;
+                                let values = self.values_list(value)?;
+                                self.bind_params(&new_env, &formals, &values)?;
+                                bindings = self.get_rest(&bindings)?;
+                            }
+                            tail_expr = self.eval_body_init(&new_env, &body)?;
+                            tail_env = new_env;
+                            continue;
+                        }
+                        Some(SpecialForm::If) => {
+                            let args = rest;
+                            if !self.test_length(&args, 3)? {
+                                return Err(SError::WrongNumberOfArgs);
+                            }
+                            let test_expr = self.get_first(&args)?;
+                            let true_expr = self.get_first(&self.get_rest(&args)?)?;
+                            let false_expr =
+                                self.get_first(&self.get_rest(&self.get_rest(&args)?)?)?;
+                            let t = self.eval_in(env, &test_expr)?;
+                            tail_expr = if t.is_truthy() { true_expr } else { false_expr };
+                            continue;
+                        }
+                        Some(SpecialForm::And | SpecialForm::Or) => {
+                            // (and) is #t and (or) is #f; otherwise the last expression is in
+                            // tail position unless an earlier one decides the result
+                            let is_and = form == Some(SpecialForm::And);
+                            if rest.is_nil() {
+                                return Ok(Expr::Boolean(is_and));
+                            }
+                            let mut args = rest;
+                            loop {
+                                let (arg, next) = self.get_first_rest(&args)?;
+                                if next.is_nil() {
+                                    tail_expr = arg;
+                                    break;
+                                }
+                                let value = self.eval_in(env, &arg)?;
+                                if value.is_truthy() != is_and {
+                                    return Ok(value);
+                                }
+                                args = next;
+                            }
+                            continue;
+                        }
+                        Some(SpecialForm::Cond) => match self.select_cond_clause(env, &rest)? {
+                            CondBranch::Done(value) => return Ok(value),
+                            CondBranch::Tail(clause_env, form) => {
+                                tail_env = clause_env;
+                                tail_expr = form;
+                                continue;
+                            }
+                        },
+                        Some(SpecialForm::CondExpand) => {
+                            // (cond-expand (requirement body ...) ... (else body ...))
+                            let mut clauses = rest;
+                            loop {
+                                if clauses.is_nil() {
+                                    return Ok(Expr::Unspecified);
+                                }
+                                let (clause, next) = self.get_first_rest(&clauses)?;
+                                let (requirement, body) = self.get_first_rest(&clause)?;
+                                if requirement.is_specific_symbol("ELSE")
+                                    || self.has_features(&requirement)?
+                                {
+                                    if body.is_nil() {
+                                        return Ok(Expr::Unspecified);
+                                    }
+                                    tail_expr = self.eval_body_init(env, &body)?;
+                                    break;
+                                }
+                                clauses = next;
+                            }
+                            continue;
+                        }
+                        Some(SpecialForm::While) => {
+                            // (while test body ...) loops here rather than recursing, so
+                            // it runs in constant stack space however many times it goes round
+                            let (test, body) = self.get_first_rest(&rest)?;
+                            while self.eval_in(env, &test)?.is_truthy() {
+                                self.eval_sequence(env, &body)?;
+                            }
+                            Ok(Expr::Unspecified)
+                        }
+                        Some(SpecialForm::Dotimes) => {
+                            // (dotimes (i n) body ...) runs the body with i bound to each
+                            // of 0 to n - 1 in turn, in a fresh environment each time
+                            let (spec, body) = self.get_first_rest(&rest)?;
+                            if !self.test_length(&spec, 2)? {
+                                return Err(SError::WrongNumberOfArgs);
+                            }
+                            let (var, count) = self.get_first_rest(&spec)?;
+                            if !var.is_identifier() {
+                                return Err(SError::ImproperSymbol);
+                            }
+                            let count = self.eval_in(env, &self.get_first(&count)?)?;
+                            for i in 0..primitive::as_integer(&count)? {
+                                let new_env = self.make_env(env)?;
+                                self.root(&new_env);
+                                self.env_set(&new_env, &var, Expr::Integer(i))?;
+                                self.eval_sequence(&new_env, &body)?;
+                                self.temp_roots.pop();
+                            }
+                            Ok(Expr::Unspecified)
+                        }
+                        Some(SpecialForm::Lambda) => {
+                            let args = rest;
+                            if !self.test_length(&args, 2)? {
+                                return Err(SError::WrongNumberOfArgs);
+                            }
+                            let arg_list = self.get_first(&args)?;
+                            let body = self.get_rest(&args)?;
+                            Ok(self.make_closure(env.clone(), arg_list, body)?)
+                        }
+                        Some(SpecialForm::Delay) => {
+                            let args = rest;
+                            if !self.test_length(&args, 1)? {
+                                return Err(SError::WrongNumberOfArgs);
+                            }
+                            self.make_promise(env.clone(), args, false)
+                        }
+                        Some(SpecialForm::DelayForce) => {
+                            // (delay-force expr) where expr gives a promise, forced iteratively
+                            let args = rest;
+                            if !self.test_length(&args, 1)? {
+                                return Err(SError::WrongNumberOfArgs);
+                            }
+                            self.make_promise(env.clone(), args, true)
+                        }
+                        Some(SpecialForm::ConsStream) => {
+                            // (cons-stream a b) is (cons a (delay b))
+                            let args = rest;
+                            if !self.test_length(&args, 2)? {
+                                return Err(SError::WrongNumberOfArgs);
+                            }
+                            let head = self.eval_in(env, &self.get_first(&args)?)?;
+                            let tail =
+                                self.make_promise(env.clone(), self.get_rest(&args)?, false)?;
+                            self.make_cons(head, tail)
+                        }
+                        Some(SpecialForm::TheEnvironment) => {
+                            if !rest.is_nil() {
+                                return Err(SError::WrongNumberOfArgs);
+                            }
+                            match env {
+                                Expr::Pair(k) => Ok(Expr::Environment(*k)),
+                                _ => Err(SError::ImproperEnvironment),
+                            }
+                        }
+                        Some(SpecialForm::Parameterize) => {
+                            // (parameterize ((param value) ...) body ...)
+                            let (mut bindings, body) = self.get_first_rest(&rest)?;
+                            let mut new_values = Vec::new();
+                            while !bindings.is_nil() {
+                                let binding = self.get_first(&bindings)?;
+                                if !self.test_length(&binding, 2)? {
+                                    return Err(SError::WrongNumberOfArgs);
+                                }
+                                let param = self.eval_in(env, &self.get_first(&binding)?)?;
+                                self.root(&param);
+                                let value =
+                                    self.eval_in(env, &self.get_first(&self.get_rest(&binding)?)?)?;
+                                self.root(&value);
+                                let converter = match &param {
+                                    Expr::Parameter(k) => self.get_rest(&Expr::Pair(*k))?,
+                                    _ => return Err(SError::type_error("parameter", &param)),
+                                };
+                                let value = self.convert_parameter_value(&converter, value)?;
+                                new_values.push((param, value));
+                                bindings = self.get_rest(&bindings)?;
+                            }
+                            let depth = self.dynamic_bindings.len();
+                            for (param, value) in new_values {
+                                let old = self.swap_parameter_value(&param, value)?;
+                                self.dynamic_bindings.push((param, old));
+                            }
+                            // restore the old values whether or not the body succeeded
+                            let result = self.eval_sequence(env, &body);
+                            self.unwind_dynamic_bindings(depth)?;
+                            result
+                        }
+                        Some(SpecialForm::Assert) => {
+                            let args = rest;
+                            if !self.test_length(&args, 1)? {
+                                return Err(SError::WrongNumberOfArgs);
+                            }
+                            let test_expr = self.get_first(&args)?;
+                            let t = self.eval_in(env, &test_expr)?;
+                            if t.is_truthy() {
+                                Ok(t)
+                            } else {
+                                Err(SError::AssertionFailed(self.format_expr(&test_expr)?))
+                            }
+                        }
+                        Some(SpecialForm::Time) => {
+                            // (time body ...) reports how long the body took and what it allocated
+                            if rest.is_nil() {
+                                return Err(SError::WrongNumberOfArgs);
+                            }
+                            let start = Instant::now();
+                            let allocations = self.allocations;
+                            let result = self.eval_sequence(env, &rest)?;
+                            let report = format!(
+                                "time: {:?}, {} cells allocated\n",
+                                start.elapsed(),
+                                self.allocations - allocations
+                            );
+                            self.write_output(&report)?;
+                            Ok(result)
+                        }
+                        Some(SpecialForm::CheckExpect | SpecialForm::CheckWithin) => {
+                            let args = rest;
+                            let n = if form == Some(SpecialForm::CheckExpect) {
+                                2
+                            } else {
+                                3
+                            };
+                            if !self.test_length(&args, n)? {
+                                return Err(SError::WrongNumberOfArgs);
+                            }
+                            // errors count as failures, except for exiting
+                            let failure = match self.check_failure(env, &args) {
+                                Ok(failure) => failure,
+                                Err(e) if e.exit_status().is_some() => return Err(e),
+                                Err(e) => Some(e.to_string()),
+                            };
+                            let source = self.format_expr(expr)?;
+                            self.checks.push(CheckResult { source, failure });
+                            Ok(Expr::Unspecified)
+                        }
+                        Some(SpecialForm::DefineRecordType) => {
+                            record::define_record_type(self, env, &rest)?;
+                            Ok(Expr::Unspecified)
+                        }
+                        Some(SpecialForm::DefineSyntax) => {
+                            syntax::define_syntax(self, env, &rest)?;
+                            Ok(Expr::Unspecified)
+                        }
+                        None => {
+                            let op = self.eval_in(env, &first)?;
+                            self.root(&op);
+                            if let Expr::Macro(_) = op {
+                                // the expansion is evaluated in place of the macro use
+                                tail_expr = syntax::expand(self, &op, expr)?;
+                                continue;
+                            }
+                            if let Expr::Closure(_) = op {
+                                // the same as apply, but the arguments are bound as they
+                                // are evaluated and the body's last form is a tail call
+                                let new_env = self.make_env(&self.get_lambda_env(&op)?)?;
+                                self.root(&new_env);
+                                let params = self.get_lambda_args(&op)?;
+                                self.eval_params(env, &new_env, &params, &rest)?;
+                                tail_node = Some(self.eval_code_init(&new_env, &op)?);
+                                tail_env = new_env;
+                                tail_expr = op;
+                                continue;
+                            }
+                            let args = self.map_list(&rest, |h, e| h.eval_in(env, e))?;
+                            self.apply(&op, &args)
+                        }
+                    }
+                }
+            };
+            return result;
+        }
+    }
+
+    fn format_expr_inner(
+        &self,
+        expr: &Expr,
+        acc: &mut String,
+        display: bool,
+        labels: &mut Labels,
+    ) -> SResult<()> {
+        match expr {
             Expr::Nil => acc.push_str("()"),
+            Expr::Unspecified => acc.push_str("#<unspecified>"),
             Expr::Boolean(false) => acc.push_str("#f"),
             Expr::Boolean(true) => acc.push_str("#t"),
-            Expr::Integer(n) => acc.push_str(&n.to_string()),
+            Expr::Integer(n) => acc.push_str(&format_integer(*n, self.radix)),
+            Expr::Char(c) if display => acc.push(*c),
+            Expr::String(s) if display => acc.push_str(&s.borrow()),
+            Expr::Char(' ') => acc.push_str("#\\space"),
+            Expr::Char('\n') => acc.push_str("#\\newline"),
+            Expr::Char('\t') => acc.push_str("#\\tab"),
+            Expr::Char(c) => {
+                acc.push_str("#\\");
+                acc.push(*c);
+            }
+            Expr::String(s) => {
+                acc.push('"');
+                for c in s.borrow().chars() {
+                    match c {
+                        '"' => acc.push_str("\\\""),
+                        '\\' => acc.push_str("\\\\"),
+                        '\n' => acc.push_str("\\n"),
+                        '\t' => acc.push_str("\\t"),
+                        _ => acc.push(c),
+                    }
+                }
+                acc.push('"');
+            }
+            Expr::Symbol(s) if !display && symbol_needs_pipes(s) => {
+                acc.push('|');
+                for c in s.chars() {
+                    match c {
+                        '|' => acc.push_str("\\|"),
+                        '\\' => acc.push_str("\\\\"),
+                        '\n' => acc.push_str("\\n"),
+                        '\t' => acc.push_str("\\t"),
+                        _ => acc.push(c),
+                    }
+                }
+                acc.push('|');
+            }
             Expr::Symbol(s) => acc.push_str(s),
+            Expr::Alias(_) => {
+                let symbol = syntax::base_symbol(expr).unwrap();
+                self.format_expr_inner(symbol, acc, display, labels)?;
+            }
             Expr::Closure(_) => acc.push_str("#<lambda>"),
+            Expr::Promise(_) => acc.push_str("#<promise>"),
+            Expr::Parameter(_) => acc.push_str("#<parameter>"),
+            Expr::Environment(_) => acc.push_str("#<environment>"),
+            Expr::Record(k) => {
+                let name = self.get_first(&self.get_first(&Expr::Pair(*k))?)?;
+                acc.push_str(&format!("#<record {}>", name.with_heap(self)));
+            }
+            Expr::Values(k) => {
+                // the values are kept as a list in the first half of the cell
+                let mut v = self.get_first(&Expr::Pair(*k))?;
+                while !v.is_nil() {
+                    let (first, rest) = self.get_first_rest(&v)?;
+                    self.format_expr_inner(&first, acc, display, labels)?;
+                    if !rest.is_nil() {
+                        acc.push(' ');
+                    }
+                    v = rest;
+                }
+            }
+            Expr::Condition(k) => {
+                let message = self.get_first(&Expr::Pair(*k))?;
+                acc.push_str(&format!("#<error {}>", message.with_heap(self)));
+            }
+            Expr::Macro(_) => acc.push_str("#<macro>"),
+            Expr::Code(_) => acc.push_str("#<code>"),
             Expr::Primitive(d) => acc.push_str(&format!("#<primitive {}>", d.name)),
-            Expr::Pair(_) => {
+            Expr::HashTable(_) => acc.push_str("#<hash-table>"),
+            Expr::Port(_) => acc.push_str("#<port>"),
+            Expr::Eof => acc.push_str("#<eof>"),
+            Expr::Vector(v) => {
+                if labels.too_deep() || labels.exhausted() {
+                    acc.push_str("...");
+                    return Ok(());
+                }
+                if labels.write_label(LabelKey::Vector(Rc::as_ptr(v)), acc) {
+                    return Ok(());
+                }
+                acc.push_str("#(");
+                labels.nesting += 1;
+                for (i, e) in v.borrow().iter().enumerate() {
+                    if i > 0 {
+                        acc.push(' ');
+                    }
+                    self.format_expr_inner(e, acc, display, labels)?;
+                }
+                labels.nesting -= 1;
+                acc.push(')');
+            }
+            Expr::Pair(k) => {
+                if labels.too_deep() || labels.exhausted() {
+                    acc.push_str("...");
+                    return Ok(());
+                }
+                if labels.write_label(LabelKey::Cell(k.0), acc) {
+                    return Ok(());
+                }
+                if let Some(prefix) = self.quote_prefix(expr)? {
+                    let rest = self.get_rest(expr)?;
+                    if !labels.is_shared(&rest) {
+                        acc.push_str(prefix);
+                        let datum = self.get_first(&rest)?;
+                        return self.format_expr_inner(&datum, acc, display, labels);
+                    }
+                }
                 acc.push('(');
+                labels.nesting += 1;
                 let (mut first, mut rest) = self.get_first_rest(expr)?;
                 loop {
-                    self.format_expr_inner(&first, acc)?;
+                    self.format_expr_inner(&first, acc, display, labels)?;
                     match rest {
                         Expr::Nil => break,
-                        Expr::Pair(_) => {
+                        // a shared tail needs a label, so it can't be spliced into this list
+                        Expr::Pair(_) if !labels.is_shared(&rest) => {
                             acc.push(' ');
+                            if labels.exhausted() {
+                                acc.push_str("...");
+                                break;
+                            }
                             (first, rest) = self.get_first_rest(&rest)?;
                         }
                         _ => {
                             acc.push_str(" . ");
-                            self.format_expr_inner(&rest, acc)?;
+                            self.format_expr_inner(&rest, acc, display, labels)?;
                             break;
                         }
                     }
                 }
+                labels.nesting -= 1;
                 acc.push(')');
             }
         }
         Ok(())
     }
 
+    fn quote_prefix(&self, expr: &Expr) -> SResult<Option<&'static str>> {
+        // (quote x) is written as 'x, and likewise for the quasiquote family
+        let head = self.get_first(expr)?;
+        let prefix = [
+            ("QUOTE", "'"),
+            ("QUASIQUOTE", "`"),
+            ("UNQUOTE", ","),
+            ("UNQUOTE-SPLICING", ",@"),
+        ]
+        .iter()
+        .find(|(name, _)| head.is_specific_symbol(name))
+        .map(|(_, prefix)| *prefix);
+        if prefix.is_some() && self.test_length(expr, 2).unwrap_or(false) {
+            Ok(prefix)
+        } else {
+            Ok(None)
+        }
+    }
+
+    // the objects a pair or vector refers to, in the order they are printed
+    fn label_children(&self, expr: &Expr) -> SResult<Vec<Expr>> {
+        match expr {
+            Expr::Pair(_) => {
+                let (first, rest) = self.get_first_rest(expr)?;
+                Ok(vec![first, rest])
+            }
+            Expr::Vector(v) => Ok(v.borrow().clone()),
+            _ => Ok(Vec::new()),
+        }
+    }
+
+    fn find_shared(&self, expr: &Expr) -> SResult<HashSet<LabelKey>> {
+        // pairs and vectors reachable more than once, which includes every cycle
+        let mut seen = HashSet::new();
+        let mut shared = HashSet::new();
+        let mut worklist = vec![expr.clone()];
+        while let Some(e) = worklist.pop() {
+            if let Expr::Values(k) = e {
+                worklist.push(self.get_first(&Expr::Pair(k))?);
+                continue;
+            }
+            let Some(key) = e.label_key() else {
+                continue;
+            };
+            if !seen.insert(key) {
+                shared.insert(key);
+                continue;
+            }
+            worklist.extend(self.label_children(&e)?.into_iter().rev());
+        }
+        Ok(shared)
+    }
+
+    fn find_cycles(&self, expr: &Expr) -> SResult<HashSet<LabelKey>> {
+        // objects reachable from themselves; a None on the stack means we have
+        // finished with the object on top of the path
+        let mut path = Vec::new();
+        let mut on_path = HashSet::new();
+        let mut done = HashSet::new();
+        let mut cycles = HashSet::new();
+        let mut worklist = vec![Some(expr.clone())];
+        while let Some(e) = worklist.pop() {
+            let e = match e {
+                None => {
+                    let key = path.pop().unwrap();
+                    on_path.remove(&key);
+                    done.insert(key);
+                    continue;
+                }
+                Some(Expr::Values(k)) => {
+                    worklist.push(Some(self.get_first(&Expr::Pair(k))?));
+                    continue;
+                }
+                Some(e) => e,
+            };
+            let Some(key) = e.label_key() else {
+                continue;
+            };
+            if on_path.contains(&key) {
+                cycles.insert(key);
+                continue;
+            }
+            if done.contains(&key) {
+                continue;
+            }
+            path.push(key);
+            on_path.insert(key);
+            worklist.push(None);
+            worklist.extend(self.label_children(&e)?.into_iter().rev().map(Some));
+        }
+        Ok(cycles)
+    }
+
     fn format_expr(&self, expr: &Expr) -> SResult<String> {
+        self.write_expr(expr, WriteMode::Shared)
+    }
+
+    fn write_expr(&self, expr: &Expr, mode: WriteMode) -> SResult<String> {
         let mut acc = String::new();
-        self.format_expr_inner(expr, &mut acc)?;
+        let mut labels = match mode {
+            WriteMode::Cycles => Labels {
+                shared: self.find_cycles(expr)?,
+                ..Default::default()
+            },
+            WriteMode::Shared => Labels {
+                shared: self.find_shared(expr)?,
+                ..Default::default()
+            },
+            WriteMode::Simple => Labels {
+                limit: Some(WRITE_SIMPLE_LIMIT),
+                ..Default::default()
+            },
+        };
+        self.format_expr_inner(expr, &mut acc, false, &mut labels)?;
         Ok(acc)
     }
 
-    fn collect(&mut self) {
-        for (_, c) in self.cells.iter_mut() {
-            c.2 = false;
+    fn format_expr_pretty(&self, expr: &Expr, width: usize) -> SResult<String> {
+        let mut labels = Labels {
+            shared: self.find_shared(expr)?,
+            ..Default::default()
+        };
+        let mut acc = String::new();
+        if !labels.shared.is_empty() {
+            // labels are numbered across the whole datum, so don't break it up
+            self.format_expr_inner(expr, &mut acc, false, &mut labels)?;
+        } else {
+            self.format_expr_pretty_inner(expr, 0, 0, width, &mut labels, &mut acc)?;
+        }
+        Ok(acc)
+    }
+
+    // closing counts the close parens that will follow expr on its last line
+    fn format_expr_pretty_inner(
+        &self,
+        expr: &Expr,
+        column: usize,
+        closing: usize,
+        width: usize,
+        labels: &mut Labels,
+        acc: &mut String,
+    ) -> SResult<()> {
+        // nothing below here is shared, so the labels stay empty all the way down
+        let mut flat = String::new();
+        self.format_expr_inner(expr, &mut flat, false, labels)?;
+        if column + flat.chars().count() + closing <= width
+            || !self.is_proper_list(expr)?
+            || expr.is_nil()
+        {
+            acc.push_str(&flat);
+            return Ok(());
+        }
+        if let Some(prefix) = self.quote_prefix(expr)? {
+            acc.push_str(prefix);
+            let datum = self.get_first(&self.get_rest(expr)?)?;
+            let column = column + prefix.len();
+            return self.format_expr_pretty_inner(&datum, column, closing, width, labels, acc);
+        }
+        let mut items = Vec::new();
+        let mut v = expr.clone();
+        while !v.is_nil() {
+            let (first, rest) = self.get_first_rest(&v)?;
+            items.push(first);
+            v = rest;
+        }
+        let mut head = String::new();
+        self.format_expr_inner(&items[0], &mut head, false, labels)?;
+        let head_len = head.chars().count();
+        let is_body_form = ["DEFINE", "LAMBDA", "LET", "LET*", "LETREC", "PARAMETERIZE"]
+            .iter()
+            .any(|s| items[0].is_specific_symbol(s));
+        // the column of the first item after the head, and of the items on later lines
+        let (first_column, rest_column) = if is_body_form && items.len() > 1 {
+            // (define (f x)
+            //   body)
+            (column + head_len + 2, column + 2)
+        } else if !items[0].is_pair() && items.len() > 1 && column + head_len + 2 < width {
+            // (f arg1
+            //    arg2), which also lines up cond clauses
+            (column + head_len + 2, column + head_len + 2)
+        } else {
+            (column + 1, column + 1)
+        };
+        acc.push('(');
+        let mut rest = &items[..];
+        if first_column != column + 1 {
+            acc.push_str(&head);
+            acc.push(' ');
+            rest = &items[1..];
+        }
+        for (i, item) in rest.iter().enumerate() {
+            let last = i + 1 == rest.len();
+            let item_closing = if last { closing + 1 } else { 0 };
+            if i == 0 {
+                self.format_expr_pretty_inner(
+                    item,
+                    first_column,
+                    item_closing,
+                    width,
+                    labels,
+                    acc,
+                )?;
+            } else {
+                acc.push('\n');
+                acc.push_str(&" ".repeat(rest_column));
+                self.format_expr_pretty_inner(item, rest_column, item_closing, width, labels, acc)?;
+            }
+        }
+        acc.push(')');
+        Ok(())
+    }
+
+    fn display_expr(&self, expr: &Expr) -> SResult<String> {
+        let mut acc = String::new();
+        let mut labels = Labels {
+            shared: self.find_shared(expr)?,
+            ..Default::default()
+        };
+        self.format_expr_inner(expr, &mut acc, true, &mut labels)?;
+        Ok(acc)
+    }
+
+    fn write_port(&mut self, port: &Expr, s: &str) -> SResult<()> {
+        let Expr::Port(p) = port else {
+            return Err(SError::type_error("output port", port));
+        };
+        match &mut *p.borrow_mut() {
+            Port::StringOutput(buffer) => {
+                buffer.push_str(s);
+                Ok(())
+            }
+            Port::ConsoleOutput => self
+                .output
+                .write_all(s.as_bytes())
+                .map_err(|_| SError::IoError),
+            Port::Input { .. } => Err(SError::type_error("output port", port)),
         }
-        let mut worklist = vec![self.symbols.clone(), self.root_env.clone()];
-        while let Some(ex) = worklist.pop() {
-            if let Expr::Pair(n) | Expr::Closure(n) = ex {
-                let cell = self.cells.get_mut(n.0).unwrap();
-                if !cell.2 {
-                    cell.2 = true;
-                    worklist.push(cell.0.clone());
-                    worklist.push(cell.1.clone());
+    }
+
+    fn write_output(&mut self, s: &str) -> SResult<()> {
+        let port = self.parameter_value(&self.current_output_port)?;
+        self.write_port(&port, s)
+    }
+
+    // whether the written form of a value reads back in as an equal value, which
+    // it doesn't if the value contains anything written as #<...>
+    fn is_readable(&self, expr: &Expr) -> SResult<bool> {
+        let mut seen_pairs = HashSet::new();
+        let mut seen_vectors = HashSet::new();
+        let mut pending = vec![expr.clone()];
+        while let Some(expr) = pending.pop() {
+            match &expr {
+                Expr::Nil
+                | Expr::Boolean(_)
+                | Expr::Integer(_)
+                | Expr::Char(_)
+                | Expr::String(_)
+                | Expr::Symbol(_) => (),
+                Expr::Pair(k) => {
+                    if seen_pairs.insert(k.0) {
+                        let (first, rest) = self.get_first_rest(&expr)?;
+                        pending.push(first);
+                        pending.push(rest);
+                    }
                 }
+                Expr::Vector(v) => {
+                    if seen_vectors.insert(Rc::as_ptr(v)) {
+                        pending.extend(v.borrow().iter().cloned());
+                    }
+                }
+                _ => return Ok(false),
             }
         }
-        self.cells.retain(|_, c| c.2);
+        Ok(true)
     }
 
-    fn dump(&self) -> SResult<()> {
-        for (k, _) in self.cells.iter() {
-            println!(
-                "cell {}: {}",
-                k,
-                self.format_expr(&Expr::Pair(ConsCellKey(k)))?
-            )
+    fn format_definitions(&mut self) -> SResult<String> {
+        // closures are saved as (define (f args) body) so their captured
+        // environment is lost, and values with no readable syntax are skipped
+        let define = self.make_symbol("DEFINE")?;
+        let quote = self.make_symbol("QUOTE")?;
+        let mut definitions = Vec::new();
+        let mut bindings = self.get_rest(&self.root_env.clone())?;
+        while !bindings.is_nil() {
+            let (binding, rest) = self.get_first_rest(&bindings)?;
+            bindings = rest;
+            let (name, value) = self.get_first_rest(&binding)?;
+            let body = match &value {
+                Expr::Primitive(p) => {
+                    let sym = self.make_symbol(&p.name)?;
+                    if sym == name {
+                        continue;
+                    }
+                    // another name for a primitive is saved as the primitive's
+                    // own name, as long as that still refers to it
+                    if self.env_get(&self.root_env.clone(), &sym).ok().as_ref() != Some(&value) {
+                        continue;
+                    }
+                    let value = self.make_cons(sym, Expr::Nil)?;
+                    self.make_cons(name.clone(), value)?
+                }
+                Expr::Closure(_) => {
+                    let args = self.get_lambda_args(&value)?;
+                    let body = self.get_lambda_body(&value)?;
+                    // closures made by primitives like compose have objects
+                    // with no written form in their bodies
+                    if !self.is_readable(&args)? || !self.is_readable(&body)? {
+                        continue;
+                    }
+                    let head = self.make_cons(name.clone(), args)?;
+                    self.make_cons(head, body)?
+                }
+                _ if !self.is_readable(&value)? => continue,
+                _ => {
+                    let quoted = self.make_cons(value.clone(), Expr::Nil)?;
+                    let quoted = self.make_cons(quote.clone(), quoted)?;
+                    let value = self.make_cons(quoted, Expr::Nil)?;
+                    self.make_cons(name.clone(), value)?
+                }
+            };
+            let form = self.make_cons(define.clone(), body)?;
+            definitions.push(self.format_expr(&form)?);
         }
-        Ok(())
+        // bindings are stored newest first but should be reloaded oldest first
+        definitions.reverse();
+        Ok(definitions.into_iter().map(|d| d + "\n").collect())
+    }
+}
+
+fn seed_from_clock() -> u64 {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos() as u64);
+    nanos | 1
+}
+
+// non-decimal integers get a prefix so that they read back as the same number
+fn format_integer(n: i64, radix: u32) -> String {
+    let sign = if n < 0 { "-" } else { "" };
+    let m = n.unsigned_abs();
+    match radix {
+        2 => format!("#b{}{:b}", sign, m),
+        8 => format!("#o{}{:o}", sign, m),
+        16 => format!("#x{}{:X}", sign, m),
+        _ => n.to_string(),
+    }
+}
+
+// whether reading the symbol's name back would give a different symbol or none at all
+fn symbol_needs_pipes(name: &str) -> bool {
+    name.is_empty()
+        || name == "."
+        || name.starts_with('#')
+        || (name != "-" && name.starts_with(|c: char| c.is_ascii_digit() || c == '-'))
+        || name
+            .chars()
+            .any(|c| c.is_whitespace() || "()\"'|".contains(c) || c != c.to_ascii_uppercase())
+}
+
+// settings for the REPL itself, which aren't affected by :reset
+struct Repl {
+    // quiet mode prints results without echoing the input or labelling the output
+    quiet: bool,
+    prompt: String,
+}
+
+impl Repl {
+    fn new() -> Self {
+        // an interactive session gets a prompt, while piped input is quiet by default
+        let interactive = std::io::stdin().is_terminal();
+        Repl {
+            quiet: !interactive,
+            prompt: if interactive { "> " } else { "" }.to_owned(),
+        }
+    }
+}
+
+// returns the exit status if a loaded file called exit
+fn run_command(heap: &mut Heap, repl: &mut Repl, command: &str) -> Option<i32> {
+    if let Some(prompt) = command.strip_prefix("prompt") {
+        // the prompt is the rest of the line, so it may contain spaces
+        if prompt.is_empty() || prompt.starts_with(' ') {
+            repl.prompt = prompt.strip_prefix(' ').unwrap_or("").to_owned();
+            println!("ok");
+            return None;
+        }
+    }
+    match command.split_whitespace().collect::<Vec<_>>().as_slice() {
+        ["reset"] => {
+            heap.reset();
+            println!("ok");
+        }
+        ["save", path] => match heap.format_definitions() {
+            Ok(definitions) => match std::fs::write(path, definitions) {
+                Ok(()) => println!("ok"),
+                Err(e) => println!("err: {}", e),
+            },
+            Err(e) => println!("err: {}", e),
+        },
+        ["load", path] => return load_file(heap, path),
+        ["radix", radix] => match radix.parse() {
+            Ok(radix @ (2 | 8 | 10 | 16)) => {
+                heap.radix = radix;
+                println!("ok");
+            }
+            _ => println!("err: radix must be 2, 8, 10 or 16"),
+        },
+        ["gc", mode @ ("full" | "incremental")] => {
+            heap.gc.incremental = *mode == "incremental";
+            heap.collect();
+            println!("ok");
+        }
+        ["quiet"] => {
+            repl.quiet = true;
+            println!("ok");
+        }
+        ["verbose"] => {
+            repl.quiet = false;
+            println!("ok");
+        }
+        _ => println!("err: unknown command :{}", command),
+    }
+    None
+}
+
+fn load_file(heap: &mut Heap, path: &str) -> Option<i32> {
+    let source = match std::fs::read_to_string(path) {
+        Ok(source) => source,
+        Err(e) => {
+            println!("err: {}", e);
+            return None;
+        }
+    };
+    match heap.eval_source(&source) {
+        Ok(_) => println!("ok"),
+        Err(e) if e.exit_status().is_some() => return e.exit_status(),
+        Err(e) => println!("err: {}", e),
     }
+    None
+}
+
+// runs a whole program from a file, or from stdin if the path is -, instead of
+// starting the REPL
+fn run_file(heap: &mut Heap, path: &str) -> ! {
+    let source = if path == "-" {
+        std::io::read_to_string(std::io::stdin())
+    } else {
+        std::fs::read_to_string(path)
+    };
+    let status = match source.map(|source| heap.eval_source(&source)) {
+        Ok(Ok(_)) => 0,
+        Ok(Err(e)) => e.exit_status().unwrap_or_else(|| {
+            eprintln!("err: {}", e);
+            1
+        }),
+        Err(e) => {
+            eprintln!("err: {}", e);
+            1
+        }
+    };
+    heap.output.flush().unwrap();
+    std::process::exit(status)
 }
 
 fn main() {
     let mut heap = Heap::new();
-    while let Some(res) = std::io::stdin().lock().lines().next() {
-        let line = res.unwrap();
-        let mut token_stream = tokenize(&line).into_iter().peekable();
+    let mut repl = Repl::new();
+    let mut args = std::env::args();
+    heap.command_line.extend(args.next());
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-q" | "--quiet" => repl.quiet = true,
+            "-v" | "--verbose" => repl.quiet = false,
+            path if path == "-" || !path.starts_with('-') => {
+                // everything after the script belongs to the script
+                heap.command_line = std::iter::once(arg.clone()).chain(args).collect();
+                run_file(&mut heap, path)
+            }
+            _ => {
+                eprintln!("unknown option {}", arg);
+                std::process::exit(1);
+            }
+        }
+    }
+    let status = 'repl: loop {
+        print!("{}", repl.prompt);
+        std::io::stdout().flush().unwrap();
+        // stdin mustn't stay locked while evaluating, as READ may need it
+        let mut line = String::new();
+        if std::io::stdin().read_line(&mut line).unwrap() == 0 {
+            break 0;
+        }
+        let line = line.trim_end_matches(['\n', '\r']);
+        if let Some(command) = line.trim_start().strip_prefix(':') {
+            if let Some(status) = run_command(&mut heap, &mut repl, command) {
+                break status;
+            }
+            continue;
+        }
+        let tokens = match tokenize(line) {
+            Ok(tokens) => tokens,
+            Err(e) => {
+                println!("err: {}", SError::ReadError(e));
+                continue;
+            }
+        };
+        let mut token_stream = tokens.into_iter().peekable();
         while token_stream.peek().is_some() {
-            let expr = parse_expr(&mut token_stream, &mut heap).unwrap();
-            println!("in:  {}", heap.format_expr(&expr).unwrap());
+            let expr = match parse_expr(&mut token_stream, &mut heap) {
+                Ok(expr) => expr,
+                Err(e) => {
+                    println!("err: {}", SError::ReadError(e));
+                    break;
+                }
+            };
+            if !repl.quiet {
+                println!("in:  {}", expr.with_heap(&heap));
+            }
             match heap.eval(&expr) {
-                Ok(result) => println!("out: {}", heap.format_expr(&result).unwrap()),
-                Err(e) => println!("err: {:?}", e),
+                Ok(Expr::Unspecified) => (),
+                Ok(result) if repl.quiet => println!("{}", result.with_heap(&heap)),
+                Ok(result) => println!("out: {}", result.with_heap(&heap)),
+                Err(e) => match e.exit_status() {
+                    Some(status) => break 'repl status,
+                    None => println!("err: {}", e),
+                },
             }
         }
-        heap.collect();
+        heap.collect_garbage();
         //let _ = heap.dump();
-    }
+    };
+    heap.output.flush().unwrap();
+    std::process::exit(status);
 }
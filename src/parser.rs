@@ -1,37 +1,78 @@
 use crate::lexer::Token;
 use crate::{Expr, Heap};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::iter::Peekable;
+use std::rc::Rc;
 
 #[derive(Debug)]
 pub enum ParseError {
     AmbiguousValue,
     UnexpectedDot,
+    UndefinedLabel,
     UnexpectedEndOfInput,
     UnmatchedBracket,
+    UnterminatedString,
+    UnterminatedSymbol,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::AmbiguousValue => write!(f, "unrecognised value"),
+            ParseError::UnexpectedDot => write!(f, "unexpected dot"),
+            ParseError::UndefinedLabel => write!(f, "reference to an undefined datum label"),
+            ParseError::UnexpectedEndOfInput => write!(f, "unexpected end of input"),
+            ParseError::UnmatchedBracket => write!(f, "unmatched close bracket"),
+            ParseError::UnterminatedString => write!(f, "unterminated string"),
+            ParseError::UnterminatedSymbol => write!(f, "unterminated |symbol|"),
+        }
+    }
 }
 
 pub(crate) fn parse_expr(
     input: &mut Peekable<impl Iterator<Item = Token>>,
     heap: &mut Heap,
+) -> Result<Expr, ParseError> {
+    parse_datum(input, heap, &mut HashMap::new())
+}
+
+fn parse_datum(
+    input: &mut Peekable<impl Iterator<Item = Token>>,
+    heap: &mut Heap,
+    labels: &mut HashMap<u64, Expr>,
 ) -> Result<Expr, ParseError> {
     match input.next() {
         None => Err(ParseError::UnexpectedEndOfInput),
-        Some(Token::Value(v)) => parse_value(&v, heap),
+        Some(Token::Value(v)) => {
+            if v == "#" && matches!(input.peek(), Some(Token::LBracket)) {
+                parse_vector(input, heap, labels)
+            } else if let Some(n) = parse_label(&v, '=') {
+                parse_labelled(n, input, heap, labels)
+            } else if let Some(n) = parse_label(&v, '#') {
+                labels.get(&n).cloned().ok_or(ParseError::UndefinedLabel)
+            } else {
+                parse_value(&v, heap)
+            }
+        }
+        Some(Token::Str(s)) => Ok(Expr::String(Rc::new(RefCell::new(s)))),
+        Some(Token::Symbol(s)) => Ok(heap.intern_symbol(&s).unwrap()),
         Some(Token::Dot) => Err(ParseError::UnexpectedDot),
         Some(Token::Tick) => {
             // for now we will assume that make_cons and make_symbol won't fail here
             let q = heap.make_symbol("QUOTE").unwrap();
-            let inner = parse_expr(input, heap)?;
+            let inner = parse_datum(input, heap, labels)?;
             let c1 = heap.make_cons(inner, Expr::Nil).unwrap();
             let c2 = heap.make_cons(q, c1).unwrap();
-            return Ok(c2);
+            Ok(c2)
         }
         Some(Token::LBracket) => {
             if let Some(Token::RBracket) = input.peek() {
                 input.next().unwrap();
                 return Ok(Expr::Nil);
             }
-            let first = parse_expr(input, heap)?;
+            let first = parse_datum(input, heap, labels)?;
             let result = heap.make_cons(first, Expr::Nil).unwrap();
             let mut result_tail = result.clone();
             loop {
@@ -44,7 +85,7 @@ pub(crate) fn parse_expr(
                     input.next().unwrap();
                     has_dot = true;
                 }
-                let next = parse_expr(input, heap)?;
+                let next = parse_datum(input, heap, labels)?;
                 if has_dot {
                     heap.set_rest(&result_tail, next).unwrap();
                     if let Some(Token::RBracket) = input.peek() {
@@ -63,12 +104,85 @@ pub(crate) fn parse_expr(
     }
 }
 
+fn parse_vector(
+    input: &mut Peekable<impl Iterator<Item = Token>>,
+    heap: &mut Heap,
+    labels: &mut HashMap<u64, Expr>,
+) -> Result<Expr, ParseError> {
+    // #(a b c) is read as a list and then copied into a vector
+    let mut list = parse_datum(input, heap, labels)?;
+    let mut items = Vec::new();
+    while !list.is_nil() {
+        let (first, rest) = heap
+            .get_first_rest(&list)
+            .map_err(|_| ParseError::UnexpectedDot)?;
+        items.push(first);
+        list = rest;
+    }
+    Ok(Expr::Vector(Rc::new(RefCell::new(items))))
+}
+
+fn parse_label(v: &str, terminator: char) -> Option<u64> {
+    v.strip_prefix('#')?.strip_suffix(terminator)?.parse().ok()
+}
+
+fn parse_labelled(
+    n: u64,
+    input: &mut Peekable<impl Iterator<Item = Token>>,
+    heap: &mut Heap,
+    labels: &mut HashMap<u64, Expr>,
+) -> Result<Expr, ParseError> {
+    // references inside the datum see a placeholder cell which we patch afterwards
+    let placeholder = heap.make_cons(Expr::Nil, Expr::Nil).unwrap();
+    labels.insert(n, placeholder.clone());
+    let datum = parse_datum(input, heap, labels)?;
+    if datum == placeholder {
+        return Err(ParseError::UndefinedLabel);
+    }
+    labels.insert(n, datum.clone());
+    let mut seen = HashSet::new();
+    let mut worklist = vec![datum.clone()];
+    let mut seen_vectors = HashSet::new();
+    while let Some(e) = worklist.pop() {
+        if let Expr::Vector(v) = &e {
+            if seen_vectors.insert(Rc::as_ptr(v)) {
+                for item in v.borrow_mut().iter_mut() {
+                    if *item == placeholder {
+                        *item = datum.clone();
+                    } else {
+                        worklist.push(item.clone());
+                    }
+                }
+            }
+        } else if let Expr::Pair(k) = e {
+            if !seen.insert(k.0) {
+                continue;
+            }
+            let (first, rest) = heap.get_first_rest(&e).unwrap();
+            if first == placeholder {
+                heap.set_first(&e, datum.clone()).unwrap();
+            } else {
+                worklist.push(first);
+            }
+            if rest == placeholder {
+                heap.set_rest(&e, datum.clone()).unwrap();
+            } else {
+                worklist.push(rest);
+            }
+        }
+    }
+    Ok(datum)
+}
+
 fn parse_value(v: &str, heap: &mut Heap) -> Result<Expr, ParseError> {
+    if let Some(name) = v.strip_prefix("#\\") {
+        return parse_char(name);
+    }
     if v.starts_with('#') {
         match v {
-            "#f" => return Ok(Expr::Boolean(false)),
-            "#t" => return Ok(Expr::Boolean(true)),
-            _ => return Err(ParseError::AmbiguousValue),
+            "#f" | "#false" => return Ok(Expr::Boolean(false)),
+            "#t" | "#true" => return Ok(Expr::Boolean(true)),
+            _ => return parse_radix_integer(v),
         }
     }
     if v.starts_with(|c: char| c.is_ascii_digit() || c == '-') {
@@ -84,3 +198,35 @@ fn parse_value(v: &str, heap: &mut Heap) -> Result<Expr, ParseError> {
     }
     Ok(heap.make_symbol(v).unwrap())
 }
+
+fn parse_radix_integer(v: &str) -> Result<Expr, ParseError> {
+    // #b101, #o17, #d99 and #xFF, optionally with a minus sign after the prefix
+    let mut chars = v.chars().skip(1);
+    let radix = match chars.next().map(|c| c.to_ascii_lowercase()) {
+        Some('b') => 2,
+        Some('o') => 8,
+        Some('d') => 10,
+        Some('x') => 16,
+        _ => return Err(ParseError::AmbiguousValue),
+    };
+    let digits = &v[2..];
+    if digits.starts_with('+') {
+        return Err(ParseError::AmbiguousValue);
+    }
+    i64::from_str_radix(digits, radix)
+        .map(Expr::Integer)
+        .map_err(|_| ParseError::AmbiguousValue)
+}
+
+fn parse_char(name: &str) -> Result<Expr, ParseError> {
+    let mut chars = name.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => Ok(Expr::Char(c)),
+        _ => match name {
+            "space" => Ok(Expr::Char(' ')),
+            "newline" => Ok(Expr::Char('\n')),
+            "tab" => Ok(Expr::Char('\t')),
+            _ => Err(ParseError::AmbiguousValue),
+        },
+    }
+}
@@ -0,0 +1,109 @@
+use std::io::BufRead;
+
+#[derive(Debug)]
+pub(crate) enum Port {
+    // console input is read from stdin a line at a time, as it is needed
+    Input {
+        chars: Vec<char>,
+        pos: usize,
+        console: bool,
+    },
+    StringOutput(String),
+    // console output goes to the heap's output
+    ConsoleOutput,
+}
+
+// ports are stateful, so two ports are only the same if they are the same object
+impl PartialEq for Port {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+impl Eq for Port {}
+
+impl Port {
+    pub(crate) fn input_string(s: &str) -> Self {
+        Port::Input {
+            chars: s.chars().collect(),
+            pos: 0,
+            console: false,
+        }
+    }
+
+    pub(crate) fn console_input() -> Self {
+        Port::Input {
+            chars: Vec::new(),
+            pos: 0,
+            console: true,
+        }
+    }
+
+    pub(crate) fn output_string() -> Self {
+        Port::StringOutput(String::new())
+    }
+
+    pub(crate) fn is_input(&self) -> bool {
+        matches!(self, Port::Input { .. })
+    }
+
+    pub(crate) fn is_output(&self) -> bool {
+        !self.is_input()
+    }
+
+    // reads another line from stdin for a console port, returning false at the end
+    pub(crate) fn fill(&mut self) -> bool {
+        let Port::Input {
+            chars,
+            console: true,
+            ..
+        } = self
+        else {
+            return false;
+        };
+        let mut line = String::new();
+        match std::io::stdin().lock().read_line(&mut line) {
+            Ok(n) if n > 0 => {
+                chars.extend(line.chars());
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // the characters that haven't been read yet
+    pub(crate) fn unread(&self) -> &[char] {
+        match self {
+            Port::Input { chars, pos, .. } => &chars[*pos..],
+            _ => &[],
+        }
+    }
+
+    pub(crate) fn consume(&mut self, n: usize) {
+        if let Port::Input { pos, .. } = self {
+            *pos += n;
+        }
+    }
+
+    pub(crate) fn peek_char(&mut self) -> Option<char> {
+        if self.unread().is_empty() {
+            self.fill();
+        }
+        self.unread().first().copied()
+    }
+
+    pub(crate) fn read_char(&mut self) -> Option<char> {
+        let c = self.peek_char();
+        if c.is_some() {
+            self.consume(1);
+        }
+        c
+    }
+
+    pub(crate) fn contents(&self) -> Option<&str> {
+        match self {
+            Port::StringOutput(buffer) => Some(buffer),
+            _ => None,
+        }
+    }
+}
@@ -1,25 +1,683 @@
-use std::rc::Rc;
+use std::{
+    cell::RefCell,
+    cmp::Ordering,
+    collections::HashSet,
+    rc::Rc,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
-use crate::{Expr, Heap, Native, PrimitiveDef, SError, SResult};
+use crate::hashtable::{Equality, HashTable};
+use crate::lexer::next_token;
+use crate::parser::{parse_expr, ParseError};
+use crate::port::Port;
+use crate::{Arity, Expr, Heap, Native, PrimitiveDef, SError, SResult, WriteMode, FEATURES};
 
 fn validate_arg_count(heap: &Heap, args: &Expr, n: usize) -> SResult<()> {
-    if !heap.test_length(args, n)? {
-        Err(SError::WrongNumberOfArgs)
-    } else {
-        Ok(())
+    Arity::exact(n).check(heap.list_length(args)?)
+}
+
+fn validate_arg_range(heap: &Heap, args: &Expr, min: usize, max: usize) -> SResult<()> {
+    Arity::range(min, max).check(heap.list_length(args)?)
+}
+
+// checks there are exactly N arguments and returns them
+fn take_args<const N: usize>(heap: &Heap, args: &Expr) -> SResult<[Expr; N]> {
+    let (required, _) = take_optional_args::<N, 0>(heap, args)?;
+    Ok(required)
+}
+
+// checks there are N required arguments followed by up to M optional ones, and
+// returns them with None for each optional argument that wasn't given
+fn take_optional_args<const N: usize, const M: usize>(
+    heap: &Heap,
+    args: &Expr,
+) -> SResult<([Expr; N], [Option<Expr>; M])> {
+    validate_arg_range(heap, args, N, N + M)?;
+    let mut v = args.clone();
+    let required = std::array::from_fn(|_| {
+        let (first, rest) = heap.get_first_rest(&v).unwrap();
+        v = rest;
+        first
+    });
+    let optional = std::array::from_fn(|_| {
+        let (first, rest) = heap.get_first_rest(&v).ok()?;
+        v = rest;
+        Some(first)
+    });
+    Ok((required, optional))
+}
+
+// defines a primitive from its argument names and the functions that extract
+// each one as a Rust value, so that the body only has to compute the result.
+// an argument with no extractor is passed on as it is:
+//
+//     native! { fn quotient(_heap, n: as_integer, d: as_divisor) { ... } }
+//     native! { fn do_lt(_heap, a, b) { ... } }
+macro_rules! native {
+    ($(fn $name:ident($heap:ident $(, $arg:ident $(: $extract:expr)?)*) $body:block)*) => {
+        $(
+            fn $name(args: &Expr, $heap: &mut Heap) -> SResult<Expr> {
+                let [$($arg),*] = take_args(&*$heap, args)?;
+                $($(let $arg = $extract(&$arg)?;)?)*
+                $body
+            }
+        )*
+    };
+}
+
+// the extractors for arguments of each type, which all fail with a type error
+// naming the type expected and the type given
+
+pub(crate) fn as_integer(expr: &Expr) -> SResult<i64> {
+    match expr {
+        Expr::Integer(n) => Ok(*n),
+        _ => Err(SError::type_error("integer", expr)),
+    }
+}
+
+fn as_index(expr: &Expr) -> SResult<usize> {
+    usize::try_from(as_integer(expr)?).map_err(|_| SError::IndexOutOfRange)
+}
+
+// the longest list, vector or string that can be made in one go, which is far
+// more than any program needs but keeps an absurd length from being an
+// allocation that takes the whole interpreter down
+const MAX_LENGTH: usize = 1 << 28;
+
+fn as_length(expr: &Expr) -> SResult<usize> {
+    match as_index(expr)? {
+        n if n > MAX_LENGTH => Err(SError::IndexOutOfRange),
+        n => Ok(n),
+    }
+}
+
+fn as_char(expr: &Expr) -> SResult<char> {
+    match expr {
+        Expr::Char(c) => Ok(*c),
+        _ => Err(SError::type_error("char", expr)),
+    }
+}
+
+fn as_string(expr: &Expr) -> SResult<Rc<RefCell<String>>> {
+    match expr {
+        Expr::String(s) => Ok(Rc::clone(s)),
+        _ => Err(SError::type_error("string", expr)),
+    }
+}
+
+fn as_symbol(expr: &Expr) -> SResult<Rc<str>> {
+    match expr {
+        Expr::Symbol(s) => Ok(Rc::clone(s)),
+        _ => Err(SError::type_error("symbol", expr)),
+    }
+}
+
+// a pair is returned as it is, ready for get_first and get_rest
+fn as_pair(expr: &Expr) -> SResult<&Expr> {
+    match expr {
+        Expr::Pair(_) => Ok(expr),
+        _ => Err(SError::type_error("pair", expr)),
+    }
+}
+
+// anything apply can call
+fn as_procedure(expr: &Expr) -> SResult<&Expr> {
+    match expr {
+        Expr::Closure(_) | Expr::Primitive(_) | Expr::Parameter(_) => Ok(expr),
+        _ => Err(SError::type_error("procedure", expr)),
+    }
+}
+
+fn as_vector(expr: &Expr) -> SResult<Rc<RefCell<Vec<Expr>>>> {
+    match expr {
+        Expr::Vector(v) => Ok(Rc::clone(v)),
+        _ => Err(SError::type_error("vector", expr)),
     }
 }
 
 fn first(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
     validate_arg_count(heap, args, 1)?;
     let arg = heap.get_first(args)?;
-    heap.get_first(&arg)
+    heap.get_first(as_pair(&arg)?)
 }
 
 fn rest(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
     validate_arg_count(heap, args, 1)?;
     let arg = heap.get_first(args)?;
-    heap.get_rest(&arg)
+    heap.get_rest(as_pair(&arg)?)
+}
+
+// PATH spells out the letters between c and r as bits (0 for a, 1 for d)
+// below a leading 1, so the last letter, which is applied first, is the lowest bit
+fn cxr<const PATH: usize>(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    validate_arg_count(heap, args, 1)?;
+    let mut v = heap.get_first(args)?;
+    let mut path = PATH;
+    while path > 1 {
+        v = if path & 1 == 0 {
+            heap.get_first(as_pair(&v)?)?
+        } else {
+            heap.get_rest(as_pair(&v)?)?
+        };
+        path >>= 1;
+    }
+    Ok(v)
+}
+
+fn cxr_name(path: usize) -> String {
+    let letters = (0..path.ilog2())
+        .rev()
+        .map(|i| if path >> i & 1 == 0 { 'a' } else { 'd' });
+    std::iter::once('c').chain(letters).chain(['r']).collect()
+}
+
+// every path of two to four letters, from caar (0b100) to cddddr (0b11111)
+const CXR_PRIMITIVES: [Native; 28] = [
+    cxr::<4>, cxr::<5>, cxr::<6>, cxr::<7>, cxr::<8>, cxr::<9>, cxr::<10>, cxr::<11>, cxr::<12>,
+    cxr::<13>, cxr::<14>, cxr::<15>, cxr::<16>, cxr::<17>, cxr::<18>, cxr::<19>, cxr::<20>,
+    cxr::<21>, cxr::<22>, cxr::<23>, cxr::<24>, cxr::<25>, cxr::<26>, cxr::<27>, cxr::<28>,
+    cxr::<29>, cxr::<30>, cxr::<31>,
+];
+
+fn list(args: &Expr, _heap: &mut Heap) -> SResult<Expr> {
+    Ok(args.clone())
+}
+
+fn last_pair_of(heap: &Heap, list: &Expr) -> SResult<Expr> {
+    let mut v = as_pair(list)?.clone();
+    loop {
+        let rest = heap.get_rest(&v)?;
+        if !rest.is_pair() {
+            return Ok(v);
+        }
+        v = rest;
+    }
+}
+
+fn last_pair(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    validate_arg_count(heap, args, 1)?;
+    let arg = heap.get_first(args)?;
+    last_pair_of(heap, &arg)
+}
+
+fn last(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    validate_arg_count(heap, args, 1)?;
+    let arg = heap.get_first(args)?;
+    heap.get_first(&last_pair_of(heap, &arg)?)
+}
+
+fn call1(heap: &mut Heap, proc: &Expr, arg: Expr) -> SResult<Expr> {
+    let args = heap.make_cons(arg, Expr::Nil)?;
+    heap.apply(proc, &args)
+}
+
+fn list_take(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    validate_arg_count(heap, args, 2)?;
+    let mut v = heap.get_first(args)?;
+    let n = as_index(&heap.get_first(&heap.get_rest(args)?)?)?;
+    let mut items = Vec::new();
+    for _ in 0..n {
+        if !v.is_pair() {
+            return Err(SError::IndexOutOfRange);
+        }
+        let (first, rest) = heap.get_first_rest(&v)?;
+        items.push(first);
+        v = rest;
+    }
+    heap.make_list(items)
+}
+
+fn list_drop(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    validate_arg_count(heap, args, 2)?;
+    let mut v = heap.get_first(args)?;
+    let n = as_index(&heap.get_first(&heap.get_rest(args)?)?)?;
+    for _ in 0..n {
+        if !v.is_pair() {
+            return Err(SError::IndexOutOfRange);
+        }
+        v = heap.get_rest(&v)?;
+    }
+    Ok(v)
+}
+
+fn make_list(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    let ([len], [fill]) = take_optional_args::<1, 1>(heap, args)?;
+    let fill = fill.unwrap_or(Expr::Unspecified);
+    heap.make_list(vec![fill; as_length(&len)?])
+}
+
+fn list_set(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    let [mut v, k, item] = take_args(heap, args)?;
+    for _ in 0..as_index(&k)? {
+        if !v.is_pair() {
+            return Err(SError::IndexOutOfRange);
+        }
+        v = heap.get_rest(&v)?;
+    }
+    if !v.is_pair() {
+        return Err(SError::IndexOutOfRange);
+    }
+    heap.set_first(&v, item)?;
+    Ok(Expr::Unspecified)
+}
+
+fn count(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    validate_arg_count(heap, args, 2)?;
+    let pred = heap.get_first(args)?;
+    let mut v = heap.get_first(&heap.get_rest(args)?)?;
+    let mut n = 0;
+    while !v.is_nil() {
+        let (first, rest) = heap.get_first_rest(&v)?;
+        if call1(heap, &pred, first)?.is_truthy() {
+            n += 1;
+        }
+        v = rest;
+    }
+    Ok(Expr::Integer(n))
+}
+
+fn find(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    validate_arg_count(heap, args, 2)?;
+    let pred = heap.get_first(args)?;
+    let mut v = heap.get_first(&heap.get_rest(args)?)?;
+    while !v.is_nil() {
+        let (first, rest) = heap.get_first_rest(&v)?;
+        if call1(heap, &pred, first.clone())?.is_truthy() {
+            return Ok(first);
+        }
+        v = rest;
+    }
+    Ok(Expr::Boolean(false))
+}
+
+fn any(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    validate_arg_count(heap, args, 2)?;
+    let pred = heap.get_first(args)?;
+    let mut v = heap.get_first(&heap.get_rest(args)?)?;
+    while !v.is_nil() {
+        let (first, rest) = heap.get_first_rest(&v)?;
+        let result = call1(heap, &pred, first)?;
+        if result.is_truthy() {
+            return Ok(result);
+        }
+        v = rest;
+    }
+    Ok(Expr::Boolean(false))
+}
+
+fn every(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    validate_arg_count(heap, args, 2)?;
+    let pred = heap.get_first(args)?;
+    let mut v = heap.get_first(&heap.get_rest(args)?)?;
+    let mut result = Expr::Boolean(true);
+    while !v.is_nil() {
+        let (first, rest) = heap.get_first_rest(&v)?;
+        result = call1(heap, &pred, first)?;
+        if !result.is_truthy() {
+            return Ok(result);
+        }
+        v = rest;
+    }
+    Ok(result)
+}
+
+fn list_index(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    validate_arg_count(heap, args, 2)?;
+    let pred = heap.get_first(args)?;
+    let mut v = heap.get_first(&heap.get_rest(args)?)?;
+    let mut n = 0;
+    while !v.is_nil() {
+        let (first, rest) = heap.get_first_rest(&v)?;
+        if call1(heap, &pred, first)?.is_truthy() {
+            return Ok(Expr::Integer(n));
+        }
+        n += 1;
+        v = rest;
+    }
+    Ok(Expr::Boolean(false))
+}
+
+fn partition(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    validate_arg_count(heap, args, 2)?;
+    let pred = heap.get_first(args)?;
+    let mut v = heap.get_first(&heap.get_rest(args)?)?;
+    let mut matching = Vec::new();
+    let mut others = Vec::new();
+    while !v.is_nil() {
+        let (first, rest) = heap.get_first_rest(&v)?;
+        if call1(heap, &pred, first.clone())?.is_truthy() {
+            matching.push(first);
+        } else {
+            others.push(first);
+        }
+        v = rest;
+    }
+    let matching = heap.make_list(matching)?;
+    let others = heap.make_list(others)?;
+    let values = heap.make_list(vec![matching, others])?;
+    heap.make_values(values)
+}
+
+// compares two values with an optional equality procedure, or equal? without one
+fn same_by(heap: &mut Heap, equality: &Expr, a: &Expr, b: &Expr) -> SResult<bool> {
+    if equality.is_nil() {
+        return heap.is_equal(a, b);
+    }
+    let proc = heap.get_first(equality)?;
+    let args = heap.make_list(vec![a.clone(), b.clone()])?;
+    Ok(heap.apply(&proc, &args)?.is_truthy())
+}
+
+fn delete(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    validate_arg_range(heap, args, 2, 3)?;
+    let (x, rest) = heap.get_first_rest(args)?;
+    let (mut v, equality) = heap.get_first_rest(&rest)?;
+    let mut kept = Vec::new();
+    while !v.is_nil() {
+        let (first, rest) = heap.get_first_rest(&v)?;
+        if !same_by(heap, &equality, &x, &first)? {
+            kept.push(first);
+        }
+        v = rest;
+    }
+    heap.make_list(kept)
+}
+
+fn delete_duplicates(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    // each element is compared with everything kept so far, so this is quadratic
+    validate_arg_range(heap, args, 1, 2)?;
+    let (mut v, equality) = heap.get_first_rest(args)?;
+    let mut kept: Vec<Expr> = Vec::new();
+    while !v.is_nil() {
+        let (first, rest) = heap.get_first_rest(&v)?;
+        let mut duplicate = false;
+        for k in &kept {
+            if same_by(heap, &equality, k, &first)? {
+                duplicate = true;
+                break;
+            }
+        }
+        if !duplicate {
+            kept.push(first);
+        }
+        v = rest;
+    }
+    heap.make_list(kept)
+}
+
+// takes one element from each list at a time, stopping at the end of the shortest
+fn list_rows(heap: &Heap, lists: &Expr) -> SResult<Vec<Vec<Expr>>> {
+    let mut columns = Vec::new();
+    let mut l = lists.clone();
+    while !l.is_nil() {
+        columns.push(heap.get_first(&l)?);
+        l = heap.get_rest(&l)?;
+    }
+    let mut rows = Vec::new();
+    while !columns.is_empty() && columns.iter().all(|c| c.is_pair()) {
+        let mut row = Vec::with_capacity(columns.len());
+        for c in columns.iter_mut() {
+            let (first, rest) = heap.get_first_rest(c)?;
+            row.push(first);
+            *c = rest;
+        }
+        rows.push(row);
+    }
+    Ok(rows)
+}
+
+// (fold kons knil list ...) calls (kons elem ... acc) from the left, while
+// (fold-left proc acc list ...) calls (proc acc elem ...)
+fn fold_with(args: &Expr, heap: &mut Heap, acc_first: bool, from_right: bool) -> SResult<Expr> {
+    let (proc, rest) = heap.get_first_rest(args)?;
+    let (mut acc, lists) = heap.get_first_rest(&rest)?;
+    let mut rows = list_rows(heap, &lists)?;
+    if from_right {
+        rows.reverse();
+    }
+    for mut row in rows {
+        if acc_first {
+            row.insert(0, acc);
+        } else {
+            row.push(acc);
+        }
+        let proc_args = heap.make_list(row)?;
+        acc = heap.apply(&proc, &proc_args)?;
+        heap.root(&acc);
+    }
+    Ok(acc)
+}
+
+fn fold(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    fold_with(args, heap, false, false)
+}
+
+fn fold_right(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    fold_with(args, heap, false, true)
+}
+
+fn fold_left(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    fold_with(args, heap, true, false)
+}
+
+// a list built from the front with set_rest, so that a long one needs neither
+// recursion nor reversing; its head is rooted as soon as it has one
+struct ListBuilder {
+    head: Expr,
+    tail: Expr,
+}
+
+impl ListBuilder {
+    fn new() -> Self {
+        ListBuilder {
+            head: Expr::Nil,
+            tail: Expr::Nil,
+        }
+    }
+
+    fn push(&mut self, heap: &mut Heap, item: Expr) -> SResult<()> {
+        let cell = heap.make_cons(item, Expr::Nil)?;
+        if self.head.is_nil() {
+            heap.root(&cell);
+            self.head = cell.clone();
+        } else {
+            heap.set_rest(&self.tail, cell.clone())?;
+        }
+        self.tail = cell;
+        Ok(())
+    }
+}
+
+// calls proc on the elements at each position in turn, up to the end of the
+// shortest list, passing visit each row of elements along with the result
+fn list_walk(
+    args: &Expr,
+    heap: &mut Heap,
+    mut visit: impl FnMut(&mut Heap, Vec<Expr>, Expr) -> SResult<()>,
+) -> SResult<()> {
+    let (proc, lists) = heap.get_first_rest(args)?;
+    let mut columns = Vec::new();
+    let mut l = lists;
+    while !l.is_nil() {
+        columns.push(heap.get_first(&l)?);
+        l = heap.get_rest(&l)?;
+    }
+    while columns.iter().all(Expr::is_pair) {
+        let mut row = Vec::with_capacity(columns.len());
+        for c in columns.iter_mut() {
+            let (first, rest) = heap.get_first_rest(c)?;
+            row.push(first);
+            *c = rest;
+        }
+        let proc_args = heap.make_list(row.clone())?;
+        let result = heap.apply(&proc, &proc_args)?;
+        visit(heap, row, result)?;
+    }
+    Ok(())
+}
+
+fn map(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    let mut results = ListBuilder::new();
+    list_walk(args, heap, |heap, _, result| results.push(heap, result))?;
+    Ok(results.head)
+}
+
+fn for_each(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    list_walk(args, heap, |_, _, _| Ok(()))?;
+    Ok(Expr::Unspecified)
+}
+
+fn filter(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    validate_arg_count(heap, args, 2)?;
+    let mut kept = ListBuilder::new();
+    list_walk(args, heap, |heap, mut row, result| {
+        if result.is_truthy() {
+            kept.push(heap, row.remove(0))?;
+        }
+        Ok(())
+    })?;
+    Ok(kept.head)
+}
+
+// (iota count [start [step]]) lists count integers from start, which defaults
+// to 0, going up by step, which defaults to 1
+fn iota(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    let ([count], [start, step]) = take_optional_args::<1, 2>(heap, args)?;
+    let start = start.as_ref().map_or(Ok(0), as_integer)?;
+    let step = step.as_ref().map_or(Ok(1), as_integer)?;
+    let mut items = ListBuilder::new();
+    let mut n = start;
+    for i in 0..as_index(&count)? {
+        // only an element that is actually listed has to fit
+        if i > 0 {
+            n = n.checked_add(step).ok_or(SError::IntegerOverflow)?;
+        }
+        items.push(heap, Expr::Integer(n))?;
+    }
+    Ok(items.head)
+}
+
+// (append-map proc list ...) appends the lists proc returns for each row of elements
+fn append_map(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    let (proc, lists) = heap.get_first_rest(args)?;
+    let mut result = Vec::new();
+    for row in list_rows(heap, &lists)? {
+        let proc_args = heap.make_list(row)?;
+        let mut v = heap.apply(&proc, &proc_args)?;
+        while !v.is_nil() {
+            let (item, rest) = heap.get_first_rest(&v)?;
+            heap.root(&item);
+            result.push(item);
+            v = rest;
+        }
+    }
+    heap.make_list(result)
+}
+
+// splices nested lists into one list of their atoms, dropping empty lists; the
+// same list may appear more than once, but one containing itself is an error
+fn flatten(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    validate_arg_count(heap, args, 1)?;
+    let mut result = Vec::new();
+    // the cells of the lists currently being walked, to catch cycles
+    let mut path = HashSet::new();
+    // each list being walked, with the cells of it walked so far
+    let mut stack = vec![(heap.get_first(args)?, Vec::new())];
+    while let Some((v, walked)) = stack.last_mut() {
+        match v {
+            Expr::Pair(k) => {
+                if !path.insert(k.0) {
+                    return Err(SError::ImproperList);
+                }
+                walked.push(k.0);
+                let (item, rest) = heap.get_first_rest(v)?;
+                *v = rest;
+                if item.is_pair() || item.is_nil() {
+                    stack.push((item, Vec::new()));
+                } else {
+                    result.push(item);
+                }
+            }
+            Expr::Nil => {
+                for k in stack.pop().unwrap().1 {
+                    path.remove(&k);
+                }
+            }
+            _ => return Err(SError::ImproperList),
+        }
+    }
+    heap.make_list(result)
+}
+
+// (reduce proc ridentity list) folds from the left like fold, with the first
+// element as the initial accumulator, giving ridentity for an empty list
+fn reduce(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    let [proc, ridentity, list] = take_args(heap, args)?;
+    if list.is_nil() {
+        return Ok(ridentity);
+    }
+    let (mut acc, mut v) = heap.get_first_rest(&list)?;
+    while !v.is_nil() {
+        let (item, rest) = heap.get_first_rest(&v)?;
+        let proc_args = heap.make_list(vec![item, acc])?;
+        acc = heap.apply(&proc, &proc_args)?;
+        heap.root(&acc);
+        v = rest;
+    }
+    Ok(acc)
+}
+
+fn zip(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    let rows = list_rows(heap, args)?;
+    let mut result = Vec::with_capacity(rows.len());
+    for row in rows {
+        result.push(heap.make_list(row)?);
+    }
+    heap.make_list(result)
+}
+
+fn unzip2(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    validate_arg_count(heap, args, 1)?;
+    let mut v = heap.get_first(args)?;
+    let mut firsts = Vec::new();
+    let mut seconds = Vec::new();
+    while !v.is_nil() {
+        let (row, rest) = heap.get_first_rest(&v)?;
+        let (first, row_rest) = heap.get_first_rest(&row)?;
+        firsts.push(first);
+        seconds.push(heap.get_first(&row_rest)?);
+        v = rest;
+    }
+    let firsts = heap.make_list(firsts)?;
+    let seconds = heap.make_list(seconds)?;
+    let values = heap.make_list(vec![firsts, seconds])?;
+    heap.make_values(values)
+}
+
+fn alist_update(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    // copies the entries before the key and shares the ones after it, leaving
+    // the original list unchanged
+    validate_arg_count(heap, args, 3)?;
+    let (key, rest) = heap.get_first_rest(args)?;
+    let (value, rest) = heap.get_first_rest(&rest)?;
+    let alist = heap.get_first(&rest)?;
+    let mut before = Vec::new();
+    let mut v = alist.clone();
+    while !v.is_nil() {
+        let (entry, rest) = heap.get_first_rest(&v)?;
+        if heap.is_equal(&heap.get_first(&entry)?, &key)? {
+            let mut result = heap.make_cons(key, value)?;
+            result = heap.make_cons(result, rest)?;
+            for entry in before.into_iter().rev() {
+                result = heap.make_cons(entry, result)?;
+            }
+            return Ok(result);
+        }
+        before.push(entry);
+        v = rest;
+    }
+    let entry = heap.make_cons(key, value)?;
+    heap.make_cons(entry, alist)
 }
 
 fn list_p(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
@@ -35,107 +693,2215 @@ fn cons(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
     heap.make_cons(arg1, arg2)
 }
 
-fn as_integer(expr: &Expr) -> SResult<i64> {
-    match expr {
-        Expr::Integer(n) => Ok(*n),
-        _ => Err(SError::TypeError),
+// (cons* a b ... tail) conses the other arguments onto the last one
+fn cons_star(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    let mut items = Vec::new();
+    let mut v = args.clone();
+    while !v.is_nil() {
+        let (item, rest) = heap.get_first_rest(&v)?;
+        items.push(item);
+        v = rest;
     }
+    let mut result = items.pop().unwrap();
+    for item in items.into_iter().rev() {
+        result = heap.make_cons(item, result)?;
+    }
+    Ok(result)
 }
 
-fn do_arithmetic(
-    args: &Expr,
-    heap: &mut Heap,
-    identity: i64,
-    bin_op: impl Fn(i64, i64) -> i64,
-) -> SResult<Expr> {
-    if args.is_nil() {
-        return Err(SError::WrongNumberOfArgs);
-    } else if heap.get_rest(args)?.is_nil() {
-        return Ok(Expr::Integer(bin_op(
-            identity,
-            as_integer(&heap.get_first(args)?)?,
-        )));
+fn eq_p(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    validate_arg_count(heap, args, 2)?;
+    let arg1 = heap.get_first(args)?;
+    let arg2 = heap.get_first(&heap.get_rest(args)?)?;
+    Ok(Expr::Boolean(arg1.is_eq(&arg2)))
+}
+
+fn equal_p(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    validate_arg_count(heap, args, 2)?;
+    let arg1 = heap.get_first(args)?;
+    let arg2 = heap.get_first(&heap.get_rest(args)?)?;
+    Ok(Expr::Boolean(heap.is_equal(&arg1, &arg2)?))
+}
+
+fn equal_hash(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    validate_arg_count(heap, args, 1)?;
+    let arg = heap.get_first(args)?;
+    Ok(Expr::Integer(heap.equal_hash(&arg) as i64))
+}
+
+fn force(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    validate_arg_count(heap, args, 1)?;
+    let arg = heap.get_first(args)?;
+    heap.force(&arg)
+}
+
+fn command_line(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    validate_arg_count(heap, args, 0)?;
+    let strings = heap
+        .command_line
+        .iter()
+        .map(|arg| Expr::String(Rc::new(RefCell::new(arg.clone()))))
+        .collect();
+    heap.make_list(strings)
+}
+
+fn getenv(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    validate_arg_count(heap, args, 1)?;
+    let name = as_string(&heap.get_first(args)?)?;
+    let value = std::env::var(&*name.borrow()).ok();
+    Ok(match value {
+        Some(value) => Expr::String(Rc::new(RefCell::new(value))),
+        None => Expr::Boolean(false),
+    })
+}
+
+fn current_time(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    validate_arg_count(heap, args, 0)?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    Ok(Expr::Integer(now as i64))
+}
+
+fn runtime(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    validate_arg_count(heap, args, 0)?;
+    Ok(Expr::Integer(heap.start.elapsed().as_millis() as i64))
+}
+
+fn random(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    validate_arg_count(heap, args, 1)?;
+    let arg = heap.get_first(args)?;
+    let n = match as_integer(&arg)? {
+        n if n > 0 => n as u64,
+        _ => return Err(SError::type_error("positive integer", &arg)),
+    };
+    // numbers past the last whole multiple of n are redrawn to avoid bias
+    let limit = u64::MAX - u64::MAX % n;
+    loop {
+        let x = heap.next_random();
+        if x < limit {
+            return Ok(Expr::Integer((x % n) as i64));
+        }
     }
-    let mut result = as_integer(&heap.get_first(args)?)?;
-    let mut v = heap.get_rest(args)?.clone();
-    while !v.is_nil() {
-        result = bin_op(result, as_integer(&heap.get_first(&v)?)?);
-        v = heap.get_rest(&v)?;
+}
+
+native! {
+    fn random_seed(heap, seed: as_integer) {
+        // scramble the seed so that small seeds don't start with small numbers,
+        // and keep the state nonzero
+        heap.random_state = (seed as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1;
+        Ok(Expr::Unspecified)
     }
-    Ok(Expr::Integer(result))
 }
 
-fn do_plus(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
-    do_arithmetic(args, heap, 0, |a, b| a + b)
+fn make_promise(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    // a promise is returned as it is, and anything else wrapped in a forced promise
+    validate_arg_count(heap, args, 1)?;
+    let arg = heap.get_first(args)?;
+    match arg {
+        Expr::Promise(_) => Ok(arg),
+        _ => heap.make_promise_box(Expr::Boolean(true), arg),
+    }
 }
 
-fn do_minus(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
-    do_arithmetic(args, heap, 0, |a, b| a - b)
+fn promise_p(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    validate_arg_count(heap, args, 1)?;
+    let arg = heap.get_first(args)?;
+    Ok(Expr::Boolean(matches!(arg, Expr::Promise(_))))
 }
 
-fn do_times(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
-    do_arithmetic(args, heap, 1, |a, b| a * b)
+fn stream_first(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    first(args, heap)
 }
 
-fn do_divide(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
-    do_arithmetic(args, heap, 1, |a, b| a / b)
+fn stream_rest(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    let tail = rest(args, heap)?;
+    heap.force(&tail)
 }
 
-fn do_predicate(args: &Expr, heap: &mut Heap, pred: impl Fn(i64, i64) -> bool) -> SResult<Expr> {
-    validate_arg_count(heap, args, 2)?;
-    let arg1 = as_integer(&heap.get_first(args)?)?;
-    let arg2 = as_integer(&heap.get_first(&heap.get_rest(args)?)?)?;
-    let result = pred(arg1, arg2);
-    Ok(Expr::Boolean(result))
+fn make_parameter(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    validate_arg_range(heap, args, 1, 2)?;
+    let value = heap.get_first(args)?;
+    let rest = heap.get_rest(args)?;
+    let converter = if rest.is_nil() {
+        Expr::Nil
+    } else {
+        heap.get_first(&rest)?
+    };
+    heap.make_parameter(value, converter)
 }
 
-fn do_numeq(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
-    do_predicate(args, heap, |a, b| a == b)
+fn values(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    // a single value is just itself
+    if !args.is_nil() && heap.get_rest(args)?.is_nil() {
+        return heap.get_first(args);
+    }
+    heap.make_values(args.clone())
 }
 
-fn do_lt(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
-    do_predicate(args, heap, |a, b| a < b)
+fn call_with_values(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    validate_arg_count(heap, args, 2)?;
+    let producer = heap.get_first(args)?;
+    let consumer = heap.get_first(&heap.get_rest(args)?)?;
+    let produced = heap.apply(&producer, &Expr::Nil)?;
+    let args = heap.values_list(produced)?;
+    heap.apply(&consumer, &args)
 }
 
-fn do_lte(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
-    do_predicate(args, heap, |a, b| a <= b)
+// (apply proc arg ... list) calls proc with the args followed by the elements of list
+fn apply(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    let (proc, mut v) = heap.get_first_rest(args)?;
+    let mut items = Vec::new();
+    loop {
+        let (item, rest) = heap.get_first_rest(&v)?;
+        if rest.is_nil() {
+            let mut spread = item;
+            while !spread.is_nil() {
+                let (item, rest) = heap.get_first_rest(&spread)?;
+                items.push(item);
+                spread = rest;
+            }
+            break;
+        }
+        items.push(item);
+        v = rest;
+    }
+    let proc_args = heap.make_list(items)?;
+    heap.apply(&proc, &proc_args)
 }
 
-fn do_gt(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
-    do_predicate(args, heap, |a, b| a > b)
+fn identity(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    let [arg] = take_args(heap, args)?;
+    Ok(arg)
 }
 
-fn do_gte(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
-    do_predicate(args, heap, |a, b| a >= b)
+// (compose f g h) is a closure (lambda args (f (g (apply h args)))), with the
+// procedures themselves in its body since they evaluate to themselves
+fn compose(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    let mut procs = Vec::new();
+    let mut v = args.clone();
+    while !v.is_nil() {
+        let (proc, rest) = heap.get_first_rest(&v)?;
+        procs.push(as_procedure(&proc)?.clone());
+        v = rest;
+    }
+    let Some(innermost) = procs.pop() else {
+        return Ok(primitive_expr("identity", Arity::exact(1), identity));
+    };
+    let params = heap.make_symbol("ARGS")?;
+    let apply = primitive_expr("apply", Arity::at_least(2), apply);
+    let mut call = heap.make_list(vec![apply, innermost, params.clone()])?;
+    for proc in procs.into_iter().rev() {
+        call = heap.make_list(vec![proc, call])?;
+    }
+    let body = heap.make_list(vec![call])?;
+    heap.make_closure(heap.root_env.clone(), params, body)
 }
 
-fn add_primitive(heap: &mut Heap, name: &str, func: Native) -> SResult<()> {
-    let sym = heap.make_symbol(name)?;
-    let env = heap.root_env.clone();
-    heap.env_set(
-        &env,
-        &sym,
-        Expr::Primitive(Rc::new(PrimitiveDef {
-            name: name.to_owned(),
-            func,
-        })),
-    )?;
-    Ok(())
+// (partial proc arg ...) is a closure (lambda args (apply proc 'arg ... args))
+fn partial(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    let (proc, mut v) = heap.get_first_rest(args)?;
+    as_procedure(&proc)?;
+    let params = heap.make_symbol("ARGS")?;
+    let quote = heap.make_symbol("QUOTE")?;
+    let mut call = vec![primitive_expr("apply", Arity::at_least(2), apply), proc];
+    while !v.is_nil() {
+        let (arg, rest) = heap.get_first_rest(&v)?;
+        call.push(heap.make_list(vec![quote.clone(), arg])?);
+        v = rest;
+    }
+    call.push(params.clone());
+    let call = heap.make_list(call)?;
+    let body = heap.make_list(vec![call])?;
+    heap.make_closure(heap.root_env.clone(), params, body)
 }
 
-pub(crate) fn add_primitives(heap: &mut Heap) -> SResult<()> {
-    add_primitive(heap, "first", first)?;
-    add_primitive(heap, "rest", rest)?;
-    add_primitive(heap, "list?", list_p)?;
-    add_primitive(heap, "cons", cons)?;
-    add_primitive(heap, "+", do_plus)?;
-    add_primitive(heap, "-", do_minus)?;
-    add_primitive(heap, "*", do_times)?;
-    add_primitive(heap, "/", do_divide)?;
-    add_primitive(heap, "=", do_numeq)?;
-    add_primitive(heap, "<", do_lt)?;
-    add_primitive(heap, "<=", do_lte)?;
-    add_primitive(heap, ">", do_gt)?;
-    add_primitive(heap, ">=", do_gte)?;
+fn memoized_call(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    let [table, proc, proc_args] = take_args(heap, args)?;
+    let t = as_hash_table(&table)?;
+    let found = t.borrow().get(heap, &proc_args)?;
+    if let Some(result) = found {
+        return Ok(result);
+    }
+    let result = heap.apply(&proc, &proc_args)?;
+    t.borrow_mut().insert(heap, proc_args, result.clone())?;
+    Ok(result)
+}
+
+// (memoize proc) is a closure (lambda args (memoized-call 'table proc args))
+// whose table caches results keyed by argument lists compared with equal?
+fn memoize(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    let [proc] = take_args(heap, args)?;
+    as_procedure(&proc)?;
+    let table = Expr::HashTable(Rc::new(RefCell::new(HashTable::new(Equality::Equal))));
+    let params = heap.make_symbol("ARGS")?;
+    let quote = heap.make_symbol("QUOTE")?;
+    let call = vec![
+        primitive_expr("memoized-call", Arity::exact(3), memoized_call),
+        heap.make_list(vec![quote, table])?,
+        proc,
+        params.clone(),
+    ];
+    let call = heap.make_list(call)?;
+    let body = heap.make_list(vec![call])?;
+    heap.make_closure(heap.root_env.clone(), params, body)
+}
+
+fn with_exception_handler(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    let [handler, thunk] = take_args(heap, args)?;
+    as_procedure(&handler)?;
+    heap.with_exception_handler(&handler, &thunk)
+}
+
+fn raise(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    let [obj] = take_args(heap, args)?;
+    heap.raise(obj, false)
+}
+
+fn raise_continuable(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    let [obj] = take_args(heap, args)?;
+    heap.raise(obj, true)
+}
+
+// (error message irritant ...) raises an error object holding its arguments
+fn error(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    let (message, irritants) = heap.get_first_rest(args)?;
+    as_string(&message)?;
+    let condition = heap.make_condition(message, irritants)?;
+    heap.raise(condition, false)
+}
+
+fn as_condition(expr: &Expr) -> SResult<Expr> {
+    match expr {
+        Expr::Condition(k) => Ok(Expr::Pair(*k)),
+        _ => Err(SError::type_error("error object", expr)),
+    }
+}
+
+fn error_object_p(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    let [arg] = take_args(heap, args)?;
+    Ok(Expr::Boolean(matches!(arg, Expr::Condition(_))))
+}
+
+fn error_object_message(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    let [arg] = take_args(heap, args)?;
+    heap.get_first(&as_condition(&arg)?)
+}
+
+fn error_object_irritants(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    let [arg] = take_args(heap, args)?;
+    heap.get_rest(&as_condition(&arg)?)
+}
+
+fn dynamic_wind(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    validate_arg_count(heap, args, 3)?;
+    let before = heap.get_first(args)?;
+    let rest = heap.get_rest(args)?;
+    let thunk = heap.get_first(&rest)?;
+    let after = heap.get_first(&heap.get_rest(&rest)?)?;
+    heap.dynamic_wind(&before, &thunk, &after)
+}
+
+fn features(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    validate_arg_count(heap, args, 0)?;
+    let mut symbols = Vec::new();
+    for feature in FEATURES {
+        symbols.push(heap.make_symbol(feature)?);
+    }
+    heap.make_list(symbols)
+}
+
+fn collect_garbage(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    validate_arg_count(heap, args, 0)?;
+    heap.collect();
+    Ok(Expr::Unspecified)
+}
+
+// the optional status for exit: an integer, #t for success or #f for failure
+fn exit_status_arg(heap: &Heap, args: &Expr) -> SResult<i32> {
+    if args.is_nil() {
+        return Ok(0);
+    }
+    match heap.get_first(args)? {
+        Expr::Boolean(true) => Ok(0),
+        Expr::Boolean(false) => Ok(1),
+        Expr::Integer(n) => i32::try_from(n).map_err(|_| SError::IndexOutOfRange),
+        other => Err(SError::type_error("integer or boolean", &other)),
+    }
+}
+
+fn exit(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    validate_arg_range(heap, args, 0, 1)?;
+    Err(SError::Exit(exit_status_arg(heap, args)?))
+}
+
+fn emergency_exit(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    validate_arg_range(heap, args, 0, 1)?;
+    Err(SError::EmergencyExit(exit_status_arg(heap, args)?))
+}
+
+fn interaction_environment(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    validate_arg_count(heap, args, 0)?;
+    match heap.root_env {
+        Expr::Pair(k) => Ok(Expr::Environment(k)),
+        _ => Err(SError::ImproperEnvironment),
+    }
+}
+
+// an optional environment argument, defaulting to the interaction environment
+fn environment_arg(heap: &Heap, env_arg: &Expr) -> SResult<Expr> {
+    if env_arg.is_nil() {
+        return Ok(heap.root_env.clone());
+    }
+    match heap.get_first(env_arg)? {
+        Expr::Environment(k) => Ok(Expr::Pair(k)),
+        other => Err(SError::type_error("environment", &other)),
+    }
+}
+
+fn eval(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    validate_arg_range(heap, args, 1, 2)?;
+    let (expr, env_arg) = heap.get_first_rest(args)?;
+    let env = environment_arg(heap, &env_arg)?;
+    heap.eval_in(&env, &expr)
+}
+
+// (repl [env]) reads and evaluates expressions from the current input port until
+// it runs out, printing their values to the current output port like a quiet
+// REPL; errors are printed and skipped, but a malformed expression ends it
+fn repl(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    let env = environment_arg(heap, args)?;
+    heap.root(&env);
+    loop {
+        let expr = match read(&Expr::Nil, heap) {
+            Ok(Expr::Eof) => return Ok(Expr::Unspecified),
+            Ok(expr) => expr,
+            Err(e) => {
+                heap.write_output(&format!("err: {}\n", e))?;
+                return Ok(Expr::Unspecified);
+            }
+        };
+        let text = match heap.eval_in(&env, &expr) {
+            Ok(Expr::Unspecified) => continue,
+            Ok(result) => heap.format_expr(&result)?,
+            Err(e) if e.exit_status().is_some() => return Err(e),
+            Err(e) => format!("err: {}", e),
+        };
+        heap.write_output(&(text + "\n"))?;
+    }
+}
+
+fn procedure_p(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    validate_arg_count(heap, args, 1)?;
+    let arg = heap.get_first(args)?;
+    Ok(Expr::Boolean(matches!(
+        arg,
+        Expr::Closure(_) | Expr::Primitive(_) | Expr::Parameter(_)
+    )))
+}
+
+fn procedure_arity(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    // a fixed arity is returned as a number, otherwise as (min . max) with #f for no maximum
+    validate_arg_count(heap, args, 1)?;
+    let arg = heap.get_first(args)?;
+    let arity = match &arg {
+        Expr::Closure(_) => heap.get_lambda_arity(&arg)?,
+        Expr::Primitive(p) => p.arity,
+        Expr::Parameter(_) => Arity::exact(0),
+        _ => return Err(SError::type_error("procedure", &arg)),
+    };
+    match arity.max {
+        Some(max) if max == arity.min => Ok(Expr::Integer(max as i64)),
+        max => heap.make_cons(
+            Expr::Integer(arity.min as i64),
+            max.map_or(Expr::Boolean(false), |n| Expr::Integer(n as i64)),
+        ),
+    }
+}
+
+fn make_string(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    let ([len], [fill]) = take_optional_args(heap, args)?;
+    let len = as_length(&len)?;
+    let fill = match fill {
+        Some(fill) => as_char(&fill)?,
+        None => ' ',
+    };
+    let s = std::iter::repeat_n(fill, len).collect();
+    Ok(Expr::String(Rc::new(RefCell::new(s))))
+}
+
+fn string_set(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    let [s, k, c] = take_args(heap, args)?;
+    let s = as_string(&s)?;
+    let k = as_index(&k)?;
+    let c = as_char(&c)?;
+    let mut chars: Vec<char> = s.borrow().chars().collect();
+    *chars.get_mut(k).ok_or(SError::IndexOutOfRange)? = c;
+    *s.borrow_mut() = chars.into_iter().collect();
+    Ok(Expr::Unspecified)
+}
+
+fn string_fill(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    let [s, c] = take_args(heap, args)?;
+    let s = as_string(&s)?;
+    let c = as_char(&c)?;
+    let len = s.borrow().chars().count();
+    *s.borrow_mut() = std::iter::repeat_n(c, len).collect();
+    Ok(Expr::Unspecified)
+}
+
+pub(crate) fn make_string_expr(s: String) -> Expr {
+    Expr::String(Rc::new(RefCell::new(s)))
+}
+
+// a separator for string-split and string-join, which is a char or a string
+fn as_separator(expr: &Expr) -> SResult<String> {
+    match expr {
+        Expr::Char(c) => Ok(c.to_string()),
+        Expr::String(s) => Ok(s.borrow().clone()),
+        _ => Err(SError::type_error("char or string", expr)),
+    }
+}
+
+fn string_split(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    // every field is kept, so consecutive separators or one at either end give
+    // empty strings, just as string-join would need to rebuild the original
+    validate_arg_count(heap, args, 2)?;
+    let s = as_string(&heap.get_first(args)?)?;
+    let separator_arg = heap.get_first(&heap.get_rest(args)?)?;
+    let separator = as_separator(&separator_arg)?;
+    if separator.is_empty() {
+        return Err(SError::type_error("non-empty separator", &separator_arg));
+    }
+    let fields = s
+        .borrow()
+        .split(separator.as_str())
+        .map(|field| make_string_expr(field.to_owned()))
+        .collect();
+    heap.make_list(fields)
+}
+
+fn string_join(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    let ([mut v], [separator]) = take_optional_args(heap, args)?;
+    let separator = match separator {
+        Some(separator) => as_separator(&separator)?,
+        None => " ".to_owned(),
+    };
+    let mut strings = Vec::new();
+    while !v.is_nil() {
+        let (first, rest) = heap.get_first_rest(&v)?;
+        strings.push(as_string(&first)?.borrow().clone());
+        v = rest;
+    }
+    Ok(make_string_expr(strings.join(&separator)))
+}
+
+// the two string arguments of the searching primitives
+fn string_pair_args(heap: &Heap, args: &Expr) -> SResult<(String, String)> {
+    validate_arg_count(heap, args, 2)?;
+    let a = as_string(&heap.get_first(args)?)?.borrow().clone();
+    let b = as_string(&heap.get_first(&heap.get_rest(args)?)?)?
+        .borrow()
+        .clone();
+    Ok((a, b))
+}
+
+fn string_contains(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    let (s, pattern) = string_pair_args(heap, args)?;
+    // indices count chars rather than bytes, like everything else on strings
+    Ok(match s.find(&pattern) {
+        Some(i) => Expr::Integer(s[..i].chars().count() as i64),
+        None => Expr::Boolean(false),
+    })
+}
+
+fn string_index(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    validate_arg_count(heap, args, 2)?;
+    let s = as_string(&heap.get_first(args)?)?.borrow().clone();
+    let target = heap.get_first(&heap.get_rest(args)?)?;
+    for (i, c) in s.chars().enumerate() {
+        let found = match target {
+            Expr::Char(t) => c == t,
+            _ => call1(heap, &target, Expr::Char(c))?.is_truthy(),
+        };
+        if found {
+            return Ok(Expr::Integer(i as i64));
+        }
+    }
+    Ok(Expr::Boolean(false))
+}
+
+// calls proc on the chars at each index in turn, up to the end of the shortest
+// string; the strings are copied first, so the procedure may change them
+fn string_walk(
+    args: &Expr,
+    heap: &mut Heap,
+    mut visit: impl FnMut(Expr) -> SResult<()>,
+) -> SResult<()> {
+    let (proc, strings) = heap.get_first_rest(args)?;
+    let mut columns = Vec::new();
+    let mut v = strings;
+    while !v.is_nil() {
+        let s = as_string(&heap.get_first(&v)?)?;
+        columns.push(s.borrow().chars().collect::<Vec<_>>());
+        v = heap.get_rest(&v)?;
+    }
+    let len = columns.iter().map(Vec::len).min().unwrap_or(0);
+    for i in 0..len {
+        let items = columns.iter().map(|c| Expr::Char(c[i])).collect();
+        let proc_args = heap.make_list(items)?;
+        visit(heap.apply(&proc, &proc_args)?)?;
+    }
+    Ok(())
+}
+
+fn string_map(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    let mut result = String::new();
+    string_walk(args, heap, |c| {
+        result.push(as_char(&c)?);
+        Ok(())
+    })?;
+    Ok(make_string_expr(result))
+}
+
+fn string_for_each(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    string_walk(args, heap, |_| Ok(()))?;
+    Ok(Expr::Unspecified)
+}
+
+fn string_prefix_p(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    let (prefix, s) = string_pair_args(heap, args)?;
+    Ok(Expr::Boolean(s.starts_with(&prefix)))
+}
+
+fn string_suffix_p(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    let (suffix, s) = string_pair_args(heap, args)?;
+    Ok(Expr::Boolean(s.ends_with(&suffix)))
+}
+
+fn string_upcase(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    validate_arg_count(heap, args, 1)?;
+    let s = as_string(&heap.get_first(args)?)?;
+    let upper = s.borrow().to_uppercase();
+    Ok(make_string_expr(upper))
+}
+
+fn string_downcase(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    validate_arg_count(heap, args, 1)?;
+    let s = as_string(&heap.get_first(args)?)?;
+    let lower = s.borrow().to_lowercase();
+    Ok(make_string_expr(lower))
+}
+
+fn string_foldcase(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    validate_arg_count(heap, args, 1)?;
+    let s = as_string(&heap.get_first(args)?)?;
+    let folded = fold_case(&s.borrow());
+    Ok(make_string_expr(folded))
+}
+
+// trims whitespace, or chars satisfying an optional predicate, from either end
+fn string_trim_with(args: &Expr, heap: &mut Heap, left: bool, right: bool) -> SResult<Expr> {
+    validate_arg_range(heap, args, 1, 2)?;
+    let s = as_string(&heap.get_first(args)?)?.borrow().clone();
+    let rest = heap.get_rest(args)?;
+    let pred = if rest.is_nil() {
+        None
+    } else {
+        Some(heap.get_first(&rest)?)
+    };
+    let mut trim = |c: char| -> SResult<bool> {
+        match &pred {
+            None => Ok(c.is_whitespace()),
+            Some(p) => Ok(call1(heap, p, Expr::Char(c))?.is_truthy()),
+        }
+    };
+    let chars: Vec<char> = s.chars().collect();
+    let mut start = 0;
+    let mut end = chars.len();
+    if left {
+        while start < end && trim(chars[start])? {
+            start += 1;
+        }
+    }
+    if right {
+        while end > start && trim(chars[end - 1])? {
+            end -= 1;
+        }
+    }
+    Ok(make_string_expr(chars[start..end].iter().collect()))
+}
+
+fn string_trim(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    string_trim_with(args, heap, true, true)
+}
+
+fn string_trim_left(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    string_trim_with(args, heap, true, false)
+}
+
+fn string_trim_right(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    string_trim_with(args, heap, false, true)
+}
+
+fn symbol_p(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    let [arg] = take_args(heap, args)?;
+    Ok(Expr::Boolean(arg.is_symbol()))
+}
+
+fn symbol_to_string(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    let [arg] = take_args(heap, args)?;
+    Ok(make_string_expr(as_symbol(&arg)?.to_string()))
+}
+
+fn string_to_symbol(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    let [arg] = take_args(heap, args)?;
+    // the name is kept as it is, like a symbol written between pipes
+    let name = as_string(&arg)?.borrow().clone();
+    heap.intern_symbol(&name)
+}
+
+// (gensym [prefix]) makes a symbol named by prefix and a number no gensym has used
+fn gensym(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    let ([], [prefix]) = take_optional_args::<0, 1>(heap, args)?;
+    let prefix = match prefix {
+        Some(prefix) => as_string(&prefix)?.borrow().clone(),
+        None => "g".to_owned(),
+    };
+    Ok(heap.gensym(&prefix))
+}
+
+fn symbol_append(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    let mut name = String::new();
+    let mut v = args.clone();
+    while !v.is_nil() {
+        match heap.get_first(&v)? {
+            Expr::Symbol(s) => name.push_str(&s),
+            Expr::String(s) => name.push_str(&s.borrow()),
+            e => return Err(SError::type_error("symbol or string", &e)),
+        }
+        v = heap.get_rest(&v)?;
+    }
+    heap.make_symbol(&name)
+}
+
+// writes to the port given as an optional argument, or else the current output port
+fn write_to_port_arg(heap: &mut Heap, port_arg: &Expr, text: &str) -> SResult<()> {
+    if port_arg.is_nil() {
+        heap.write_output(text)
+    } else {
+        let port = heap.get_first(port_arg)?;
+        as_output_port(&port)?;
+        heap.write_port(&port, text)
+    }
+}
+
+fn write_string(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    validate_arg_range(heap, args, 1, 2)?;
+    let (s, port_arg) = heap.get_first_rest(args)?;
+    let text = as_string(&s)?.borrow().clone();
+    write_to_port_arg(heap, &port_arg, &text)?;
+    Ok(Expr::Unspecified)
+}
+
+fn display(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    validate_arg_range(heap, args, 1, 2)?;
+    let (expr, port_arg) = heap.get_first_rest(args)?;
+    let text = heap.display_expr(&expr)?;
+    write_to_port_arg(heap, &port_arg, &text)?;
+    Ok(Expr::Unspecified)
+}
+
+fn write_with(args: &Expr, heap: &mut Heap, mode: WriteMode) -> SResult<Expr> {
+    validate_arg_range(heap, args, 1, 2)?;
+    let (expr, port_arg) = heap.get_first_rest(args)?;
+    let text = heap.write_expr(&expr, mode)?;
+    write_to_port_arg(heap, &port_arg, &text)?;
+    Ok(Expr::Unspecified)
+}
+
+fn write(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    write_with(args, heap, WriteMode::Cycles)
+}
+
+fn write_shared(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    write_with(args, heap, WriteMode::Shared)
+}
+
+fn write_simple(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    write_with(args, heap, WriteMode::Simple)
+}
+
+fn with_output_to_string(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    validate_arg_count(heap, args, 1)?;
+    let thunk = heap.get_first(args)?;
+    let port = Rc::new(RefCell::new(Port::output_string()));
+    // equivalent to parameterizing current-output-port around the thunk
+    let param = heap.current_output_port.clone();
+    let depth = heap.dynamic_bindings.len();
+    let old = heap.swap_parameter_value(&param, Expr::Port(Rc::clone(&port)))?;
+    heap.dynamic_bindings.push((param, old));
+    let result = heap.apply(&thunk, &Expr::Nil);
+    heap.unwind_dynamic_bindings(depth)?;
+    result?;
+    let text = port.borrow().contents().unwrap_or_default().to_owned();
+    Ok(Expr::String(Rc::new(RefCell::new(text))))
+}
+
+fn newline(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    validate_arg_range(heap, args, 0, 1)?;
+    write_to_port_arg(heap, args, "\n")?;
+    Ok(Expr::Unspecified)
+}
+
+fn run_tests(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    validate_arg_count(heap, args, 0)?;
+    let checks = std::mem::take(&mut heap.checks);
+    let mut report = String::new();
+    for check in &checks {
+        if let Some(failure) = &check.failure {
+            report.push_str(&format!("check failed: {}: {}\n", check.source, failure));
+        }
+    }
+    let passed = checks.iter().filter(|c| c.failure.is_none()).count();
+    report.push_str(&format!("{} of {} checks passed\n", passed, checks.len()));
+    heap.write_output(&report)?;
+    Ok(Expr::Unspecified)
+}
+
+fn pp(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    validate_arg_range(heap, args, 1, 2)?;
+    let expr = heap.get_first(args)?;
+    let rest = heap.get_rest(args)?;
+    let width = if rest.is_nil() {
+        79
+    } else {
+        as_index(&heap.get_first(&rest)?)?
+    };
+    let text = heap.format_expr_pretty(&expr, width)?;
+    heap.write_output(&(text + "\n"))?;
+    Ok(Expr::Unspecified)
+}
+
+fn print(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    let mut parts = Vec::new();
+    let mut v = args.clone();
+    while !v.is_nil() {
+        parts.push(heap.display_expr(&heap.get_first(&v)?)?);
+        v = heap.get_rest(&v)?;
+    }
+    heap.write_output(&(parts.join(" ") + "\n"))?;
+    Ok(Expr::Unspecified)
+}
+
+fn make_vector_expr(items: Vec<Expr>) -> Expr {
+    Expr::Vector(Rc::new(RefCell::new(items)))
+}
+
+// optional start and end arguments, defaulting to the whole of a sequence of length len
+fn range_args(heap: &Heap, range: &Expr, len: usize) -> SResult<(usize, usize)> {
+    let mut bounds = [0, len];
+    let mut v = range.clone();
+    for bound in bounds.iter_mut() {
+        if v.is_nil() {
+            break;
+        }
+        *bound = as_index(&heap.get_first(&v)?)?;
+        v = heap.get_rest(&v)?;
+    }
+    let [start, end] = bounds;
+    if start > end || end > len {
+        return Err(SError::IndexOutOfRange);
+    }
+    Ok((start, end))
+}
+
+fn vector(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    let mut items = Vec::new();
+    let mut v = args.clone();
+    while !v.is_nil() {
+        let (first, rest) = heap.get_first_rest(&v)?;
+        items.push(first);
+        v = rest;
+    }
+    Ok(make_vector_expr(items))
+}
+
+fn make_vector(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    validate_arg_range(heap, args, 1, 2)?;
+    let (len, rest) = heap.get_first_rest(args)?;
+    let fill = if rest.is_nil() {
+        Expr::Unspecified
+    } else {
+        heap.get_first(&rest)?
+    };
+    Ok(make_vector_expr(vec![fill; as_length(&len)?]))
+}
+
+fn vector_p(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    validate_arg_count(heap, args, 1)?;
+    Ok(Expr::Boolean(matches!(
+        heap.get_first(args)?,
+        Expr::Vector(_)
+    )))
+}
+
+fn vector_length(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    validate_arg_count(heap, args, 1)?;
+    let v = as_vector(&heap.get_first(args)?)?;
+    let len = v.borrow().len();
+    Ok(Expr::Integer(len as i64))
+}
+
+fn vector_ref(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    validate_arg_count(heap, args, 2)?;
+    let v = as_vector(&heap.get_first(args)?)?;
+    let k = as_index(&heap.get_first(&heap.get_rest(args)?)?)?;
+    let item = v.borrow().get(k).cloned();
+    item.ok_or(SError::IndexOutOfRange)
+}
+
+fn vector_set(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    validate_arg_count(heap, args, 3)?;
+    let v = as_vector(&heap.get_first(args)?)?;
+    let rest = heap.get_rest(args)?;
+    let k = as_index(&heap.get_first(&rest)?)?;
+    let item = heap.get_first(&heap.get_rest(&rest)?)?;
+    *v.borrow_mut().get_mut(k).ok_or(SError::IndexOutOfRange)? = item;
+    Ok(Expr::Unspecified)
+}
+
+fn vector_to_list(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    validate_arg_range(heap, args, 1, 3)?;
+    let (v, range) = heap.get_first_rest(args)?;
+    let v = as_vector(&v)?;
+    let (start, end) = range_args(heap, &range, v.borrow().len())?;
+    let items = v.borrow()[start..end].to_vec();
+    heap.make_list(items)
+}
+
+fn list_to_vector(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    validate_arg_count(heap, args, 1)?;
+    vector(&heap.get_first(args)?, heap)
+}
+
+// calls proc on the elements at each index in turn, up to the end of the shortest vector
+fn vector_walk(args: &Expr, heap: &mut Heap, mut visit: impl FnMut(Expr)) -> SResult<()> {
+    let (proc, vectors) = heap.get_first_rest(args)?;
+    let mut columns = Vec::new();
+    let mut v = vectors;
+    while !v.is_nil() {
+        columns.push(as_vector(&heap.get_first(&v)?)?);
+        v = heap.get_rest(&v)?;
+    }
+    let len = columns.iter().map(|c| c.borrow().len()).min().unwrap_or(0);
+    for i in 0..len {
+        // the procedure may change the vectors, so no borrow is held while it runs
+        let items = columns.iter().map(|c| c.borrow()[i].clone()).collect();
+        let proc_args = heap.make_list(items)?;
+        let result = heap.apply(&proc, &proc_args)?;
+        heap.root(&result);
+        visit(result);
+    }
+    Ok(())
+}
+
+fn vector_map(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    let mut results = Vec::new();
+    vector_walk(args, heap, |result| results.push(result))?;
+    Ok(make_vector_expr(results))
+}
+
+fn vector_for_each(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    vector_walk(args, heap, |_| ())?;
+    Ok(Expr::Unspecified)
+}
+
+// a merge sort, which is stable because an item only goes before an earlier
+// one if less says it must; the standard library's sorts can't stop at an error
+fn merge_sort(heap: &mut Heap, less: &Expr, mut items: Vec<Expr>) -> SResult<Vec<Expr>> {
+    if items.len() < 2 {
+        return Ok(items);
+    }
+    let right = items.split_off(items.len() / 2);
+    let left = merge_sort(heap, less, items)?;
+    let right = merge_sort(heap, less, right)?;
+    let mut merged = Vec::with_capacity(left.len() + right.len());
+    let mut left = left.into_iter().peekable();
+    let mut right = right.into_iter().peekable();
+    while let (Some(a), Some(b)) = (left.peek(), right.peek()) {
+        let args = heap.make_list(vec![b.clone(), a.clone()])?;
+        if heap.apply(less, &args)?.is_truthy() {
+            merged.extend(right.next());
+        } else {
+            merged.extend(left.next());
+        }
+    }
+    merged.extend(left);
+    merged.extend(right);
+    Ok(merged)
+}
+
+// (sort sequence less?) returns a sorted copy of a list or vector
+fn sort(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    let [sequence, less] = take_args(heap, args)?;
+    if let Expr::Vector(v) = &sequence {
+        let items = v.borrow().clone();
+        return Ok(make_vector_expr(merge_sort(heap, &less, items)?));
+    }
+    let mut items = Vec::new();
+    let mut v = sequence;
+    while !v.is_nil() {
+        let (item, rest) = heap.get_first_rest(&v)?;
+        items.push(item);
+        v = rest;
+    }
+    let sorted = merge_sort(heap, &less, items)?;
+    heap.make_list(sorted)
+}
+
+fn vector_sort(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    let [v, less] = take_args(heap, args)?;
+    let v = as_vector(&v)?;
+    let items = v.borrow().clone();
+    *v.borrow_mut() = merge_sort(heap, &less, items)?;
+    Ok(Expr::Unspecified)
+}
+
+// (vector-binary-search vector key compare) finds an index of key in a sorted
+// vector, where (compare element key) is negative, zero or positive as element
+// comes before, matches or comes after key, giving #f if it isn't there
+fn vector_binary_search(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    let [v, key, compare] = take_args(heap, args)?;
+    let v = as_vector(&v)?;
+    let (mut low, mut high) = (0, v.borrow().len());
+    while low < high {
+        let mid = low + (high - low) / 2;
+        // the comparison may change the vector, so no borrow is held while it runs
+        let item = v
+            .borrow()
+            .get(mid)
+            .cloned()
+            .ok_or(SError::IndexOutOfRange)?;
+        let compare_args = heap.make_list(vec![item, key.clone()])?;
+        match as_integer(&heap.apply(&compare, &compare_args)?)?.cmp(&0) {
+            Ordering::Less => low = mid + 1,
+            Ordering::Greater => high = mid,
+            Ordering::Equal => return Ok(Expr::Integer(mid as i64)),
+        }
+    }
+    Ok(Expr::Boolean(false))
+}
+
+fn vector_fill(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    validate_arg_range(heap, args, 2, 4)?;
+    let (v, rest) = heap.get_first_rest(args)?;
+    let v = as_vector(&v)?;
+    let (fill, range) = heap.get_first_rest(&rest)?;
+    let (start, end) = range_args(heap, &range, v.borrow().len())?;
+    v.borrow_mut()[start..end].fill(fill);
+    Ok(Expr::Unspecified)
+}
+
+fn vector_copy(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    validate_arg_range(heap, args, 1, 3)?;
+    let (v, range) = heap.get_first_rest(args)?;
+    let v = as_vector(&v)?;
+    let (start, end) = range_args(heap, &range, v.borrow().len())?;
+    let items = v.borrow()[start..end].to_vec();
+    Ok(make_vector_expr(items))
+}
+
+fn vector_copy_to(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    // (vector-copy! to at from [start [end]])
+    validate_arg_range(heap, args, 3, 5)?;
+    let (to, rest) = heap.get_first_rest(args)?;
+    let to = as_vector(&to)?;
+    let (at, rest) = heap.get_first_rest(&rest)?;
+    let at = as_index(&at)?;
+    let (from, range) = heap.get_first_rest(&rest)?;
+    let from = as_vector(&from)?;
+    let (start, end) = range_args(heap, &range, from.borrow().len())?;
+    if at + (end - start) > to.borrow().len() {
+        return Err(SError::IndexOutOfRange);
+    }
+    // going through a copy means that overlapping ranges of one vector work like memmove
+    let items = from.borrow()[start..end].to_vec();
+    to.borrow_mut()[at..at + items.len()].clone_from_slice(&items);
+    Ok(Expr::Unspecified)
+}
+
+fn as_hash_table(expr: &Expr) -> SResult<Rc<RefCell<HashTable>>> {
+    match expr {
+        Expr::HashTable(t) => Ok(Rc::clone(t)),
+        _ => Err(SError::type_error("hash table", expr)),
+    }
+}
+
+// the equality for a new table, from an optional eq? or equal? argument
+fn equality_arg(heap: &Heap, equality_arg: &Expr) -> SResult<Equality> {
+    if equality_arg.is_nil() {
+        return Ok(Equality::Equal);
+    }
+    match heap.get_first(equality_arg)? {
+        Expr::Primitive(p) if p.name == "eq?" => Ok(Equality::Eq),
+        Expr::Primitive(p) if p.name == "equal?" => Ok(Equality::Equal),
+        other => Err(SError::type_error("eq? or equal?", &other)),
+    }
+}
+
+fn make_hash_table(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    validate_arg_range(heap, args, 0, 1)?;
+    let equality = equality_arg(heap, args)?;
+    Ok(Expr::HashTable(Rc::new(RefCell::new(HashTable::new(
+        equality,
+    )))))
+}
+
+fn hash_table_to_alist(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    validate_arg_count(heap, args, 1)?;
+    let t = as_hash_table(&heap.get_first(args)?)?;
+    let entries: Vec<_> = t.borrow().entries().cloned().collect();
+    let mut result = Expr::Nil;
+    for (key, value) in entries {
+        let entry = heap.make_cons(key, value)?;
+        result = heap.make_cons(entry, result)?;
+    }
+    Ok(result)
+}
+
+fn alist_to_hash_table(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    validate_arg_range(heap, args, 1, 2)?;
+    let (mut alist, rest) = heap.get_first_rest(args)?;
+    let mut table = HashTable::new(equality_arg(heap, &rest)?);
+    // as with assoc, the first entry for a key is the one that counts
+    while !alist.is_nil() {
+        let (key, value) = heap.get_first_rest(&heap.get_first(&alist)?)?;
+        if table.get(heap, &key)?.is_none() {
+            table.insert(heap, key, value)?;
+        }
+        alist = heap.get_rest(&alist)?;
+    }
+    Ok(Expr::HashTable(Rc::new(RefCell::new(table))))
+}
+
+fn hash_table_p(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    validate_arg_count(heap, args, 1)?;
+    let arg = heap.get_first(args)?;
+    Ok(Expr::Boolean(matches!(arg, Expr::HashTable(_))))
+}
+
+fn hash_table_set(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    validate_arg_count(heap, args, 3)?;
+    let t = as_hash_table(&heap.get_first(args)?)?;
+    let rest = heap.get_rest(args)?;
+    let key = heap.get_first(&rest)?;
+    let value = heap.get_first(&heap.get_rest(&rest)?)?;
+    t.borrow_mut().insert(heap, key, value)?;
+    Ok(Expr::Unspecified)
+}
+
+fn hash_table_ref_default(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    validate_arg_count(heap, args, 3)?;
+    let t = as_hash_table(&heap.get_first(args)?)?;
+    let rest = heap.get_rest(args)?;
+    let key = heap.get_first(&rest)?;
+    let default = heap.get_first(&heap.get_rest(&rest)?)?;
+    let found = t.borrow().get(heap, &key)?;
+    Ok(found.unwrap_or(default))
+}
+
+// (hash-table-update! table key proc default-thunk) stores (proc value) for key,
+// where value is what's already there or else the result of (default-thunk)
+fn hash_table_update(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    let [table, key, proc, default] = take_args(heap, args)?;
+    let t = as_hash_table(&table)?;
+    let found = t.borrow().get(heap, &key)?;
+    let value = match found {
+        Some(value) => value,
+        None => heap.apply(&default, &Expr::Nil)?,
+    };
+    let value = call1(heap, &proc, value)?;
+    t.borrow_mut().insert(heap, key, value)?;
+    Ok(Expr::Unspecified)
+}
+
+fn hash_table_contains_p(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    validate_arg_count(heap, args, 2)?;
+    let t = as_hash_table(&heap.get_first(args)?)?;
+    let key = heap.get_first(&heap.get_rest(args)?)?;
+    let found = t.borrow().get(heap, &key)?;
+    Ok(Expr::Boolean(found.is_some()))
+}
+
+fn hash_table_delete(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    validate_arg_count(heap, args, 2)?;
+    let t = as_hash_table(&heap.get_first(args)?)?;
+    let key = heap.get_first(&heap.get_rest(args)?)?;
+    t.borrow_mut().remove(heap, &key)?;
+    Ok(Expr::Unspecified)
+}
+
+fn hash_table_count(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    validate_arg_count(heap, args, 1)?;
+    let t = as_hash_table(&heap.get_first(args)?)?;
+    let len = t.borrow().len();
+    Ok(Expr::Integer(len as i64))
+}
+
+fn as_input_port(expr: &Expr) -> SResult<Rc<RefCell<Port>>> {
+    match expr {
+        Expr::Port(p) if p.borrow().is_input() => Ok(Rc::clone(p)),
+        _ => Err(SError::type_error("input port", expr)),
+    }
+}
+
+fn as_output_port(expr: &Expr) -> SResult<Rc<RefCell<Port>>> {
+    match expr {
+        Expr::Port(p) if p.borrow().is_output() => Ok(Rc::clone(p)),
+        _ => Err(SError::type_error("output port", expr)),
+    }
+}
+
+fn open_input_string(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    validate_arg_count(heap, args, 1)?;
+    let s = as_string(&heap.get_first(args)?)?;
+    let port = Port::input_string(&s.borrow());
+    Ok(Expr::Port(Rc::new(RefCell::new(port))))
+}
+
+fn open_output_string(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    validate_arg_count(heap, args, 0)?;
+    Ok(Expr::Port(Rc::new(RefCell::new(Port::output_string()))))
+}
+
+fn get_output_string(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    validate_arg_count(heap, args, 1)?;
+    let port = as_output_port(&heap.get_first(args)?)?;
+    let text = port.borrow().contents().unwrap_or_default().to_owned();
+    Ok(Expr::String(Rc::new(RefCell::new(text))))
+}
+
+fn input_port_p(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    validate_arg_count(heap, args, 1)?;
+    Ok(Expr::Boolean(as_input_port(&heap.get_first(args)?).is_ok()))
+}
+
+fn output_port_p(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    validate_arg_count(heap, args, 1)?;
+    Ok(Expr::Boolean(
+        as_output_port(&heap.get_first(args)?).is_ok(),
+    ))
+}
+
+// the port given as an optional argument, or else the current input port
+fn input_port_arg(heap: &Heap, port_arg: &Expr) -> SResult<Rc<RefCell<Port>>> {
+    if port_arg.is_nil() {
+        as_input_port(&heap.parameter_value(&heap.current_input_port)?)
+    } else {
+        as_input_port(&heap.get_first(port_arg)?)
+    }
+}
+
+fn read_char(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    validate_arg_range(heap, args, 0, 1)?;
+    let port = input_port_arg(heap, args)?;
+    let c = port.borrow_mut().read_char();
+    Ok(c.map_or(Expr::Eof, Expr::Char))
+}
+
+fn peek_char(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    validate_arg_range(heap, args, 0, 1)?;
+    let port = input_port_arg(heap, args)?;
+    let c = port.borrow_mut().peek_char();
+    Ok(c.map_or(Expr::Eof, Expr::Char))
+}
+
+fn read_line(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    validate_arg_range(heap, args, 0, 1)?;
+    let port = input_port_arg(heap, args)?;
+    let mut port = port.borrow_mut();
+    let mut line = String::new();
+    loop {
+        match port.read_char() {
+            None if line.is_empty() => return Ok(Expr::Eof),
+            None | Some('\n') => break,
+            Some(c) => line.push(c),
+        }
+    }
+    Ok(Expr::String(Rc::new(RefCell::new(line))))
+}
+
+fn write_char(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    validate_arg_range(heap, args, 1, 2)?;
+    let (c, port_arg) = heap.get_first_rest(args)?;
+    let c = as_char(&c)?;
+    write_to_port_arg(heap, &port_arg, &c.to_string())?;
+    Ok(Expr::Unspecified)
+}
+
+// parses one datum from the start of chars, also returning how many chars it used
+fn read_datum(chars: &[char], heap: &mut Heap) -> (Result<Expr, ParseError>, usize) {
+    // tokens are taken one at a time so that only the datum's characters are used
+    let mut unread = chars.iter().copied().peekable();
+    let mut lex_error = None;
+    let mut tokens = std::iter::from_fn(|| {
+        next_token(&mut unread).unwrap_or_else(|e| {
+            lex_error = Some(e);
+            None
+        })
+    })
+    .peekable();
+    let result = if tokens.peek().is_none() {
+        Ok(Expr::Eof)
+    } else {
+        parse_expr(&mut tokens, heap)
+    };
+    drop(tokens);
+    let used = chars.len() - unread.len();
+    match (lex_error, result) {
+        (Some(e), _) => (Err(e), used),
+        (None, result) => (result, used),
+    }
+}
+
+fn read(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    validate_arg_range(heap, args, 0, 1)?;
+    let port = input_port_arg(heap, args)?;
+    let mut port = port.borrow_mut();
+    loop {
+        let (result, used) = read_datum(port.unread(), heap);
+        match result {
+            // a console port may have the rest of the datum on the next line
+            Ok(Expr::Eof)
+            | Err(
+                ParseError::UnexpectedEndOfInput
+                | ParseError::UnterminatedString
+                | ParseError::UnterminatedSymbol,
+            ) if port.fill() => continue,
+            _ => {
+                port.consume(used);
+                return result.map_err(SError::ReadError);
+            }
+        }
+    }
+}
+
+fn eof_object(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    validate_arg_count(heap, args, 0)?;
+    Ok(Expr::Eof)
+}
+
+fn eof_object_p(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    validate_arg_count(heap, args, 1)?;
+    Ok(Expr::Boolean(heap.get_first(args)? == Expr::Eof))
+}
+
+// combines result with each argument in turn, so (- a b c) is ((a - b) - c)
+fn do_arithmetic<'a>(
+    mut v: &'a Expr,
+    heap: &'a Heap,
+    mut result: i64,
+    bin_op: impl Fn(i64, i64) -> SResult<i64>,
+) -> SResult<Expr> {
+    while !v.is_nil() {
+        let (next, rest) = heap.first_rest_ref(v)?;
+        result = bin_op(result, as_integer(next)?)?;
+        v = rest;
+    }
+    Ok(Expr::Integer(result))
+}
+
+// (+) is 0 and (*) is 1, the values that leave a single argument unchanged
+fn do_plus(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    do_arithmetic(args, heap, 0, |a, b| {
+        a.checked_add(b).ok_or(SError::IntegerOverflow)
+    })
+}
+
+fn do_times(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    do_arithmetic(args, heap, 1, |a, b| {
+        a.checked_mul(b).ok_or(SError::IntegerOverflow)
+    })
+}
+
+// (- x) negates x
+fn do_minus(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    let (first, rest) = heap.first_rest_ref(args)?;
+    let first = as_integer(first)?;
+    if rest.is_nil() {
+        return first
+            .checked_neg()
+            .map(Expr::Integer)
+            .ok_or(SError::IntegerOverflow);
+    }
+    do_arithmetic(rest, heap, first, |a, b| {
+        a.checked_sub(b).ok_or(SError::IntegerOverflow)
+    })
+}
+
+// (/ x) is the reciprocal of x, which is only an integer when x is 1 or -1,
+// and the quotient of several arguments rounds towards zero at each step
+fn do_divide(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    let (first, rest) = heap.first_rest_ref(args)?;
+    let first = as_integer(first)?;
+    if rest.is_nil() {
+        return match first {
+            0 => Err(SError::DivisionByZero),
+            1 | -1 => Ok(Expr::Integer(first)),
+            _ => Err(SError::NonIntegerResult),
+        };
+    }
+    do_arithmetic(rest, heap, first, |a, b| match b {
+        0 => Err(SError::DivisionByZero),
+        b => truncate_quotient(a, b),
+    })
+}
+
+native! {
+    fn exact_integer_sqrt(heap, n: as_integer) {
+        if n < 0 {
+            return Err(SError::type_error("non-negative integer", &Expr::Integer(n)));
+        }
+        let s = n.isqrt();
+        let values = heap.make_list(vec![Expr::Integer(s), Expr::Integer(n - s * s)])?;
+        heap.make_values(values)
+    }
+}
+
+fn as_divisor(expr: &Expr) -> SResult<i64> {
+    match as_integer(expr)? {
+        0 => Err(SError::DivisionByZero),
+        d => Ok(d),
+    }
+}
+
+// the three ways of rounding a quotient: towards zero, towards negative
+// infinity, or so that the remainder is never negative. d is never zero, so
+// the only quotient that doesn't fit is that of the most negative integer by -1
+fn truncate_quotient(n: i64, d: i64) -> SResult<i64> {
+    n.checked_div(d).ok_or(SError::IntegerOverflow)
+}
+
+fn floor_quotient(n: i64, d: i64) -> SResult<i64> {
+    let q = truncate_quotient(n, d)?;
+    if n % d != 0 && (n < 0) != (d < 0) {
+        Ok(q - 1)
+    } else {
+        Ok(q)
+    }
+}
+
+fn euclidean_quotient(n: i64, d: i64) -> SResult<i64> {
+    n.checked_div_euclid(d).ok_or(SError::IntegerOverflow)
+}
+
+// the quotient and remainder of n and d, with the remainder always chosen so
+// that q * d + r = n. q * d may not fit even when r does, but wrapping still
+// gives the right r, and when q itself doesn't fit d is -1 and r is 0
+fn integer_division(
+    n: i64,
+    d: i64,
+    quotient: impl Fn(i64, i64) -> SResult<i64>,
+) -> (SResult<i64>, i64) {
+    let q = quotient(n, d);
+    let r = match q {
+        Ok(q) => n.wrapping_sub(q.wrapping_mul(d)),
+        Err(_) => 0,
+    };
+    (q, r)
+}
+
+fn both_division(
+    heap: &mut Heap,
+    n: i64,
+    d: i64,
+    quotient: impl Fn(i64, i64) -> SResult<i64>,
+) -> SResult<Expr> {
+    let (q, r) = integer_division(n, d, quotient);
+    let values = heap.make_list(vec![Expr::Integer(q?), Expr::Integer(r)])?;
+    heap.make_values(values)
+}
+
+native! {
+    fn floor_divide(heap, n: as_integer, d: as_divisor) {
+        both_division(heap, n, d, floor_quotient)
+    }
+
+    fn truncate_divide(heap, n: as_integer, d: as_divisor) {
+        both_division(heap, n, d, truncate_quotient)
+    }
+
+    fn euclidean_divide(heap, n: as_integer, d: as_divisor) {
+        both_division(heap, n, d, euclidean_quotient)
+    }
+
+    fn quotient(_heap, n: as_integer, d: as_divisor) {
+        Ok(Expr::Integer(truncate_quotient(n, d)?))
+    }
+
+    fn remainder(_heap, n: as_integer, d: as_divisor) {
+        Ok(Expr::Integer(integer_division(n, d, truncate_quotient).1))
+    }
+
+    fn floored_quotient(_heap, n: as_integer, d: as_divisor) {
+        Ok(Expr::Integer(floor_quotient(n, d)?))
+    }
+
+    fn modulo(_heap, n: as_integer, d: as_divisor) {
+        Ok(Expr::Integer(integer_division(n, d, floor_quotient).1))
+    }
+}
+
+fn bitwise_and(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    do_arithmetic(args, heap, -1, |a, b| Ok(a & b))
+}
+
+fn bitwise_or(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    do_arithmetic(args, heap, 0, |a, b| Ok(a | b))
+}
+
+fn bitwise_xor(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    do_arithmetic(args, heap, 0, |a, b| Ok(a ^ b))
+}
+
+native! {
+    fn bitwise_not(_heap, n: as_integer) {
+        Ok(Expr::Integer(!n))
+    }
+
+    fn arithmetic_shift(_heap, n: as_integer, count: as_integer) {
+        // shifting left is n * 2^count, which fails if any bit that matters
+        // moves out, while shifting right by the full width or more moves
+        // every bit out, leaving 0 or the sign
+        let result = if count >= 0 {
+            let shifted = u32::try_from(count)
+                .ok()
+                .and_then(|c| n.checked_shl(c).filter(|r| r >> c == n));
+            match shifted {
+                Some(r) => r,
+                None if n == 0 => 0,
+                None => return Err(SError::IntegerOverflow),
+            }
+        } else {
+            n >> count.unsigned_abs().min(63)
+        };
+        Ok(Expr::Integer(result))
+    }
+
+    fn zero_p(_heap, n: as_integer) {
+        Ok(Expr::Boolean(n == 0))
+    }
+
+    fn positive_p(_heap, n: as_integer) {
+        Ok(Expr::Boolean(n > 0))
+    }
+
+    fn negative_p(_heap, n: as_integer) {
+        Ok(Expr::Boolean(n < 0))
+    }
+
+    fn odd_p(_heap, n: as_integer) {
+        Ok(Expr::Boolean(n % 2 != 0))
+    }
+
+    fn even_p(_heap, n: as_integer) {
+        Ok(Expr::Boolean(n % 2 == 0))
+    }
+}
+
+// every number is an exact integer for now, so number?, complex?, real?,
+// rational?, integer? and exact-integer? all agree, and none is ever a nan or
+// infinite; they all give #f rather than an error for anything else
+fn number_p(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    let [arg] = take_args(heap, args)?;
+    Ok(Expr::Boolean(matches!(arg, Expr::Integer(_))))
+}
+
+fn exact_nonnegative_integer_p(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    let [arg] = take_args(heap, args)?;
+    Ok(Expr::Boolean(matches!(arg, Expr::Integer(n) if n >= 0)))
+}
+
+fn nan_p(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    take_args::<1>(heap, args)?;
+    Ok(Expr::Boolean(false))
+}
+
+fn finite_p(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    number_p(args, heap)
+}
+
+// the comparisons work on integers, chars or strings, but both arguments must
+// have the same type
+fn compare_values(a: &Expr, b: &Expr) -> SResult<Ordering> {
+    match (a, b) {
+        (Expr::Integer(x), Expr::Integer(y)) => Ok(x.cmp(y)),
+        (Expr::Char(x), Expr::Char(y)) => Ok(x.cmp(y)),
+        (Expr::String(x), Expr::String(y)) => Ok(x.borrow().cmp(&y.borrow())),
+        (Expr::Integer(_), _) => Err(SError::type_error("integer", b)),
+        (Expr::Char(_), _) => Err(SError::type_error("char", b)),
+        (Expr::String(_), _) => Err(SError::type_error("string", b)),
+        _ => Err(SError::type_error("integer, char or string", a)),
+    }
+}
+
+native! {
+    fn do_numeq(_heap, a, b) {
+        Ok(Expr::Boolean(compare_values(&a, &b)?.is_eq()))
+    }
+
+    fn do_lt(_heap, a, b) {
+        Ok(Expr::Boolean(compare_values(&a, &b)?.is_lt()))
+    }
+
+    fn do_lte(_heap, a, b) {
+        Ok(Expr::Boolean(compare_values(&a, &b)?.is_le()))
+    }
+
+    fn do_gt(_heap, a, b) {
+        Ok(Expr::Boolean(compare_values(&a, &b)?.is_gt()))
+    }
+
+    fn do_gte(_heap, a, b) {
+        Ok(Expr::Boolean(compare_values(&a, &b)?.is_ge()))
+    }
+}
+
+fn compare_chain<T>(
+    args: &Expr,
+    heap: &mut Heap,
+    extract: impl Fn(&Expr) -> SResult<T>,
+    pred: impl Fn(&T, &T) -> bool,
+) -> SResult<Expr> {
+    // callers are registered with at least two arguments
+    let mut prev = extract(&heap.get_first(args)?)?;
+    let mut v = heap.get_rest(args)?;
+    let mut result = true;
+    while !v.is_nil() {
+        let next = extract(&heap.get_first(&v)?)?;
+        result = result && pred(&prev, &next);
+        prev = next;
+        v = heap.get_rest(&v)?;
+    }
+    Ok(Expr::Boolean(result))
+}
+
+fn string_contents(expr: &Expr) -> SResult<String> {
+    Ok(as_string(expr)?.borrow().clone())
+}
+
+fn string_eq(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    compare_chain(args, heap, string_contents, |a, b| a == b)
+}
+
+// utf-8 byte order agrees with code point order, so String's Ord is lexicographic by code point
+fn string_lt(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    compare_chain(args, heap, string_contents, |a, b| a < b)
+}
+
+fn string_lte(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    compare_chain(args, heap, string_contents, |a, b| a <= b)
+}
+
+fn string_gt(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    compare_chain(args, heap, string_contents, |a, b| a > b)
+}
+
+fn string_gte(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    compare_chain(args, heap, string_contents, |a, b| a >= b)
+}
+
+// the optional radix argument of char->digit and digit->char
+fn radix_arg(heap: &Heap, rest: &Expr) -> SResult<u32> {
+    if rest.is_nil() {
+        return Ok(10);
+    }
+    let arg = heap.get_first(rest)?;
+    match as_integer(&arg)? {
+        n @ 2..=36 => Ok(n as u32),
+        _ => Err(SError::type_error("radix from 2 to 36", &arg)),
+    }
+}
+
+fn char_to_digit(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    validate_arg_range(heap, args, 1, 2)?;
+    let c = as_char(&heap.get_first(args)?)?;
+    let radix = radix_arg(heap, &heap.get_rest(args)?)?;
+    Ok(match c.to_digit(radix) {
+        Some(d) => Expr::Integer(d as i64),
+        None => Expr::Boolean(false),
+    })
+}
+
+fn digit_to_char(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    validate_arg_range(heap, args, 1, 2)?;
+    let n = as_integer(&heap.get_first(args)?)?;
+    let radix = radix_arg(heap, &heap.get_rest(args)?)?;
+    let digit = u32::try_from(n)
+        .ok()
+        .and_then(|d| char::from_digit(d, radix));
+    Ok(match digit {
+        Some(c) => Expr::Char(c),
+        None => Expr::Boolean(false),
+    })
+}
+
+// case folding as the lowercase of the uppercase, which matches Unicode's full
+// folding for nearly every char (so that both ß and SS fold to ss)
+fn fold_case(s: &str) -> String {
+    s.chars()
+        .flat_map(char::to_uppercase)
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+// a char can't become several, so one whose mapping would is left alone
+fn single_char(mut mapped: impl Iterator<Item = char>, c: char) -> char {
+    match (mapped.next(), mapped.next()) {
+        (Some(m), None) => m,
+        _ => c,
+    }
+}
+
+fn char_fold(c: char) -> char {
+    single_char(fold_case(c.encode_utf8(&mut [0; 4])).chars(), c)
+}
+
+fn folded_char(expr: &Expr) -> SResult<char> {
+    Ok(char_fold(as_char(expr)?))
+}
+
+fn folded_string(expr: &Expr) -> SResult<String> {
+    Ok(fold_case(&as_string(expr)?.borrow()))
+}
+
+native! {
+    fn char_upcase(_heap, c: as_char) {
+        Ok(Expr::Char(single_char(c.to_uppercase(), c)))
+    }
+
+    fn char_downcase(_heap, c: as_char) {
+        Ok(Expr::Char(single_char(c.to_lowercase(), c)))
+    }
+
+    fn char_foldcase(_heap, c: as_char) {
+        Ok(Expr::Char(char_fold(c)))
+    }
+}
+
+fn char_ci_eq(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    compare_chain(args, heap, folded_char, |a, b| a == b)
+}
+
+fn char_ci_lt(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    compare_chain(args, heap, folded_char, |a, b| a < b)
+}
+
+fn char_ci_lte(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    compare_chain(args, heap, folded_char, |a, b| a <= b)
+}
+
+fn char_ci_gt(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    compare_chain(args, heap, folded_char, |a, b| a > b)
+}
+
+fn char_ci_gte(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    compare_chain(args, heap, folded_char, |a, b| a >= b)
+}
+
+fn string_ci_eq(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    compare_chain(args, heap, folded_string, |a, b| a == b)
+}
+
+fn string_ci_lt(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    compare_chain(args, heap, folded_string, |a, b| a < b)
+}
+
+fn string_ci_lte(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    compare_chain(args, heap, folded_string, |a, b| a <= b)
+}
+
+fn string_ci_gt(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    compare_chain(args, heap, folded_string, |a, b| a > b)
+}
+
+fn string_ci_gte(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    compare_chain(args, heap, folded_string, |a, b| a >= b)
+}
+
+fn add_value(heap: &mut Heap, name: &str, value: Expr) -> SResult<()> {
+    let sym = heap.make_symbol(name)?;
+    let env = heap.root_env.clone();
+    heap.env_set(&env, &sym, value)
+}
+
+fn primitive_expr(name: &str, arity: Arity, func: Native) -> Expr {
+    Expr::Primitive(Rc::new(PrimitiveDef {
+        name: name.to_owned(),
+        arity,
+        func,
+    }))
+}
+
+fn add_primitive(heap: &mut Heap, name: &str, arity: Arity, func: Native) -> SResult<()> {
+    let sym = heap.make_symbol(name)?;
+    let env = heap.root_env.clone();
+    heap.env_set(&env, &sym, primitive_expr(name, arity, func))?;
+    Ok(())
+}
+
+pub(crate) fn add_primitives(heap: &mut Heap) -> SResult<()> {
+    add_primitive(heap, "first", Arity::exact(1), first)?;
+    add_primitive(heap, "rest", Arity::exact(1), rest)?;
+    for (i, func) in CXR_PRIMITIVES.iter().enumerate() {
+        add_primitive(heap, &cxr_name(i + 4), Arity::exact(1), *func)?;
+    }
+    add_primitive(heap, "list", Arity::at_least(0), list)?;
+    add_primitive(heap, "last-pair", Arity::exact(1), last_pair)?;
+    add_primitive(heap, "last", Arity::exact(1), last)?;
+    add_primitive(heap, "take", Arity::exact(2), list_take)?;
+    add_primitive(heap, "drop", Arity::exact(2), list_drop)?;
+    add_primitive(heap, "list-tail", Arity::exact(2), list_drop)?;
+    add_primitive(heap, "make-list", Arity::range(1, 2), make_list)?;
+    add_primitive(heap, "list-set!", Arity::exact(3), list_set)?;
+    add_primitive(heap, "count", Arity::exact(2), count)?;
+    add_primitive(heap, "find", Arity::exact(2), find)?;
+    add_primitive(heap, "any", Arity::exact(2), any)?;
+    add_primitive(heap, "every", Arity::exact(2), every)?;
+    add_primitive(heap, "list-index", Arity::exact(2), list_index)?;
+    add_primitive(heap, "partition", Arity::exact(2), partition)?;
+    add_primitive(heap, "fold", Arity::at_least(3), fold)?;
+    add_primitive(heap, "reduce", Arity::exact(3), reduce)?;
+    add_primitive(heap, "fold-right", Arity::at_least(3), fold_right)?;
+    add_primitive(heap, "fold-left", Arity::at_least(3), fold_left)?;
+    add_primitive(heap, "map", Arity::at_least(2), map)?;
+    add_primitive(heap, "for-each", Arity::at_least(2), for_each)?;
+    add_primitive(heap, "filter", Arity::exact(2), filter)?;
+    add_primitive(heap, "iota", Arity::range(1, 3), iota)?;
+    add_primitive(heap, "append-map", Arity::at_least(2), append_map)?;
+    add_primitive(heap, "flatten", Arity::exact(1), flatten)?;
+    add_primitive(heap, "zip", Arity::at_least(1), zip)?;
+    add_primitive(heap, "unzip2", Arity::exact(1), unzip2)?;
+    add_primitive(heap, "alist-update", Arity::exact(3), alist_update)?;
+    add_primitive(heap, "delete", Arity::range(2, 3), delete)?;
+    add_primitive(
+        heap,
+        "delete-duplicates",
+        Arity::range(1, 2),
+        delete_duplicates,
+    )?;
+    add_primitive(heap, "list?", Arity::exact(1), list_p)?;
+    add_primitive(heap, "cons", Arity::exact(2), cons)?;
+    add_primitive(heap, "cons*", Arity::at_least(1), cons_star)?;
+    add_primitive(heap, "eq?", Arity::exact(2), eq_p)?;
+    add_primitive(heap, "equal?", Arity::exact(2), equal_p)?;
+    add_primitive(heap, "equal-hash", Arity::exact(1), equal_hash)?;
+    add_primitive(heap, "force", Arity::exact(1), force)?;
+    add_primitive(heap, "make-promise", Arity::exact(1), make_promise)?;
+    add_primitive(heap, "promise?", Arity::exact(1), promise_p)?;
+    add_primitive(heap, "stream-first", Arity::exact(1), stream_first)?;
+    add_primitive(heap, "stream-rest", Arity::exact(1), stream_rest)?;
+    add_primitive(heap, "make-parameter", Arity::range(1, 2), make_parameter)?;
+    add_primitive(heap, "values", Arity::at_least(0), values)?;
+    add_primitive(heap, "call-with-values", Arity::exact(2), call_with_values)?;
+    add_primitive(heap, "apply", Arity::at_least(2), apply)?;
+    add_primitive(heap, "identity", Arity::exact(1), identity)?;
+    add_primitive(heap, "compose", Arity::at_least(0), compose)?;
+    add_primitive(heap, "partial", Arity::at_least(1), partial)?;
+    add_primitive(heap, "memoize", Arity::exact(1), memoize)?;
+    add_primitive(heap, "dynamic-wind", Arity::exact(3), dynamic_wind)?;
+    add_primitive(
+        heap,
+        "with-exception-handler",
+        Arity::exact(2),
+        with_exception_handler,
+    )?;
+    add_primitive(heap, "raise", Arity::exact(1), raise)?;
+    add_primitive(
+        heap,
+        "raise-continuable",
+        Arity::exact(1),
+        raise_continuable,
+    )?;
+    add_primitive(heap, "error", Arity::at_least(1), error)?;
+    add_primitive(heap, "error-object?", Arity::exact(1), error_object_p)?;
+    add_primitive(
+        heap,
+        "error-object-message",
+        Arity::exact(1),
+        error_object_message,
+    )?;
+    add_primitive(
+        heap,
+        "error-object-irritants",
+        Arity::exact(1),
+        error_object_irritants,
+    )?;
+    add_primitive(heap, "collect-garbage", Arity::exact(0), collect_garbage)?;
+    add_primitive(heap, "features", Arity::exact(0), features)?;
+    add_primitive(heap, "exit", Arity::range(0, 1), exit)?;
+    add_primitive(heap, "emergency-exit", Arity::range(0, 1), emergency_exit)?;
+    add_primitive(
+        heap,
+        "interaction-environment",
+        Arity::exact(0),
+        interaction_environment,
+    )?;
+    add_primitive(heap, "eval", Arity::range(1, 2), eval)?;
+    add_primitive(heap, "repl", Arity::range(0, 1), repl)?;
+    add_primitive(heap, "procedure?", Arity::exact(1), procedure_p)?;
+    add_primitive(heap, "procedure-arity", Arity::exact(1), procedure_arity)?;
+    add_primitive(heap, "make-string", Arity::range(1, 2), make_string)?;
+    add_primitive(heap, "string-set!", Arity::exact(3), string_set)?;
+    add_primitive(heap, "string-fill!", Arity::exact(2), string_fill)?;
+    add_primitive(heap, "string-split", Arity::exact(2), string_split)?;
+    add_primitive(heap, "string-join", Arity::range(1, 2), string_join)?;
+    add_primitive(heap, "string-contains", Arity::exact(2), string_contains)?;
+    add_primitive(heap, "string-index", Arity::exact(2), string_index)?;
+    add_primitive(heap, "string-prefix?", Arity::exact(2), string_prefix_p)?;
+    add_primitive(heap, "string-suffix?", Arity::exact(2), string_suffix_p)?;
+    add_primitive(heap, "string-upcase", Arity::exact(1), string_upcase)?;
+    add_primitive(heap, "string-downcase", Arity::exact(1), string_downcase)?;
+    add_primitive(heap, "string-foldcase", Arity::exact(1), string_foldcase)?;
+    add_primitive(heap, "string-map", Arity::at_least(2), string_map)?;
+    add_primitive(heap, "string-for-each", Arity::at_least(2), string_for_each)?;
+    add_primitive(heap, "string-trim", Arity::range(1, 2), string_trim)?;
+    add_primitive(
+        heap,
+        "string-trim-left",
+        Arity::range(1, 2),
+        string_trim_left,
+    )?;
+    add_primitive(
+        heap,
+        "string-trim-right",
+        Arity::range(1, 2),
+        string_trim_right,
+    )?;
+    add_primitive(heap, "symbol?", Arity::exact(1), symbol_p)?;
+    add_primitive(heap, "symbol->string", Arity::exact(1), symbol_to_string)?;
+    add_primitive(heap, "string->symbol", Arity::exact(1), string_to_symbol)?;
+    add_primitive(heap, "gensym", Arity::range(0, 1), gensym)?;
+    add_primitive(heap, "symbol-append", Arity::at_least(0), symbol_append)?;
+    add_primitive(heap, "string=?", Arity::at_least(2), string_eq)?;
+    add_primitive(heap, "string<?", Arity::at_least(2), string_lt)?;
+    add_primitive(heap, "string<=?", Arity::at_least(2), string_lte)?;
+    add_primitive(heap, "string>?", Arity::at_least(2), string_gt)?;
+    add_primitive(heap, "string>=?", Arity::at_least(2), string_gte)?;
+    add_primitive(heap, "char-ci=?", Arity::at_least(2), char_ci_eq)?;
+    add_primitive(heap, "char-ci<?", Arity::at_least(2), char_ci_lt)?;
+    add_primitive(heap, "char-ci<=?", Arity::at_least(2), char_ci_lte)?;
+    add_primitive(heap, "char-ci>?", Arity::at_least(2), char_ci_gt)?;
+    add_primitive(heap, "char-ci>=?", Arity::at_least(2), char_ci_gte)?;
+    add_primitive(heap, "char-upcase", Arity::exact(1), char_upcase)?;
+    add_primitive(heap, "char-downcase", Arity::exact(1), char_downcase)?;
+    add_primitive(heap, "char-foldcase", Arity::exact(1), char_foldcase)?;
+    add_primitive(heap, "char->digit", Arity::range(1, 2), char_to_digit)?;
+    add_primitive(heap, "digit->char", Arity::range(1, 2), digit_to_char)?;
+    add_primitive(heap, "string-ci=?", Arity::at_least(2), string_ci_eq)?;
+    add_primitive(heap, "string-ci<?", Arity::at_least(2), string_ci_lt)?;
+    add_primitive(heap, "string-ci<=?", Arity::at_least(2), string_ci_lte)?;
+    add_primitive(heap, "string-ci>?", Arity::at_least(2), string_ci_gt)?;
+    add_primitive(heap, "string-ci>=?", Arity::at_least(2), string_ci_gte)?;
+    add_primitive(heap, "write-string", Arity::range(1, 2), write_string)?;
+    add_primitive(heap, "display", Arity::range(1, 2), display)?;
+    add_primitive(heap, "write", Arity::range(1, 2), write)?;
+    add_primitive(heap, "write-shared", Arity::range(1, 2), write_shared)?;
+    add_primitive(heap, "write-simple", Arity::range(1, 2), write_simple)?;
+    add_primitive(
+        heap,
+        "with-output-to-string",
+        Arity::exact(1),
+        with_output_to_string,
+    )?;
+    add_primitive(heap, "command-line", Arity::exact(0), command_line)?;
+    add_primitive(heap, "getenv", Arity::exact(1), getenv)?;
+    add_primitive(heap, "current-time", Arity::exact(0), current_time)?;
+    add_primitive(heap, "runtime", Arity::exact(0), runtime)?;
+    add_primitive(heap, "random", Arity::exact(1), random)?;
+    add_primitive(heap, "random-seed", Arity::exact(1), random_seed)?;
+    add_primitive(heap, "run-tests", Arity::exact(0), run_tests)?;
+    add_primitive(heap, "newline", Arity::range(0, 1), newline)?;
+    add_primitive(heap, "print", Arity::at_least(0), print)?;
+    add_primitive(heap, "pp", Arity::range(1, 2), pp)?;
+    add_primitive(heap, "vector", Arity::at_least(0), vector)?;
+    add_primitive(heap, "make-vector", Arity::range(1, 2), make_vector)?;
+    add_primitive(heap, "vector?", Arity::exact(1), vector_p)?;
+    add_primitive(heap, "vector-length", Arity::exact(1), vector_length)?;
+    add_primitive(heap, "vector-ref", Arity::exact(2), vector_ref)?;
+    add_primitive(heap, "vector-set!", Arity::exact(3), vector_set)?;
+    add_primitive(heap, "vector->list", Arity::range(1, 3), vector_to_list)?;
+    add_primitive(heap, "list->vector", Arity::exact(1), list_to_vector)?;
+    add_primitive(heap, "vector-map", Arity::at_least(2), vector_map)?;
+    add_primitive(heap, "vector-for-each", Arity::at_least(2), vector_for_each)?;
+    add_primitive(heap, "vector-copy", Arity::range(1, 3), vector_copy)?;
+    add_primitive(heap, "vector-copy!", Arity::range(3, 5), vector_copy_to)?;
+    add_primitive(heap, "sort", Arity::exact(2), sort)?;
+    add_primitive(heap, "vector-sort!", Arity::exact(2), vector_sort)?;
+    add_primitive(
+        heap,
+        "vector-binary-search",
+        Arity::exact(3),
+        vector_binary_search,
+    )?;
+    add_primitive(heap, "vector-fill!", Arity::range(2, 4), vector_fill)?;
+    add_primitive(heap, "make-hash-table", Arity::range(0, 1), make_hash_table)?;
+    add_primitive(heap, "hash-table?", Arity::exact(1), hash_table_p)?;
+    add_primitive(heap, "hash-table-set!", Arity::exact(3), hash_table_set)?;
+    add_primitive(
+        heap,
+        "hash-table-update!",
+        Arity::exact(4),
+        hash_table_update,
+    )?;
+    add_primitive(
+        heap,
+        "hash-table-ref/default",
+        Arity::exact(3),
+        hash_table_ref_default,
+    )?;
+    add_primitive(
+        heap,
+        "hash-table-contains?",
+        Arity::exact(2),
+        hash_table_contains_p,
+    )?;
+    add_primitive(
+        heap,
+        "hash-table-delete!",
+        Arity::exact(2),
+        hash_table_delete,
+    )?;
+    add_primitive(
+        heap,
+        "hash-table->alist",
+        Arity::exact(1),
+        hash_table_to_alist,
+    )?;
+    add_primitive(
+        heap,
+        "alist->hash-table",
+        Arity::range(1, 2),
+        alist_to_hash_table,
+    )?;
+    add_primitive(heap, "hash-table-count", Arity::exact(1), hash_table_count)?;
+    add_primitive(
+        heap,
+        "open-input-string",
+        Arity::exact(1),
+        open_input_string,
+    )?;
+    add_primitive(
+        heap,
+        "open-output-string",
+        Arity::exact(0),
+        open_output_string,
+    )?;
+    add_primitive(
+        heap,
+        "get-output-string",
+        Arity::exact(1),
+        get_output_string,
+    )?;
+    add_primitive(heap, "input-port?", Arity::exact(1), input_port_p)?;
+    add_primitive(heap, "output-port?", Arity::exact(1), output_port_p)?;
+    add_primitive(heap, "read-char", Arity::range(0, 1), read_char)?;
+    add_primitive(heap, "peek-char", Arity::range(0, 1), peek_char)?;
+    add_primitive(heap, "read-line", Arity::range(0, 1), read_line)?;
+    add_primitive(heap, "write-char", Arity::range(1, 2), write_char)?;
+    add_primitive(heap, "read", Arity::range(0, 1), read)?;
+    let console_input = Expr::Port(Rc::new(RefCell::new(Port::console_input())));
+    heap.current_input_port = heap.make_parameter(console_input, Expr::Nil)?;
+    add_value(heap, "current-input-port", heap.current_input_port.clone())?;
+    let console_output = Expr::Port(Rc::new(RefCell::new(Port::ConsoleOutput)));
+    heap.current_output_port = heap.make_parameter(console_output, Expr::Nil)?;
+    add_value(
+        heap,
+        "current-output-port",
+        heap.current_output_port.clone(),
+    )?;
+    add_primitive(heap, "eof-object", Arity::exact(0), eof_object)?;
+    add_primitive(heap, "eof-object?", Arity::exact(1), eof_object_p)?;
+    add_primitive(heap, "+", Arity::at_least(0), do_plus)?;
+    add_primitive(heap, "-", Arity::at_least(1), do_minus)?;
+    add_primitive(heap, "*", Arity::at_least(0), do_times)?;
+    add_primitive(heap, "/", Arity::at_least(1), do_divide)?;
+    add_primitive(
+        heap,
+        "exact-integer-sqrt",
+        Arity::exact(1),
+        exact_integer_sqrt,
+    )?;
+    add_primitive(heap, "floor/", Arity::exact(2), floor_divide)?;
+    add_primitive(heap, "truncate/", Arity::exact(2), truncate_divide)?;
+    add_primitive(heap, "euclidean/", Arity::exact(2), euclidean_divide)?;
+    add_primitive(heap, "quotient", Arity::exact(2), quotient)?;
+    add_primitive(heap, "remainder", Arity::exact(2), remainder)?;
+    add_primitive(heap, "modulo", Arity::exact(2), modulo)?;
+    add_primitive(heap, "truncate-quotient", Arity::exact(2), quotient)?;
+    add_primitive(heap, "truncate-remainder", Arity::exact(2), remainder)?;
+    add_primitive(heap, "floor-quotient", Arity::exact(2), floored_quotient)?;
+    add_primitive(heap, "floor-remainder", Arity::exact(2), modulo)?;
+    add_primitive(heap, "bitwise-and", Arity::at_least(1), bitwise_and)?;
+    add_primitive(heap, "bitwise-or", Arity::at_least(1), bitwise_or)?;
+    add_primitive(heap, "bitwise-xor", Arity::at_least(1), bitwise_xor)?;
+    add_primitive(heap, "bitwise-not", Arity::exact(1), bitwise_not)?;
+    add_primitive(heap, "arithmetic-shift", Arity::exact(2), arithmetic_shift)?;
+    for name in [
+        "number?",
+        "complex?",
+        "real?",
+        "rational?",
+        "integer?",
+        "exact-integer?",
+    ] {
+        add_primitive(heap, name, Arity::exact(1), number_p)?;
+    }
+    add_primitive(
+        heap,
+        "exact-nonnegative-integer?",
+        Arity::exact(1),
+        exact_nonnegative_integer_p,
+    )?;
+    add_primitive(heap, "nan?", Arity::exact(1), nan_p)?;
+    add_primitive(heap, "infinite?", Arity::exact(1), nan_p)?;
+    add_primitive(heap, "finite?", Arity::exact(1), finite_p)?;
+    add_primitive(heap, "zero?", Arity::exact(1), zero_p)?;
+    add_primitive(heap, "positive?", Arity::exact(1), positive_p)?;
+    add_primitive(heap, "negative?", Arity::exact(1), negative_p)?;
+    add_primitive(heap, "odd?", Arity::exact(1), odd_p)?;
+    add_primitive(heap, "even?", Arity::exact(1), even_p)?;
+    add_primitive(heap, "=", Arity::exact(2), do_numeq)?;
+    add_primitive(heap, "<", Arity::exact(2), do_lt)?;
+    add_primitive(heap, "<=", Arity::exact(2), do_lte)?;
+    add_primitive(heap, ">", Arity::exact(2), do_gt)?;
+    add_primitive(heap, ">=", Arity::exact(2), do_gte)?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn integers(heap: &mut Heap, ns: &[i64]) -> Expr {
+        heap.make_list(ns.iter().map(|n| Expr::Integer(*n)).collect())
+            .unwrap()
+    }
+
+    // the message each extractor gives for a value of the wrong type
+    fn expected(result: SResult<impl Sized>) -> String {
+        match result {
+            Err(e) => e.to_string(),
+            Ok(_) => "ok".to_owned(),
+        }
+    }
+
+    #[test]
+    fn extractors_name_the_expected_type() {
+        let nil = Expr::Nil;
+        for (message, expected_type) in [
+            (expected(as_integer(&nil)), "integer"),
+            (expected(as_char(&nil)), "char"),
+            (expected(as_string(&nil)), "string"),
+            (expected(as_symbol(&nil)), "symbol"),
+            (expected(as_pair(&nil)), "pair"),
+            (expected(as_procedure(&nil)), "procedure"),
+            (expected(as_vector(&nil)), "vector"),
+            (expected(as_condition(&nil)), "error object"),
+            (expected(as_separator(&nil)), "char or string"),
+            (expected(as_hash_table(&nil)), "hash table"),
+            (expected(as_input_port(&nil)), "input port"),
+            (expected(as_output_port(&nil)), "output port"),
+            (expected(as_divisor(&nil)), "integer"),
+        ] {
+            assert_eq!(
+                message,
+                format!("type error: expected {}, got empty list", expected_type)
+            );
+        }
+        assert_eq!(expected(as_index(&Expr::Integer(-1))), "index out of range");
+        assert_eq!(expected(as_divisor(&Expr::Integer(0))), "division by zero");
+    }
+
+    #[test]
+    fn take_args_checks_the_count() {
+        let mut heap = Heap::new();
+        let args = integers(&mut heap, &[1, 2]);
+        let [a, b] = take_args::<2>(&heap, &args).unwrap();
+        assert_eq!((a, b), (Expr::Integer(1), Expr::Integer(2)));
+        assert!(matches!(
+            take_args::<3>(&heap, &args),
+            Err(SError::ArityMismatch { got: 2, .. })
+        ));
+        assert!(matches!(
+            take_args::<1>(&heap, &args),
+            Err(SError::ArityMismatch { got: 2, .. })
+        ));
+        assert!(take_args::<0>(&heap, &Expr::Nil).is_ok());
+    }
+
+    #[test]
+    fn take_optional_args_fills_in_missing_ones() {
+        let mut heap = Heap::new();
+        let args = integers(&mut heap, &[1, 2]);
+        let ([a], [b, c]) = take_optional_args::<1, 2>(&heap, &args).unwrap();
+        assert_eq!(a, Expr::Integer(1));
+        assert_eq!(b, Some(Expr::Integer(2)));
+        assert_eq!(c, None);
+        let args = integers(&mut heap, &[1, 2, 3, 4]);
+        assert!(matches!(
+            take_optional_args::<1, 2>(&heap, &args),
+            Err(SError::ArityMismatch { got: 4, .. })
+        ));
+        assert!(matches!(
+            take_optional_args::<1, 2>(&heap, &Expr::Nil),
+            Err(SError::ArityMismatch { got: 0, .. })
+        ));
+    }
+
+    #[test]
+    fn native_checks_arity_and_types() {
+        let mut heap = Heap::new();
+        let args = integers(&mut heap, &[0]);
+        assert_eq!(zero_p(&args, &mut heap).unwrap(), Expr::Boolean(true));
+        let args = integers(&mut heap, &[-16, -2]);
+        assert_eq!(
+            arithmetic_shift(&args, &mut heap).unwrap(),
+            Expr::Integer(-4)
+        );
+        let args = integers(&mut heap, &[1, 2]);
+        assert!(matches!(
+            zero_p(&args, &mut heap),
+            Err(SError::ArityMismatch { got: 2, .. })
+        ));
+        let args = integers(&mut heap, &[1]);
+        assert!(matches!(
+            arithmetic_shift(&args, &mut heap),
+            Err(SError::ArityMismatch { got: 1, .. })
+        ));
+        let args = heap.make_list(vec![Expr::Boolean(true)]).unwrap();
+        assert!(matches!(
+            zero_p(&args, &mut heap),
+            Err(SError::TypeError {
+                expected: "integer",
+                got: "boolean"
+            })
+        ));
+        // arguments with no extractor are passed on as they are
+        let args = heap
+            .make_list(vec![Expr::Char('a'), Expr::Char('b')])
+            .unwrap();
+        assert_eq!(do_lt(&args, &mut heap).unwrap(), Expr::Boolean(true));
+        let args = integers(&mut heap, &[1, 2, 3]);
+        assert!(matches!(
+            do_lt(&args, &mut heap),
+            Err(SError::ArityMismatch { got: 3, .. })
+        ));
+        let args = integers(&mut heap, &[7, 0]);
+        assert!(matches!(
+            quotient(&args, &mut heap),
+            Err(SError::DivisionByZero)
+        ));
+    }
+}
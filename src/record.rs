@@ -0,0 +1,173 @@
+use std::rc::Rc;
+
+use crate::{Arity, Expr, Heap, Native, PrimitiveDef, SError, SResult};
+
+// a record is a cell (type . field values) and its type is a list (name field ...),
+// so two records have the same type only if they share the same type list.
+// the procedures made by define-record-type are ordinary closures whose bodies
+// call the unnamed primitives below with the type quoted into them
+
+fn make_record(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    let (rtd, fields) = heap.get_first_rest(args)?;
+    if let Expr::Pair(key) = heap.make_cons(rtd, fields)? {
+        Ok(Expr::Record(key))
+    } else {
+        unreachable!()
+    }
+}
+
+fn has_type(heap: &Heap, rtd: &Expr, obj: &Expr) -> SResult<bool> {
+    match obj {
+        Expr::Record(k) => Ok(heap.get_first(&Expr::Pair(*k))?.is_eq(rtd)),
+        _ => Ok(false),
+    }
+}
+
+fn record_p(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    let (rtd, rest) = heap.get_first_rest(args)?;
+    let obj = heap.get_first(&rest)?;
+    Ok(Expr::Boolean(has_type(heap, &rtd, &obj)?))
+}
+
+// finds the pair holding a field given (type record index ...)
+fn field_cell(heap: &Heap, args: &Expr) -> SResult<Expr> {
+    let (rtd, rest) = heap.get_first_rest(args)?;
+    let (obj, rest) = heap.get_first_rest(&rest)?;
+    let index = heap.get_first(&rest)?;
+    match (obj, index) {
+        (Expr::Record(k), Expr::Integer(n)) if has_type(heap, &rtd, &Expr::Record(k))? => {
+            let mut cell = heap.get_rest(&Expr::Pair(k))?;
+            for _ in 0..n {
+                cell = heap.get_rest(&cell)?;
+            }
+            Ok(cell)
+        }
+        (obj, _) => Err(SError::type_error("record of matching type", &obj)),
+    }
+}
+
+fn record_ref(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    let cell = field_cell(heap, args)?;
+    heap.get_first(&cell)
+}
+
+fn record_set(args: &Expr, heap: &mut Heap) -> SResult<Expr> {
+    let cell = field_cell(heap, args)?;
+    let value = heap.get_first(&heap.get_rest(&heap.get_rest(&heap.get_rest(args)?)?)?)?;
+    heap.set_first(&cell, value)?;
+    Ok(Expr::Unspecified)
+}
+
+fn internal(name: &str, arity: usize, func: Native) -> Expr {
+    Expr::Primitive(Rc::new(PrimitiveDef {
+        name: name.to_owned(),
+        arity: Arity::exact(arity),
+        func,
+    }))
+}
+
+fn define_procedure(
+    heap: &mut Heap,
+    env: &Expr,
+    name: &Expr,
+    params: Expr,
+    call: Vec<Expr>,
+) -> SResult<()> {
+    if !name.is_identifier() {
+        return Err(SError::ImproperSymbol);
+    }
+    let call = heap.make_list(call)?;
+    let body = heap.make_cons(call, Expr::Nil)?;
+    let closure = heap.make_closure(env.clone(), params, body)?;
+    heap.env_set(env, name, closure)
+}
+
+// (define-record-type name (constructor field ...) predicate (field accessor [modifier]) ...)
+pub(crate) fn define_record_type(heap: &mut Heap, env: &Expr, args: &Expr) -> SResult<()> {
+    let (type_name, rest) = heap.get_first_rest(args)?;
+    let (constructor, rest) = heap.get_first_rest(&rest)?;
+    let (predicate, specs) = heap.get_first_rest(&rest)?;
+    if !type_name.is_identifier() {
+        return Err(SError::ImproperSymbol);
+    }
+    let mut fields = Vec::new();
+    let mut spec = specs.clone();
+    while !spec.is_nil() {
+        let field = heap.get_first(&heap.get_first(&spec)?)?;
+        if !field.is_identifier() {
+            return Err(SError::ImproperSymbol);
+        }
+        fields.push(field);
+        spec = heap.get_rest(&spec)?;
+    }
+    let field_list = heap.make_list(fields.clone())?;
+    let rtd = heap.make_cons(type_name.clone(), field_list)?;
+    heap.env_set(env, &type_name, rtd.clone())?;
+    let quote = heap.make_symbol("QUOTE")?;
+    let quoted_rtd = heap.make_list(vec![quote, rtd])?;
+    let obj = heap.make_symbol("OBJ")?;
+    let value = heap.make_symbol("VALUE")?;
+
+    // fields not named in the constructor are left unspecified
+    let (name, params) = heap.get_first_rest(&constructor)?;
+    let mut named = Vec::new();
+    let mut p = params.clone();
+    while !p.is_nil() {
+        let param = heap.get_first(&p)?;
+        if !fields.contains(&param) {
+            return Err(SError::ImproperSymbol);
+        }
+        named.push(param);
+        p = heap.get_rest(&p)?;
+    }
+    let mut call = vec![
+        internal("make-record", fields.len() + 1, make_record),
+        quoted_rtd.clone(),
+    ];
+    for field in &fields {
+        call.push(if named.contains(field) {
+            field.clone()
+        } else {
+            Expr::Unspecified
+        });
+    }
+    define_procedure(heap, env, &name, params, call)?;
+
+    let params = heap.make_list(vec![obj.clone()])?;
+    let call = vec![
+        internal("record?", 2, record_p),
+        quoted_rtd.clone(),
+        obj.clone(),
+    ];
+    define_procedure(heap, env, &predicate, params, call)?;
+
+    let mut spec = specs;
+    let mut index = 0;
+    while !spec.is_nil() {
+        let (_, procs) = heap.get_first_rest(&heap.get_first(&spec)?)?;
+        let (accessor, rest) = heap.get_first_rest(&procs)?;
+        let params = heap.make_list(vec![obj.clone()])?;
+        let call = vec![
+            internal("record-ref", 3, record_ref),
+            quoted_rtd.clone(),
+            obj.clone(),
+            Expr::Integer(index),
+        ];
+        define_procedure(heap, env, &accessor, params, call)?;
+        if !rest.is_nil() {
+            let modifier = heap.get_first(&rest)?;
+            let params = heap.make_list(vec![obj.clone(), value.clone()])?;
+            let call = vec![
+                internal("record-set!", 4, record_set),
+                quoted_rtd.clone(),
+                obj.clone(),
+                Expr::Integer(index),
+                value.clone(),
+            ];
+            define_procedure(heap, env, &modifier, params, call)?;
+        }
+        spec = heap.get_rest(&spec)?;
+        index += 1;
+    }
+    Ok(())
+}
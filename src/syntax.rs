@@ -0,0 +1,495 @@
+use std::{cell::RefCell, collections::HashSet, rc::Rc};
+
+use crate::{Expr, Heap, SError, SResult};
+
+// a macro is a cell (env ellipsis literals rule ...) where env is the
+// environment it was defined in and each rule is a list (pattern template).
+// using it matches the whole form against each pattern in turn, ignoring the
+// keyword, and evaluates the first match's template in its place with the
+// pattern variables filled in.
+// hygiene is by renaming: each symbol the template itself puts in the
+// expansion (rather than a pattern variable) becomes an alias, so any binding
+// it makes can't capture or be captured by the macro's arguments. an alias
+// that isn't bound itself means what the original symbol does where the macro
+// was defined, and special forms and keywords like else see through it
+
+// an identifier that a template put in an expansion. it is only ever the same
+// as itself, so nothing written in a program can stand in for it
+#[derive(Debug)]
+pub(crate) struct Alias {
+    // the symbol in the template, which may itself be an alias
+    pub(crate) original: Expr,
+    // the environment the macro was defined in
+    pub(crate) env: Expr,
+}
+
+impl PartialEq for Alias {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+impl Eq for Alias {}
+
+#[derive(Clone)]
+enum Binding {
+    One(Expr),
+    // a pattern variable under an ellipsis, with one binding for each repetition
+    Many(Vec<Binding>),
+}
+
+// pattern variables and what they matched. a pattern from a macro's expansion
+// can have aliases for variables, so they are told apart like identifiers
+#[derive(Clone, Default)]
+struct Bindings(Vec<(Expr, Binding)>);
+
+impl Bindings {
+    fn get(&self, var: &Expr) -> Option<&Binding> {
+        self.0
+            .iter()
+            .find(|(v, _)| v == var)
+            .map(|(_, binding)| binding)
+    }
+
+    fn insert(&mut self, var: Expr, binding: Binding) {
+        match self.0.iter_mut().find(|(v, _)| *v == var) {
+            Some((_, old)) => *old = binding,
+            None => self.0.push((var, binding)),
+        }
+    }
+
+    fn remove(&mut self, var: &Expr) -> Option<Binding> {
+        let i = self.0.iter().position(|(v, _)| v == var)?;
+        Some(self.0.swap_remove(i).1)
+    }
+}
+
+// the symbol an identifier was made from, back through any renaming
+pub(crate) fn base_symbol(expr: &Expr) -> Option<&Expr> {
+    match expr {
+        Expr::Symbol(_) => Some(expr),
+        Expr::Alias(alias) => base_symbol(&alias.original),
+        _ => None,
+    }
+}
+
+// the name of the symbol an identifier was made from, for comparing it with keywords
+pub(crate) fn base_name(expr: &Expr) -> Option<&str> {
+    match base_symbol(expr)? {
+        Expr::Symbol(name) => Some(name),
+        _ => unreachable!(),
+    }
+}
+
+fn is_named(expr: &Expr, name: &str) -> bool {
+    base_name(expr) == Some(name)
+}
+
+fn same_name(a: &Expr, b: &Expr) -> bool {
+    base_name(a).is_some_and(|name| is_named(b, name))
+}
+
+// whether a datum has aliases anywhere in it
+pub(crate) fn has_aliases(heap: &Heap, datum: &Expr) -> SResult<bool> {
+    let mut seen_pairs = HashSet::new();
+    let mut seen_vectors = HashSet::new();
+    let mut pending = vec![datum.clone()];
+    while let Some(datum) = pending.pop() {
+        match &datum {
+            Expr::Alias(_) => return Ok(true),
+            Expr::Pair(k) if seen_pairs.insert(k.0) => {
+                let (first, rest) = heap.get_first_rest(&datum)?;
+                pending.push(first);
+                pending.push(rest);
+            }
+            Expr::Vector(v) if seen_vectors.insert(Rc::as_ptr(v)) => {
+                pending.extend(v.borrow().iter().cloned());
+            }
+            _ => (),
+        }
+    }
+    Ok(false)
+}
+
+// a quoted datum with its aliases turned back into the symbols they were made
+// from, since a quoted symbol is only data. the datum is copied only as far as
+// it has to be
+pub(crate) fn strip_aliases(heap: &mut Heap, datum: &Expr) -> SResult<Expr> {
+    match datum {
+        Expr::Alias(_) => Ok(base_symbol(datum).unwrap().clone()),
+        Expr::Pair(_) if has_aliases(heap, datum)? => {
+            let (first, rest) = heap.get_first_rest(datum)?;
+            let first = strip_aliases(heap, &first)?;
+            let rest = strip_aliases(heap, &rest)?;
+            heap.make_cons(first, rest)
+        }
+        Expr::Vector(v) if has_aliases(heap, datum)? => {
+            let items = v.borrow().clone();
+            let items = items
+                .iter()
+                .map(|item| strip_aliases(heap, item))
+                .collect::<SResult<_>>()?;
+            Ok(Expr::Vector(Rc::new(RefCell::new(items))))
+        }
+        _ => Ok(datum.clone()),
+    }
+}
+
+// the elements of a list along with whatever ends it, which is () for a proper list
+fn list_items(heap: &Heap, list: &Expr) -> SResult<(Vec<Expr>, Expr)> {
+    let mut items = Vec::new();
+    let mut v = list;
+    while v.is_pair() {
+        let (first, rest) = heap.first_rest_ref(v)?;
+        items.push(first.clone());
+        v = rest;
+    }
+    Ok((items, v.clone()))
+}
+
+fn make_list_with_tail(heap: &mut Heap, items: Vec<Expr>, tail: Expr) -> SResult<Expr> {
+    let mut result = tail;
+    for item in items.into_iter().rev() {
+        result = heap.make_cons(item, result)?;
+    }
+    Ok(result)
+}
+
+fn symbol_list(heap: &Heap, list: &Expr) -> SResult<Vec<Expr>> {
+    let (items, tail) = list_items(heap, list)?;
+    if !tail.is_nil() || !items.iter().all(Expr::is_identifier) {
+        return Err(SError::ImproperSyntaxRules);
+    }
+    Ok(items)
+}
+
+// (define-syntax name (syntax-rules [ellipsis] (literal ...) (pattern template) ...))
+pub(crate) fn define_syntax(heap: &mut Heap, env: &Expr, args: &Expr) -> SResult<()> {
+    if !heap.test_length(args, 2)? {
+        return Err(SError::WrongNumberOfArgs);
+    }
+    let (name, rest) = heap.get_first_rest(args)?;
+    if !name.is_identifier() {
+        return Err(SError::ImproperSymbol);
+    }
+    let spec = heap.get_first(&rest)?;
+    if !spec.is_pair() {
+        return Err(SError::ImproperSyntaxRules);
+    }
+    let (head, mut rest) = heap.get_first_rest(&spec)?;
+    if !head.is_specific_symbol("SYNTAX-RULES") {
+        return Err(SError::ImproperSyntaxRules);
+    }
+    let ellipsis = match heap.get_first(&rest)? {
+        custom if custom.is_identifier() => {
+            rest = heap.get_rest(&rest)?;
+            custom
+        }
+        _ => heap.make_symbol("...")?,
+    };
+    let (literals, rules) = heap.get_first_rest(&rest)?;
+    symbol_list(heap, &literals)?;
+    let (items, tail) = list_items(heap, &rules)?;
+    if !tail.is_nil() {
+        return Err(SError::ImproperSyntaxRules);
+    }
+    for rule in &items {
+        if !rule.is_pair() || !heap.test_length(rule, 2)? || !heap.get_first(rule)?.is_pair() {
+            return Err(SError::ImproperSyntaxRules);
+        }
+    }
+    let transformer = heap.make_cons(ellipsis, rest)?;
+    let transformer = heap.make_cons(env.clone(), transformer)?;
+    if let Expr::Pair(key) = transformer {
+        heap.env_set(env, &name, Expr::Macro(key))
+    } else {
+        unreachable!()
+    }
+}
+
+pub(crate) fn expand(heap: &mut Heap, transformer: &Expr, form: &Expr) -> SResult<Expr> {
+    let Expr::Macro(k) = transformer else {
+        unreachable!()
+    };
+    let (env, rest) = heap.get_first_rest(&Expr::Pair(*k))?;
+    let (ellipsis, rest) = heap.get_first_rest(&rest)?;
+    let (literals, mut rules) = heap.get_first_rest(&rest)?;
+    let literals = symbol_list(heap, &literals)?;
+    let args = heap.get_rest(form)?;
+    let mut expander = Expander {
+        heap,
+        env,
+        ellipsis,
+        literals,
+        escaped: false,
+        quoted: false,
+        renames: Vec::new(),
+    };
+    while !rules.is_nil() {
+        let (rule, next) = expander.heap.get_first_rest(&rules)?;
+        let (pattern, rest) = expander.heap.get_first_rest(&rule)?;
+        let template = expander.heap.get_first(&rest)?;
+        let mut bindings = Bindings::default();
+        let pattern_args = expander.heap.get_rest(&pattern)?;
+        if expander.match_pattern(&pattern_args, &args, &mut bindings)? {
+            return expander.expand_template(&template, &bindings);
+        }
+        rules = next;
+    }
+    Err(SError::NoMatchingSyntax(expander.heap.format_expr(form)?))
+}
+
+struct Expander<'a> {
+    heap: &'a mut Heap,
+    // the environment the macro was defined in
+    env: Expr,
+    ellipsis: Expr,
+    literals: Vec<Expr>,
+    // inside (... template), where the ellipsis is an ordinary symbol
+    escaped: bool,
+    // inside a quoted part of the template, where nothing is renamed
+    quoted: bool,
+    // the alias each identifier in the template stands for in this expansion
+    renames: Vec<(Expr, Expr)>,
+}
+
+impl Expander<'_> {
+    fn is_ellipsis(&self, expr: &Expr) -> bool {
+        !self.escaped && same_name(expr, &self.ellipsis)
+    }
+
+    fn is_literal(&self, expr: &Expr) -> bool {
+        self.literals.iter().any(|literal| same_name(expr, literal))
+    }
+
+    fn match_pattern(
+        &mut self,
+        pattern: &Expr,
+        form: &Expr,
+        bindings: &mut Bindings,
+    ) -> SResult<bool> {
+        match pattern {
+            Expr::Symbol(_) | Expr::Alias(_) => {
+                if self.is_literal(pattern) {
+                    Ok(same_name(form, pattern))
+                } else {
+                    if !is_named(pattern, "_") {
+                        bindings.insert(pattern.clone(), Binding::One(form.clone()));
+                    }
+                    Ok(true)
+                }
+            }
+            Expr::Pair(_) => {
+                let (patterns, pattern_tail) = list_items(self.heap, pattern)?;
+                let (items, tail) = list_items(self.heap, form)?;
+                self.match_sequence(&patterns, &pattern_tail, items, tail, bindings)
+            }
+            Expr::Vector(v) => {
+                let Expr::Vector(w) = form else {
+                    return Ok(false);
+                };
+                let patterns = v.borrow().clone();
+                let items = w.borrow().clone();
+                self.match_sequence(&patterns, &Expr::Nil, items, Expr::Nil, bindings)
+            }
+            _ => self.heap.is_equal(pattern, form),
+        }
+    }
+
+    // matches the elements of a list or vector, where the pattern may have one
+    // element followed by an ellipsis to match any number of them
+    fn match_sequence(
+        &mut self,
+        patterns: &[Expr],
+        pattern_tail: &Expr,
+        mut items: Vec<Expr>,
+        tail: Expr,
+        bindings: &mut Bindings,
+    ) -> SResult<bool> {
+        let Some(pos) = patterns.iter().position(|p| self.is_ellipsis(p)) else {
+            if items.len() < patterns.len()
+                || (pattern_tail.is_nil() && items.len() > patterns.len())
+            {
+                return Ok(false);
+            }
+            // whatever the fixed patterns don't match is left for the tail pattern
+            let extra = items.split_off(patterns.len());
+            let rest = make_list_with_tail(self.heap, extra, tail)?;
+            for (pattern, item) in patterns.iter().zip(&items) {
+                if !self.match_pattern(pattern, item, bindings)? {
+                    return Ok(false);
+                }
+            }
+            return self.match_pattern(pattern_tail, &rest, bindings);
+        };
+        if pos == 0 {
+            return Err(SError::ImproperSyntaxRules);
+        }
+        let (before, after) = (&patterns[..pos - 1], &patterns[pos + 1..]);
+        if items.len() < before.len() + after.len() {
+            return Ok(false);
+        }
+        let repeats = items.len() - after.len();
+        for (pattern, item) in before.iter().zip(&items) {
+            if !self.match_pattern(pattern, item, bindings)? {
+                return Ok(false);
+            }
+        }
+        let repeated = &patterns[pos - 1];
+        let mut vars = Vec::new();
+        self.pattern_vars(repeated, &mut vars);
+        let mut matches = vec![Vec::new(); vars.len()];
+        for item in &items[before.len()..repeats] {
+            let mut repetition = Bindings::default();
+            if !self.match_pattern(repeated, item, &mut repetition)? {
+                return Ok(false);
+            }
+            for (var, found) in vars.iter().zip(&mut matches) {
+                found.push(repetition.remove(var).ok_or(SError::ImproperSyntaxRules)?);
+            }
+        }
+        for (var, found) in vars.into_iter().zip(matches) {
+            bindings.insert(var, Binding::Many(found));
+        }
+        for (pattern, item) in after.iter().zip(&items[repeats..]) {
+            if !self.match_pattern(pattern, item, bindings)? {
+                return Ok(false);
+            }
+        }
+        self.match_pattern(pattern_tail, &tail, bindings)
+    }
+
+    fn pattern_vars(&self, pattern: &Expr, vars: &mut Vec<Expr>) {
+        match pattern {
+            Expr::Symbol(_) | Expr::Alias(_)
+                if !self.is_ellipsis(pattern)
+                    && !self.is_literal(pattern)
+                    && !is_named(pattern, "_") =>
+            {
+                vars.push(pattern.clone());
+            }
+            Expr::Pair(_) => {
+                if let Ok((first, rest)) = self.heap.first_rest_ref(pattern) {
+                    self.pattern_vars(first, vars);
+                    self.pattern_vars(rest, vars);
+                }
+            }
+            Expr::Vector(v) => {
+                for item in v.borrow().iter() {
+                    self.pattern_vars(item, vars);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn rename(&mut self, identifier: &Expr) -> Expr {
+        if self.quoted {
+            return identifier.clone();
+        }
+        if let Some((_, alias)) = self.renames.iter().find(|(id, _)| id == identifier) {
+            return alias.clone();
+        }
+        let alias = Expr::Alias(Rc::new(Alias {
+            original: identifier.clone(),
+            env: self.env.clone(),
+        }));
+        self.renames.push((identifier.clone(), alias.clone()));
+        alias
+    }
+
+    fn expand_template(&mut self, template: &Expr, bindings: &Bindings) -> SResult<Expr> {
+        match template {
+            Expr::Symbol(_) | Expr::Alias(_) => match bindings.get(template) {
+                Some(Binding::One(value)) => Ok(value.clone()),
+                Some(Binding::Many(_)) => Err(SError::ImproperSyntaxRules),
+                None => Ok(self.rename(template)),
+            },
+            Expr::Pair(_) => {
+                let (items, tail) = list_items(self.heap, template)?;
+                if items.len() == 2 && tail.is_nil() && self.is_ellipsis(&items[0]) {
+                    // (... template) stands for template with the ellipsis taken literally
+                    self.escaped = true;
+                    let result = self.expand_template(&items[1], bindings);
+                    self.escaped = false;
+                    return result;
+                }
+                let was_quoted = self.quoted;
+                if items[0].is_specific_symbol("QUOTE") && bindings.get(&items[0]).is_none() {
+                    self.quoted = true;
+                }
+                let result = self.expand_sequence(&items, bindings);
+                let tail = self.expand_template(&tail, bindings);
+                self.quoted = was_quoted;
+                make_list_with_tail(self.heap, result?, tail?)
+            }
+            Expr::Vector(v) => {
+                let items = v.borrow().clone();
+                let result = self.expand_sequence(&items, bindings)?;
+                Ok(Expr::Vector(Rc::new(RefCell::new(result))))
+            }
+            _ => Ok(template.clone()),
+        }
+    }
+
+    // expands each template in turn, where one followed by an ellipsis gives an
+    // expansion for each repetition of the pattern variables in it (and one
+    // followed by two ellipses goes through two levels of repetition, and so on)
+    fn expand_sequence(&mut self, templates: &[Expr], bindings: &Bindings) -> SResult<Vec<Expr>> {
+        let mut result = Vec::new();
+        let mut i = 0;
+        while i < templates.len() {
+            let template = &templates[i];
+            let mut depth = 0;
+            while templates
+                .get(i + 1 + depth)
+                .is_some_and(|t| self.is_ellipsis(t))
+            {
+                depth += 1;
+            }
+            if depth == 0 {
+                result.push(self.expand_template(template, bindings)?);
+            } else {
+                self.expand_repeated(template, depth, bindings, &mut result)?;
+            }
+            i += 1 + depth;
+        }
+        Ok(result)
+    }
+
+    fn expand_repeated(
+        &mut self,
+        template: &Expr,
+        depth: usize,
+        bindings: &Bindings,
+        result: &mut Vec<Expr>,
+    ) -> SResult<()> {
+        let mut vars = Vec::new();
+        self.pattern_vars(template, &mut vars);
+        let mut repeated = Vec::new();
+        for var in vars {
+            if let Some(Binding::Many(values)) = bindings.get(&var) {
+                repeated.push((var, values));
+            }
+        }
+        // every variable repeated here must have been matched the same number of times
+        let Some(count) = repeated.first().map(|(_, values)| values.len()) else {
+            return Err(SError::ImproperSyntaxRules);
+        };
+        if repeated.iter().any(|(_, values)| values.len() != count) {
+            return Err(SError::ImproperSyntaxRules);
+        }
+        for i in 0..count {
+            let mut inner = bindings.clone();
+            for (var, values) in &repeated {
+                inner.insert(var.clone(), values[i].clone());
+            }
+            if depth > 1 {
+                self.expand_repeated(template, depth - 1, &inner, result)?;
+            } else {
+                result.push(self.expand_template(template, &inner)?);
+            }
+        }
+        Ok(())
+    }
+}
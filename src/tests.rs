@@ -0,0 +1,2258 @@
+use std::{cell::RefCell, io::Write, rc::Rc};
+
+use crate::analyse::Node;
+use crate::gc::Phase;
+use crate::lexer::{tokenize, Token};
+use crate::parser::ParseError;
+use crate::{run_command, Arity, Expr, Heap, Repl, SError};
+
+// what a program writes to its output, shared with the test that reads it
+#[derive(Clone, Default)]
+struct Output(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Output {
+    fn take(&self) -> String {
+        String::from_utf8(std::mem::take(&mut *self.0.borrow_mut())).unwrap()
+    }
+}
+
+fn heap_with_output() -> (Heap, Output) {
+    let output = Output::default();
+    (Heap::with_output(Box::new(output.clone())), output)
+}
+
+// evaluates a whole program, giving the written form of its last value or the error
+fn eval(heap: &mut Heap, source: &str) -> String {
+    match heap.eval_source(source) {
+        Ok(value) => heap.format_expr(&value).unwrap(),
+        Err(e) => format!("err: {}", e),
+    }
+}
+
+fn run(source: &str) -> String {
+    eval(&mut Heap::new(), source)
+}
+
+#[test]
+fn mutable_strings() {
+    assert_eq!(run("(make-string 3 #\\a)"), "\"aaa\"");
+    assert_eq!(
+        run("(define s (make-string 3 #\\a)) (string-set! s 1 #\\b) s"),
+        "\"aba\""
+    );
+    assert_eq!(
+        run("(define s (make-string 2 #\\a)) (string-fill! s #\\z) s"),
+        "\"zz\""
+    );
+    assert_eq!(
+        run("(string-set! (make-string 2 #\\a) 2 #\\b)"),
+        "err: index out of range"
+    );
+    assert_eq!(
+        run("(make-string 100000000000 #\\a)"),
+        "err: index out of range"
+    );
+}
+
+#[test]
+fn write_string_and_print() {
+    let (mut heap, output) = heap_with_output();
+    assert_eq!(
+        eval(&mut heap, "(write-string \"a \\\"b\\\"\")"),
+        "#<unspecified>"
+    );
+    assert_eq!(output.take(), "a \"b\"");
+    eval(&mut heap, "(print 1 \"two\" #\\3 'four)");
+    assert_eq!(output.take(), "1 two 3 FOUR\n");
+    eval(&mut heap, "(print)");
+    assert_eq!(output.take(), "\n");
+}
+
+#[test]
+fn hash_table_equality() {
+    let mut heap = Heap::new();
+    eval(
+        &mut heap,
+        r#"(define eq-table (make-hash-table eq?))
+           (define equal-table (make-hash-table equal?))
+           (hash-table-set! eq-table (list 1 2) 'a)
+           (hash-table-set! eq-table (list 1 2) 'b)
+           (hash-table-set! equal-table (list 1 2) 'a)
+           (hash-table-set! equal-table (list 1 2) 'b)"#,
+    );
+    assert_eq!(eval(&mut heap, "(hash-table-count eq-table)"), "2");
+    assert_eq!(eval(&mut heap, "(hash-table-count equal-table)"), "1");
+    assert_eq!(
+        eval(
+            &mut heap,
+            "(hash-table-ref/default equal-table (list 1 2) #f)"
+        ),
+        "B"
+    );
+    assert_eq!(
+        eval(&mut heap, "(hash-table-ref/default eq-table (list 1 2) #f)"),
+        "#f"
+    );
+    assert_eq!(
+        eval(
+            &mut heap,
+            r#"(hash-table-set! equal-table "kkk" 1)
+               (hash-table-ref/default equal-table (make-string 3 #\k) #f)"#
+        ),
+        "1"
+    );
+}
+
+#[test]
+fn promises_run_once() {
+    let mut heap = Heap::new();
+    eval(
+        &mut heap,
+        "(define runs 0) (define p (delay (cond (#t (set! runs (+ runs 1)) (* 6 7)))))",
+    );
+    assert_eq!(eval(&mut heap, "runs"), "0");
+    assert_eq!(eval(&mut heap, "(force p)"), "42");
+    assert_eq!(eval(&mut heap, "(force p)"), "42");
+    assert_eq!(eval(&mut heap, "runs"), "1");
+    assert_eq!(eval(&mut heap, "(force 5)"), "5");
+}
+
+#[test]
+fn infinite_streams() {
+    let mut heap = Heap::new();
+    eval(
+        &mut heap,
+        r#"(define (integers-from n) (cons-stream n (integers-from (+ n 1))))
+           (define (stream-take s n)
+             (if (= n 0) '() (cons (stream-first s) (stream-take (stream-rest s) (- n 1)))))"#,
+    );
+    assert_eq!(
+        eval(&mut heap, "(stream-take (integers-from 0) 5)"),
+        "(0 1 2 3 4)"
+    );
+}
+
+#[test]
+fn parameterize_restores_values() {
+    let mut heap = Heap::new();
+    eval(&mut heap, "(define p (make-parameter 1))");
+    assert_eq!(eval(&mut heap, "(parameterize ((p 2)) (p))"), "2");
+    assert_eq!(eval(&mut heap, "(p)"), "1");
+    assert_eq!(
+        eval(&mut heap, "(parameterize ((p 3)) (error \"boom\"))"),
+        "err: boom"
+    );
+    assert_eq!(eval(&mut heap, "(p)"), "1");
+    eval(
+        &mut heap,
+        "(define q (make-parameter 10 (lambda (x) (* x 2))))",
+    );
+    assert_eq!(eval(&mut heap, "(q)"), "20");
+    assert_eq!(eval(&mut heap, "(parameterize ((q 5)) (q))"), "10");
+}
+
+#[test]
+fn dynamic_wind_runs_after() {
+    let mut heap = Heap::new();
+    eval(
+        &mut heap,
+        r#"(define log '())
+           (define (note x) (set! log (cons x log)))"#,
+    );
+    assert_eq!(
+        eval(
+            &mut heap,
+            "(dynamic-wind (lambda () (note 'before)) (lambda () 'result) (lambda () (note 'after)))"
+        ),
+        "RESULT"
+    );
+    assert_eq!(eval(&mut heap, "log"), "(AFTER BEFORE)");
+    eval(&mut heap, "(set! log '())");
+    assert_eq!(
+        eval(
+            &mut heap,
+            "(dynamic-wind (lambda () (note 'before)) (lambda () (undefined)) (lambda () (note 'after)))"
+        ),
+        "err: unbound symbol: UNDEFINED"
+    );
+    assert_eq!(eval(&mut heap, "log"), "(AFTER BEFORE)");
+}
+
+#[test]
+fn case_insensitive_comparisons() {
+    assert_eq!(run(r#"(string-ci=? "Hello" "hELLO" "hello")"#), "#t");
+    assert_eq!(run(r#"(string-ci=? "Hello" "help")"#), "#f");
+    assert_eq!(run(r#"(string-ci<? "apple" "BANANA")"#), "#t");
+    assert_eq!(run(r#"(string<? "apple" "BANANA")"#), "#f");
+    assert_eq!(run(r#"(string-ci>=? "b" "B" "a")"#), "#t");
+    assert_eq!(run(r"(char-ci=? #\a #\A)"), "#t");
+    assert_eq!(run(r"(char-ci<? #\a #\B #\c)"), "#t");
+    assert_eq!(run(r"(= #\a #\A)"), "#f");
+}
+
+#[test]
+fn string_ordering() {
+    assert_eq!(run(r#"(string<? "abc" "abcd")"#), "#t");
+    assert_eq!(run(r#"(string>? "abc" "abcd")"#), "#f");
+    assert_eq!(run(r#"(string<=? "abc" "abc" "abd")"#), "#t");
+    assert_eq!(run(r#"(string>=? "b" "a" "a")"#), "#t");
+    assert_eq!(run(r#"(string=? "abc" "abc" "abc")"#), "#t");
+    assert_eq!(run(r#"(string=? "abc" "abcd")"#), "#f");
+    assert_eq!(run(r#"(string<? "a" "c" "b")"#), "#f");
+}
+
+#[test]
+fn reset_discards_definitions() {
+    let mut heap = Heap::new();
+    eval(&mut heap, "(define x 1) (define (first y) y)");
+    assert_eq!(eval(&mut heap, "(first '(2 3))"), "(2 3)");
+    heap.reset();
+    assert_eq!(eval(&mut heap, "x"), "err: unbound symbol: X");
+    assert_eq!(eval(&mut heap, "(first '(2 3))"), "2");
+}
+
+#[test]
+fn save_and_load_definitions() {
+    let mut heap = Heap::new();
+    eval(
+        &mut heap,
+        r#"(define size 3)
+           (define name "box")
+           (define items '(a (b . c) #(1 2)))
+           (define (area n) (* n size))"#,
+    );
+    let saved = heap.format_definitions().unwrap();
+    let mut fresh = Heap::new();
+    eval(&mut fresh, &saved);
+    assert_eq!(eval(&mut fresh, "(area size)"), "9");
+    assert_eq!(eval(&mut fresh, "name"), "\"box\"");
+    assert_eq!(eval(&mut fresh, "items"), "(A (B . C) #(1 2))");
+    assert_eq!(fresh.format_definitions().unwrap(), saved);
+}
+
+#[test]
+fn pretty_print_nested_let() {
+    let (mut heap, output) = heap_with_output();
+    eval(
+        &mut heap,
+        "(pp '(let ((alpha (compute 1 2)) (beta (compute 3 4))) (let ((gamma (+ alpha beta))) (display gamma) (newline) gamma)) 30)",
+    );
+    let text = output.take();
+    assert!(
+        text.lines().all(|line| line.chars().count() <= 30),
+        "{}",
+        text
+    );
+    assert_eq!(
+        text,
+        "(LET ((ALPHA (COMPUTE 1 2))\n      (BETA (COMPUTE 3 4)))\n  (LET ((GAMMA (+ ALPHA\n                  BETA)))\n    (DISPLAY GAMMA)\n    (NEWLINE)\n    GAMMA))\n"
+    );
+    eval(&mut heap, "(define x (list 1 2)) (pp (list x x) 5)");
+    assert_eq!(output.take(), "(#0=(1 2) #0#)\n");
+}
+
+#[test]
+fn quote_abbreviations_round_trip() {
+    assert_eq!(run("'(quote (1 2))"), "'(1 2)");
+    assert_eq!(run("''a"), "'A");
+    assert_eq!(
+        run("'(quasiquote (a (unquote b) (unquote-splicing c)))"),
+        "`(A ,B ,@C)"
+    );
+    assert_eq!(run("'(quote 1 2)"), "(QUOTE 1 2)");
+    assert_eq!(run("'(quote . a)"), "(QUOTE . A)");
+}
+
+#[test]
+fn shared_structure_labels() {
+    assert_eq!(run("(define x (list 1 2)) (list x x)"), "(#0=(1 2) #0#)");
+    assert_eq!(
+        run("(define v (vector 'a)) (define x (list v)) (cons x (cons v x))"),
+        "(#0=(#1=#(A)) #1# . #0#)"
+    );
+    assert_eq!(run("(list (list 1 2) (list 1 2))"), "((1 2) (1 2))");
+    assert_eq!(
+        run("(define v (vector 1 2)) (vector-set! v 1 v) v"),
+        "#0=#(1 #0#)"
+    );
+}
+
+#[test]
+fn datum_labels_on_input() {
+    let mut heap = Heap::new();
+    eval(&mut heap, "(define x '#0=(1 . #0#))");
+    assert_eq!(eval(&mut heap, "(eq? x (rest x))"), "#t");
+    assert_eq!(eval(&mut heap, "(first (rest (rest x)))"), "1");
+    assert_eq!(eval(&mut heap, "x"), "#0=(1 . #0#)");
+    eval(&mut heap, "(define y '(#1=(a b) #1#))");
+    assert_eq!(eval(&mut heap, "(eq? (first y) (first (rest y)))"), "#t");
+    assert_eq!(eval(&mut heap, "'(#2=a #2#)"), "(A A)");
+}
+
+#[test]
+fn first_class_environments() {
+    let mut heap = Heap::new();
+    eval(
+        &mut heap,
+        r#"(define (make-env x) (the-environment))
+           (define env (make-env 42))
+           (define x 1)"#,
+    );
+    assert_eq!(eval(&mut heap, "(eval 'x env)"), "42");
+    assert_eq!(eval(&mut heap, "(eval '(+ x 1) env)"), "43");
+    assert_eq!(eval(&mut heap, "(eval 'x (interaction-environment))"), "1");
+    assert_eq!(eval(&mut heap, "(eval 'x)"), "1");
+    heap.collect_garbage();
+    assert_eq!(eval(&mut heap, "(eval 'x env)"), "42");
+}
+
+#[test]
+fn procedure_predicate_and_arity() {
+    assert_eq!(run("(procedure? (lambda (x) x))"), "#t");
+    assert_eq!(run("(procedure? cons)"), "#t");
+    assert_eq!(run("(procedure? 'cons)"), "#f");
+    assert_eq!(run("(procedure-arity (lambda (x y) x))"), "2");
+    assert_eq!(run("(procedure-arity cons)"), "2");
+    assert_eq!(run("(procedure-arity (lambda (x . rest) x))"), "(1 . #f)");
+    assert_eq!(run("(procedure-arity +)"), "(0 . #f)");
+}
+
+#[test]
+fn arity_errors_name_expected_count() {
+    assert_eq!(
+        run("(cons 1)"),
+        "err: wrong number of arguments: expected 2, got 1"
+    );
+    assert_eq!(
+        run("(string=? \"a\")"),
+        "err: wrong number of arguments: expected at least 2, got 1"
+    );
+    assert_eq!(
+        run("(char-ci<? #\\a)"),
+        "err: wrong number of arguments: expected at least 2, got 1"
+    );
+    assert_eq!(
+        run("(make-string)"),
+        "err: wrong number of arguments: expected 1 to 2, got 0"
+    );
+}
+
+#[test]
+fn errors_carry_context() {
+    assert_eq!(
+        run("undefined-thing"),
+        "err: unbound symbol: UNDEFINED-THING"
+    );
+    assert_eq!(
+        run("(+ 1 'a)"),
+        "err: type error: expected integer, got symbol"
+    );
+    assert_eq!(run("(5 1)"), "err: not callable: integer");
+}
+
+#[test]
+fn error_messages() {
+    let cases = [
+        (
+            SError::ArityMismatch {
+                expected: Arity::exact(2),
+                got: 1,
+            },
+            "wrong number of arguments: expected 2, got 1",
+        ),
+        (
+            SError::AssertionFailed("(= 1 2)".into()),
+            "assertion failed: (= 1 2)",
+        ),
+        (SError::ImproperLambda, "malformed lambda"),
+        (SError::ImproperList, "improper list"),
+        (SError::ImproperSymbol, "expected a symbol"),
+        (SError::ImproperEnvironment, "malformed environment"),
+        (SError::ImproperSyntaxRules, "malformed syntax-rules"),
+        (SError::DivisionByZero, "division by zero"),
+        (SError::EmergencyExit(3), "exit with status 3"),
+        (SError::Exit(0), "exit with status 0"),
+        (SError::HandlerReturned, "exception handler returned"),
+        (SError::IndexOutOfRange, "index out of range"),
+        (SError::IntegerOverflow, "integer overflow"),
+        (SError::IoError, "i/o error"),
+        (SError::NonIntegerResult, "result is not an integer"),
+        (
+            SError::NoMatchingSyntax("(SWAP! A)".into()),
+            "no syntax rule matches (SWAP! A)",
+        ),
+        (
+            SError::NotCallable(Expr::Integer(5)),
+            "not callable: integer",
+        ),
+        (SError::Raise(Expr::Nil, "oops".into()), "oops"),
+        (
+            SError::ReadError(ParseError::UnmatchedBracket),
+            "read error: unmatched close bracket",
+        ),
+        (
+            SError::type_error("string", &Expr::Boolean(true)),
+            "type error: expected string, got boolean",
+        ),
+        (SError::UnboundSymbol("FOO".into()), "unbound symbol: FOO"),
+        (SError::WrongNumberOfArgs, "wrong number of arguments"),
+    ];
+    for (error, message) in cases {
+        assert_eq!(error.to_string(), message);
+    }
+    assert_eq!(run("(+ 1"), "err: read error: unexpected end of input");
+    assert_eq!(run("\"abc"), "err: read error: unterminated string");
+    assert_eq!(
+        run("#5#"),
+        "err: read error: reference to an undefined datum label"
+    );
+}
+
+#[test]
+fn values_display_with_heap() {
+    let mut heap = Heap::new();
+    let value = heap.eval_source("(list 1 \"two\" #\\3)").unwrap();
+    assert_eq!(format!("{}", value.with_heap(&heap)), "(1 \"two\" #\\3)");
+}
+
+#[test]
+fn define_returns_unspecified() {
+    let mut heap = Heap::new();
+    assert!(matches!(
+        heap.eval_source("(define x 1)"),
+        Ok(Expr::Unspecified)
+    ));
+    assert_eq!(eval(&mut heap, "(set! x 2)"), "#<unspecified>");
+    assert_eq!(eval(&mut heap, "(define (f) x)"), "#<unspecified>");
+    assert_eq!(
+        eval(&mut heap, "(list (vector-set! (vector 1) 0 2))"),
+        "(#<unspecified>)"
+    );
+    assert_eq!(eval(&mut heap, "(f)"), "2");
+}
+
+#[test]
+fn cxr_accessors() {
+    assert_eq!(run("(cadr (list 1 2 3))"), "2");
+    assert_eq!(run("(caddr (list 1 2 3))"), "3");
+    assert_eq!(run("(cddr (list 1 2 3))"), "(3)");
+    assert_eq!(run("(caar '((1 2) 3))"), "1");
+    assert_eq!(run("(cdar '((1 2) 3))"), "(2)");
+    assert_eq!(run("(cadddr '(1 2 3 4))"), "4");
+    assert_eq!(
+        run("(cadr '(1))"),
+        "err: type error: expected pair, got empty list"
+    );
+}
+
+#[test]
+fn last_pair_and_last() {
+    assert_eq!(run("(last-pair '(1))"), "(1)");
+    assert_eq!(run("(last-pair '(1 2 3))"), "(3)");
+    assert_eq!(run("(last-pair '(1 2 . 3))"), "(2 . 3)");
+    assert_eq!(run("(last '(1))"), "1");
+    assert_eq!(run("(last '(1 2 3))"), "3");
+    assert_eq!(
+        run("(last '())"),
+        "err: type error: expected pair, got empty list"
+    );
+}
+
+#[test]
+fn count_take_and_drop() {
+    assert_eq!(run("(take '(1 2 3) 0)"), "()");
+    assert_eq!(run("(take '(1 2 3) 2)"), "(1 2)");
+    assert_eq!(run("(take '(1 2 3) 3)"), "(1 2 3)");
+    assert_eq!(run("(take '(1 2 3) 4)"), "err: index out of range");
+    assert_eq!(
+        run("(take '(1 2) 9223372036854775807)"),
+        "err: index out of range"
+    );
+    assert_eq!(run("(drop '(1 2 3) 0)"), "(1 2 3)");
+    assert_eq!(run("(drop '(1 2 3) 3)"), "()");
+    assert_eq!(run("(drop '(1 2 3) 4)"), "err: index out of range");
+    assert_eq!(run("(list-tail '(1 2 3) 1)"), "(2 3)");
+    assert_eq!(run("(count odd? '(1 2 3 5))"), "3");
+    assert_eq!(run("(count odd? '())"), "0");
+}
+
+#[test]
+fn find_any_and_every() {
+    assert_eq!(run("(find even? '(1 3 4 5 6))"), "4");
+    assert_eq!(run("(find even? '(1 3))"), "#f");
+    assert_eq!(
+        run("(any (lambda (x) (and (even? x) (* x 10))) '(1 2 3))"),
+        "20"
+    );
+    assert_eq!(run("(any even? '())"), "#f");
+    assert_eq!(run("(every (lambda (x) (and (odd? x) x)) '(1 3 5))"), "5");
+    assert_eq!(run("(every odd? '(1 2 3))"), "#f");
+    assert_eq!(run("(every odd? '())"), "#t");
+    // the predicate would fail on the symbol if it got that far
+    assert_eq!(run("(find even? '(1 2 a))"), "2");
+    assert_eq!(run("(any even? '(1 2 a))"), "#t");
+    assert_eq!(run("(every even? '(1 a))"), "#f");
+}
+
+#[test]
+fn record_types() {
+    let mut heap = Heap::new();
+    eval(
+        &mut heap,
+        r#"(define-record-type point (make-point x y) point? (x point-x set-point-x!) (y point-y))
+           (define p (make-point 1 2))"#,
+    );
+    assert_eq!(eval(&mut heap, "(point? p)"), "#t");
+    assert_eq!(eval(&mut heap, "(point? (vector 1 2))"), "#f");
+    assert_eq!(eval(&mut heap, "(list (point-x p) (point-y p))"), "(1 2)");
+    eval(&mut heap, "(set-point-x! p 10)");
+    assert_eq!(eval(&mut heap, "(point-x p)"), "10");
+    heap.collect_garbage();
+    assert_eq!(eval(&mut heap, "(point-y p)"), "2");
+    assert_eq!(
+        eval(&mut heap, "(point-x 5)"),
+        "err: type error: expected record of matching type, got integer"
+    );
+}
+
+#[test]
+fn assert_form() {
+    let mut heap = Heap::new();
+    eval(
+        &mut heap,
+        "(define runs 0) (define (tick) (cond (#t (set! runs (+ runs 1)) runs)))",
+    );
+    assert_eq!(eval(&mut heap, "(assert (tick))"), "1");
+    assert_eq!(eval(&mut heap, "runs"), "1");
+    assert_eq!(
+        eval(&mut heap, "(assert (= (tick) 0))"),
+        "err: assertion failed: (= (TICK) 0)"
+    );
+    assert_eq!(eval(&mut heap, "runs"), "2");
+}
+
+#[test]
+fn check_expect_records_results() {
+    let (mut heap, output) = heap_with_output();
+    eval(
+        &mut heap,
+        r#"(check-expect (+ 1 2) 3)
+           (check-expect (list 1 2) (list 1 2))
+           (check-expect (* 2 2) 5)
+           (check-within 10 12 3)
+           (check-within 10 15 3)"#,
+    );
+    let failures: Vec<_> = heap.checks.iter().map(|c| c.failure.is_some()).collect();
+    assert_eq!(failures, [false, false, true, false, true]);
+    assert_eq!(heap.checks[2].source, "(CHECK-EXPECT (* 2 2) 5)");
+    eval(&mut heap, "(run-tests)");
+    let report = output.take();
+    assert!(report.ends_with("3 of 5 checks passed\n"), "{}", report);
+    assert!(
+        report.contains("check failed: (CHECK-EXPECT (* 2 2) 5)"),
+        "{}",
+        report
+    );
+    assert!(heap.checks.is_empty());
+}
+
+#[test]
+fn bitwise_operations() {
+    assert_eq!(run("(bitwise-and 12 10)"), "8");
+    assert_eq!(run("(bitwise-or 12 10)"), "14");
+    assert_eq!(run("(bitwise-xor 12 10)"), "6");
+    assert_eq!(run("(bitwise-not 0)"), "-1");
+    assert_eq!(run("(bitwise-not 5)"), "-6");
+    assert_eq!(run("(arithmetic-shift 1 4)"), "16");
+    assert_eq!(run("(arithmetic-shift 16 -2)"), "4");
+    assert_eq!(run("(arithmetic-shift -16 -2)"), "-4");
+    assert_eq!(run("(arithmetic-shift -1 -100)"), "-1");
+    assert_eq!(run("(arithmetic-shift 5 -100)"), "0");
+    assert_eq!(run("(arithmetic-shift -1 63)"), "-9223372036854775808");
+    assert_eq!(run("(arithmetic-shift 0 100)"), "0");
+    assert_eq!(run("(arithmetic-shift 1 63)"), "err: integer overflow");
+    assert_eq!(run("(arithmetic-shift 3 62)"), "err: integer overflow");
+    assert_eq!(run("(arithmetic-shift -3 62)"), "err: integer overflow");
+    assert_eq!(run("(arithmetic-shift 1 64)"), "err: integer overflow");
+}
+
+#[test]
+fn integer_roots_and_division_pairs() {
+    let mut heap = Heap::new();
+    for n in [0i64, 1, 2, 15, 16, 17, 1000, i64::MAX] {
+        let check = format!(
+            "(call-with-values (lambda () (exact-integer-sqrt {n})) (lambda (s r) (list (= (+ (* s s) r) {n}) (< r (+ (* 2 s) 1)))))"
+        );
+        assert_eq!(eval(&mut heap, &check), "(#t #t)", "{}", n);
+    }
+    assert_eq!(
+        eval(
+            &mut heap,
+            "(call-with-values (lambda () (exact-integer-sqrt 17)) list)"
+        ),
+        "(4 1)"
+    );
+    assert_eq!(
+        eval(
+            &mut heap,
+            "(call-with-values (lambda () (floor/ -7 2)) list)"
+        ),
+        "(-4 1)"
+    );
+    assert_eq!(
+        eval(
+            &mut heap,
+            "(call-with-values (lambda () (truncate/ -7 2)) list)"
+        ),
+        "(-3 -1)"
+    );
+    assert_eq!(
+        eval(&mut heap, "(exact-integer-sqrt -1)"),
+        "err: type error: expected non-negative integer, got integer"
+    );
+}
+
+#[test]
+fn pipe_quoted_symbols() {
+    assert_eq!(run("'|a b|"), "|a b|");
+    assert_eq!(run(r"'|a\|b|"), r"|a\|b|");
+    assert_eq!(run("(symbol->string '|Hello World|)"), "\"Hello World\"");
+    assert_eq!(run("'|ABC|"), "ABC");
+    assert_eq!(run("(eq? '|FOO| 'foo)"), "#t");
+    assert_eq!(run("(string->symbol \"x(y\")"), "|x(y|");
+    assert_eq!(run("'|abc"), "err: read error: unterminated |symbol|");
+}
+
+#[test]
+fn write_shared_and_simple() {
+    let (mut heap, output) = heap_with_output();
+    eval(&mut heap, "(define x (list 1 2)) (define y (list x x))");
+    eval(&mut heap, "(write y)");
+    assert_eq!(output.take(), "((1 2) (1 2))");
+    eval(&mut heap, "(write-shared y)");
+    assert_eq!(output.take(), "(#0=(1 2) #0#)");
+    eval(&mut heap, "(write-simple y)");
+    assert_eq!(output.take(), "((1 2) (1 2))");
+    eval(&mut heap, "(define v (vector 1 x)) (vector-set! v 0 v)");
+    eval(&mut heap, "(write v)");
+    assert_eq!(output.take(), "#0=#(#0# (1 2))");
+    eval(&mut heap, "(write-simple (list v))");
+    let simple = output.take();
+    assert!(simple.starts_with("(#(#(#("), "{}", simple);
+    assert_eq!(simple.matches("#(").count(), 99);
+    assert!(simple.contains("#(... ...) (1 2))"), "{}", simple);
+}
+
+#[test]
+fn time_reports_and_returns_value() {
+    let (mut heap, output) = heap_with_output();
+    assert_eq!(eval(&mut heap, "(time (+ 1 2))"), "3");
+    let report = output.take();
+    assert!(report.starts_with("time: "), "{}", report);
+    assert!(!report.starts_with("time: 0ns"), "{}", report);
+    assert_eq!(eval(&mut heap, "(time (iota 5))"), "(0 1 2 3 4)");
+    let report = output.take();
+    let cells: usize = report
+        .trim_end()
+        .strip_suffix(" cells allocated")
+        .and_then(|r| r.rsplit(' ').next())
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!(cells >= 5, "{}", report);
+    assert_eq!(eval(&mut heap, "(time)"), "err: wrong number of arguments");
+}
+
+#[test]
+fn symbol_append() {
+    assert_eq!(run("(symbol-append 'foo '- 'bar)"), "FOO-BAR");
+    assert_eq!(run("(eq? (symbol-append 'point \"-x\") 'point-x)"), "#t");
+    assert_eq!(run("(symbol-append)"), "||");
+    assert_eq!(
+        run("(symbol-append 'a 1)"),
+        "err: type error: expected symbol or string, got integer"
+    );
+}
+
+#[test]
+fn integer_radix() {
+    let mut heap = Heap::new();
+    let mut repl = Repl {
+        quiet: true,
+        prompt: String::new(),
+    };
+    run_command(&mut heap, &mut repl, "radix 16");
+    assert_eq!(eval(&mut heap, "255"), "#xFF");
+    assert_eq!(eval(&mut heap, "(- 255)"), "#x-FF");
+    assert_eq!(eval(&mut heap, "(list #x-FF #b101)"), "(#x-FF #x5)");
+    run_command(&mut heap, &mut repl, "radix 2");
+    assert_eq!(eval(&mut heap, "5"), "#b101");
+    run_command(&mut heap, &mut repl, "radix 8");
+    assert_eq!(eval(&mut heap, "8"), "#o10");
+    run_command(&mut heap, &mut repl, "radix 7");
+    assert_eq!(eval(&mut heap, "8"), "#o10");
+    run_command(&mut heap, &mut repl, "radix 10");
+    assert_eq!(eval(&mut heap, "#xFF"), "255");
+}
+
+#[test]
+fn with_output_to_string() {
+    let (mut heap, output) = heap_with_output();
+    assert_eq!(
+        eval(
+            &mut heap,
+            "(with-output-to-string (lambda () (display 42)))"
+        ),
+        "\"42\""
+    );
+    assert_eq!(
+        eval(
+            &mut heap,
+            "(with-output-to-string (lambda () (cond (#t (display 1) (with-output-to-string (lambda () (display 2))) (display 3)))))"
+        ),
+        "\"13\""
+    );
+    assert_eq!(
+        eval(
+            &mut heap,
+            "(with-output-to-string (lambda () (cond (#t (display 1) (undefined)))))"
+        ),
+        "err: unbound symbol: UNDEFINED"
+    );
+    eval(&mut heap, "(display 5)");
+    assert_eq!(output.take(), "5");
+}
+
+#[test]
+fn string_ports() {
+    let mut heap = Heap::new();
+    eval(&mut heap, "(define in (open-input-string \"ab\"))");
+    assert_eq!(eval(&mut heap, "(peek-char in)"), "#\\a");
+    assert_eq!(eval(&mut heap, "(read-char in)"), "#\\a");
+    assert_eq!(eval(&mut heap, "(read-char in)"), "#\\b");
+    assert_eq!(eval(&mut heap, "(eof-object? (peek-char in))"), "#t");
+    assert_eq!(eval(&mut heap, "(eof-object? (read-char in))"), "#t");
+    assert_eq!(
+        eval(&mut heap, "(read (open-input-string \"(1 . x)\"))"),
+        "(1 . X)"
+    );
+    eval(
+        &mut heap,
+        r#"(define out (open-output-string))
+           (write-char #\h out)
+           (write "i" out)"#,
+    );
+    heap.collect_garbage();
+    assert_eq!(eval(&mut heap, "(get-output-string out)"), "\"h\\\"i\\\"\"");
+    assert_eq!(eval(&mut heap, "(input-port? in)"), "#t");
+    assert_eq!(eval(&mut heap, "(output-port? in)"), "#f");
+}
+
+#[test]
+fn current_output_port_parameter() {
+    let (mut heap, output) = heap_with_output();
+    eval(&mut heap, "(define out (open-output-string))");
+    eval(
+        &mut heap,
+        "(parameterize ((current-output-port out)) (cond (#t (display 1) (write \"two\"))))",
+    );
+    assert_eq!(output.take(), "");
+    assert_eq!(
+        eval(&mut heap, "(get-output-string out)"),
+        "\"1\\\"two\\\"\""
+    );
+    eval(&mut heap, "(display 3)");
+    assert_eq!(output.take(), "3");
+    assert_eq!(
+        eval(
+            &mut heap,
+            "(parameterize ((current-input-port (open-input-string \"xy\"))) (read-char))"
+        ),
+        "#\\x"
+    );
+}
+
+#[test]
+fn read_line_from_port() {
+    let mut heap = Heap::new();
+    eval(
+        &mut heap,
+        "(define in (open-input-string \"first\\nsecond\"))",
+    );
+    assert_eq!(eval(&mut heap, "(read-line in)"), "\"first\"");
+    assert_eq!(eval(&mut heap, "(read-line in)"), "\"second\"");
+    assert_eq!(eval(&mut heap, "(eof-object? (read-line in))"), "#t");
+    eval(&mut heap, "(define in (open-input-string \"\\n\"))");
+    assert_eq!(eval(&mut heap, "(read-line in)"), "\"\"");
+    assert_eq!(eval(&mut heap, "(eof-object? (read-line in))"), "#t");
+}
+
+#[test]
+fn lambda_parameter_shapes() {
+    assert_eq!(run("((lambda (a b) (list a b)) 1 2)"), "(1 2)");
+    assert_eq!(
+        run("((lambda (a . rest) (list a rest)) 1 2 3)"),
+        "(1 (2 3))"
+    );
+    assert_eq!(run("((lambda (a . rest) (list a rest)) 1)"), "(1 ())");
+    assert_eq!(run("((lambda args args) 1 2)"), "(1 2)");
+    assert_eq!(run("((lambda () 5))"), "5");
+    assert_eq!(run("(lambda (a 1) a)"), "err: expected a symbol");
+    assert_eq!(run("(lambda (a . 1) a)"), "err: expected a symbol");
+    assert_eq!(run("(lambda 1 1)"), "err: expected a symbol");
+    assert_eq!(run("(define (f . 2) 1)"), "err: expected a symbol");
+}
+
+#[test]
+fn equal_on_shared_structure() {
+    let mut heap = Heap::new();
+    eval(
+        &mut heap,
+        r#"(define (doubling n x) (if (= n 0) x (doubling (- n 1) (list x x))))
+           (define a (doubling 60 (list 1)))
+           (define b (doubling 60 (list 1)))
+           (define c (doubling 60 (list 2)))"#,
+    );
+    // comparing every path would take 2^60 steps
+    assert_eq!(eval(&mut heap, "(equal? a b)"), "#t");
+    assert_eq!(eval(&mut heap, "(equal? a c)"), "#f");
+    assert_eq!(
+        eval(&mut heap, "(equal? '#0=(1 . #0#) '#1=(1 . #1#))"),
+        "#t"
+    );
+    assert_eq!(
+        eval(
+            &mut heap,
+            "(equal? '(1 (2 #(3))) (list 1 (list 2 (vector 3))))"
+        ),
+        "#t"
+    );
+}
+
+#[test]
+fn hash_table_alist_round_trip() {
+    let mut heap = Heap::new();
+    eval(
+        &mut heap,
+        r#"(define t (alist->hash-table '((a . 1) (b . 2) (a . 3)) eq?))
+           (define (value key alist) (rest (find (lambda (p) (eq? (first p) key)) alist)))
+           (define alist (hash-table->alist t))"#,
+    );
+    assert_eq!(eval(&mut heap, "(hash-table-count t)"), "2");
+    // the first association for a key wins, as with assq
+    assert_eq!(
+        eval(&mut heap, "(list (value 'a alist) (value 'b alist))"),
+        "(1 2)"
+    );
+    assert_eq!(
+        eval(&mut heap, "(fold (lambda (p n) (+ n 1)) 0 alist)"),
+        "2"
+    );
+    eval(&mut heap, "(define t2 (alist->hash-table alist equal?))");
+    assert_eq!(eval(&mut heap, "(hash-table-ref/default t2 'b #f)"), "2");
+    assert_eq!(
+        eval(&mut heap, "(hash-table->alist (make-hash-table eq?))"),
+        "()"
+    );
+}
+
+#[test]
+fn vector_map_for_each_and_fill() {
+    assert_eq!(
+        run("(vector-map (lambda (x) (* x x)) #(1 2 3))"),
+        "#(1 4 9)"
+    );
+    assert_eq!(run("(vector-map + #(1 2) #(10 20))"), "#(11 22)");
+    // like map, it stops at the end of the shortest vector
+    assert_eq!(run("(vector-map + #(1 2) #(10))"), "#(11)");
+    let (mut heap, output) = heap_with_output();
+    eval(&mut heap, "(vector-for-each display #(1 2 3))");
+    assert_eq!(output.take(), "123");
+    assert_eq!(
+        eval(
+            &mut heap,
+            "(define v (vector 1 2 3 4)) (vector-fill! v 0 1 3) v"
+        ),
+        "#(1 0 0 4)"
+    );
+    assert_eq!(eval(&mut heap, "(vector-fill! v 9) v"), "#(9 9 9 9)");
+    assert_eq!(
+        eval(&mut heap, "(vector-fill! v 0 3 5)"),
+        "err: index out of range"
+    );
+    assert_eq!(run("(make-vector 2 'a)"), "#(A A)");
+    assert_eq!(run("(make-vector 100000000000)"), "err: index out of range");
+}
+
+#[test]
+fn vector_copy_and_overlapping_copy() {
+    assert_eq!(run("(vector-copy #(1 2 3))"), "#(1 2 3)");
+    assert_eq!(run("(vector-copy #(1 2 3) 1)"), "#(2 3)");
+    assert_eq!(run("(vector-copy #(1 2 3) 1 2)"), "#(2)");
+    assert_eq!(run("(vector-copy #(1 2 3) 2 1)"), "err: index out of range");
+    assert_eq!(run("(define v #(1 2)) (eq? v (vector-copy v))"), "#f");
+    assert_eq!(
+        run("(define v (vector 1 2 3 4 5)) (vector-copy! v 1 v 0 4) v"),
+        "#(1 1 2 3 4)"
+    );
+    assert_eq!(
+        run("(define v (vector 1 2 3 4 5)) (vector-copy! v 0 v 1) v"),
+        "#(2 3 4 5 5)"
+    );
+    assert_eq!(
+        run("(define v (vector 1 2 3)) (vector-copy! v 2 #(7 8)) v"),
+        "err: index out of range"
+    );
+}
+
+#[test]
+fn define_values() {
+    let mut heap = Heap::new();
+    eval(&mut heap, "(define-values (q r) (floor/ 7 2))");
+    assert_eq!(eval(&mut heap, "(list q r)"), "(3 1)");
+    eval(&mut heap, "(define-values (a . rest) (values 1 2 3))");
+    assert_eq!(eval(&mut heap, "(list a rest)"), "(1 (2 3))");
+    assert_eq!(
+        eval(&mut heap, "(define-values (x y) (values 1 2 3))"),
+        "err: wrong number of arguments: expected 2, got 3"
+    );
+}
+
+#[test]
+fn let_values() {
+    assert_eq!(
+        run("(let-values (((q r) (floor/ 7 2)) ((x) (values 10))) (list q r x))"),
+        "(3 1 10)"
+    );
+    assert_eq!(
+        run("(define q 5) (let-values (((q) (values 1)) ((r) (values q))) (list q r))"),
+        "(1 5)"
+    );
+    assert_eq!(run("(let-values (((a . b) (values 1 2 3))) b)"), "(2 3)");
+    assert_eq!(
+        run("(let-values (((a b) (values 1))) a)"),
+        "err: wrong number of arguments: expected 2, got 1"
+    );
+}
+
+#[test]
+fn and_or_cond_in_tail_position() {
+    let mut heap = Heap::new();
+    eval(
+        &mut heap,
+        r#"(define (count-down n) (cond ((= n 0) 'done) (#t (count-down (- n 1)))))
+           (define (all-even n) (or (= n 0) (and (even? (* 2 n)) (all-even (- n 1)))))"#,
+    );
+    assert_eq!(eval(&mut heap, "(count-down 100000)"), "DONE");
+    assert_eq!(eval(&mut heap, "(all-even 100000)"), "#t");
+}
+
+#[test]
+fn delay_force_chains() {
+    let mut heap = Heap::new();
+    eval(
+        &mut heap,
+        "(define (loop n) (delay-force (if (= n 0) (delay 'done) (loop (- n 1)))))",
+    );
+    assert_eq!(eval(&mut heap, "(force (loop 100000))"), "DONE");
+    assert_eq!(eval(&mut heap, "(force (make-promise 5))"), "5");
+    assert_eq!(
+        eval(&mut heap, "(define p (delay 1)) (eq? p (make-promise p))"),
+        "#t"
+    );
+    assert_eq!(eval(&mut heap, "(promise? (make-promise 5))"), "#t");
+}
+
+#[test]
+fn char_digit_conversion() {
+    assert_eq!(run("(char->digit #\\7)"), "7");
+    assert_eq!(run("(char->digit #\\f 16)"), "15");
+    assert_eq!(run("(char->digit #\\F 16)"), "15");
+    assert_eq!(run("(char->digit #\\z 36)"), "35");
+    assert_eq!(run("(char->digit #\\a)"), "#f");
+    assert_eq!(run("(char->digit #\\8 8)"), "#f");
+    assert_eq!(run("(digit->char 7)"), "#\\7");
+    assert_eq!(run("(digit->char 11 16)"), "#\\b");
+    assert_eq!(run("(digit->char 10)"), "#f");
+}
+
+#[test]
+fn command_line_list() {
+    let mut heap = Heap::new();
+    heap.command_line = vec!["prog.scm".into(), "a b".into()];
+    assert_eq!(eval(&mut heap, "(command-line)"), r#"("prog.scm" "a b")"#);
+}
+
+#[test]
+fn getenv_reads_variables() {
+    std::env::set_var("SCHEME_TEST_GETENV", "some value");
+    assert_eq!(run("(getenv \"SCHEME_TEST_GETENV\")"), "\"some value\"");
+    assert_eq!(run("(getenv \"SCHEME_TEST_GETENV_UNSET\")"), "#f");
+    assert_eq!(
+        run("(getenv 'home)"),
+        "err: type error: expected string, got symbol"
+    );
+}
+
+#[test]
+fn runtime_and_current_time() {
+    let mut heap = Heap::new();
+    assert_eq!(
+        eval(
+            &mut heap,
+            "(define a (runtime)) (define b (runtime)) (and (<= 0 a) (<= a b))"
+        ),
+        "#t"
+    );
+    // some time after this test was written
+    assert_eq!(eval(&mut heap, "(> (current-time) 1700000000)"), "#t");
+}
+
+#[test]
+fn seeded_random_is_deterministic() {
+    let mut heap = Heap::new();
+    let draw = "(list (random 100) (random 100) (random 100) (random 100))";
+    eval(&mut heap, "(random-seed 42)");
+    let first = eval(&mut heap, draw);
+    eval(&mut heap, "(random-seed 42)");
+    assert_eq!(eval(&mut heap, draw), first);
+    eval(&mut heap, "(random-seed 7)");
+    assert_ne!(eval(&mut heap, draw), first);
+    assert_eq!(
+        eval(
+            &mut heap,
+            "(every (lambda (n) (and (>= n 0) (< n 3))) (map (lambda (i) (random 3)) (iota 50)))"
+        ),
+        "#t"
+    );
+    assert_eq!(
+        eval(&mut heap, "(random 0)"),
+        "err: type error: expected positive integer, got integer"
+    );
+}
+
+#[test]
+fn list_index_and_partition() {
+    assert_eq!(run("(list-index even? '(1 3 4 5))"), "2");
+    assert_eq!(run("(list-index even? '(1 3))"), "#f");
+    assert_eq!(
+        run("(call-with-values (lambda () (partition odd? '(1 2 3 4 5))) list)"),
+        "((1 3 5) (2 4))"
+    );
+    assert_eq!(
+        run("(call-with-values (lambda () (partition odd? '())) list)"),
+        "(() ())"
+    );
+}
+
+#[test]
+fn delete_and_delete_duplicates() {
+    assert_eq!(run("(delete 2 '(1 2 3 2))"), "(1 3)");
+    assert_eq!(run("(delete '(a) '((a) b (a)))"), "(B)");
+    assert_eq!(run("(delete 5 '(1 2))"), "(1 2)");
+    assert_eq!(run("(delete-duplicates '(1 2 1 3 2 4))"), "(1 2 3 4)");
+    assert_eq!(run("(delete-duplicates '((a) (a) b))"), "((A) B)");
+    assert_eq!(
+        run("(delete-duplicates '(1 2 3 4 5) (lambda (a b) (= (remainder a 2) (remainder b 2))))"),
+        "(1 2)"
+    );
+    assert_eq!(
+        run("(define l '(1 2 1)) (delete-duplicates l) l"),
+        "(1 2 1)"
+    );
+}
+
+#[test]
+fn folds_over_several_lists() {
+    // fold passes the elements before the accumulator, fold-left passes it first
+    assert_eq!(run("(fold cons* '() '(a b c) '(1 2 3))"), "(C 3 B 2 A 1)");
+    assert_eq!(
+        run("(fold-right cons* '() '(a b c) '(1 2 3))"),
+        "(A 1 B 2 C 3)"
+    );
+    assert_eq!(
+        run("(fold-right (lambda (x y acc) (cons (cons x y) acc)) '() '(a b) '(1 2))"),
+        "((A . 1) (B . 2))"
+    );
+    assert_eq!(run("(fold-left list '() '(1 2) '(3 4))"), "((() 1 3) 2 4)");
+    assert_eq!(run("(fold + 0 '(1 2 3) '(10 20))"), "33");
+}
+
+#[test]
+fn zip_and_unzip() {
+    assert_eq!(run("(zip '(1 2 3) '(a b c))"), "((1 A) (2 B) (3 C))");
+    assert_eq!(run("(zip '(1 2 3) '(a))"), "((1 A))");
+    assert_eq!(run("(zip '(1 2))"), "((1) (2))");
+    assert_eq!(
+        run("(call-with-values (lambda () (unzip2 (zip '(1 2 3) '(a b c)))) list)"),
+        "((1 2 3) (A B C))"
+    );
+}
+
+#[test]
+fn alist_update() {
+    let mut heap = Heap::new();
+    eval(&mut heap, "(define al '((a . 1) ((b) . 2)))");
+    assert_eq!(
+        eval(&mut heap, "(alist-update '(b) 3 al)"),
+        "((A . 1) ((B) . 3))"
+    );
+    assert_eq!(
+        eval(&mut heap, "(alist-update 'c 4 al)"),
+        "((C . 4) (A . 1) ((B) . 2))"
+    );
+    assert_eq!(eval(&mut heap, "al"), "((A . 1) ((B) . 2))");
+}
+
+#[test]
+fn dots_inside_tokens() {
+    assert!(matches!(tokenize("1.5").unwrap().as_slice(), [Token::Value(v)] if v == "1.5"));
+    assert!(matches!(tokenize("...").unwrap().as_slice(), [Token::Value(v)] if v == "..."));
+    assert!(matches!(
+        tokenize("(a . b)").unwrap().as_slice(),
+        [
+            Token::LBracket,
+            Token::Value(_),
+            Token::Dot,
+            Token::Value(_),
+            Token::RBracket
+        ]
+    ));
+    assert!(matches!(
+        tokenize("(a .b)").unwrap().as_slice(),
+        [Token::LBracket, Token::Value(_), Token::Value(v), Token::RBracket] if v == ".b"
+    ));
+    assert_eq!(run("'(a . b)"), "(A . B)");
+    assert_eq!(run("'(a ... b)"), "(A ... B)");
+}
+
+#[test]
+fn long_boolean_spellings() {
+    assert_eq!(run("(list #t #f #true #false)"), "(#t #f #t #f)");
+    assert_eq!(run("(eq? #true #t)"), "#t");
+    assert_eq!(run("#truth"), "err: read error: unrecognised value");
+}
+
+#[test]
+fn polymorphic_comparisons() {
+    assert_eq!(run("(< 1 2)"), "#t");
+    assert_eq!(run("(= 2 2)"), "#t");
+    assert_eq!(run("(>= 1 2)"), "#f");
+    assert_eq!(run("(< #\\a #\\b)"), "#t");
+    assert_eq!(run("(= \"abc\" \"abc\")"), "#t");
+    assert_eq!(run("(> \"abc\" \"abd\")"), "#f");
+    assert_eq!(
+        run("(= 1 \"1\")"),
+        "err: type error: expected integer, got string"
+    );
+    assert_eq!(
+        run("(< #\\a 1)"),
+        "err: type error: expected char, got integer"
+    );
+    assert_eq!(
+        run("(< 'a 'b)"),
+        "err: type error: expected integer, char or string, got symbol"
+    );
+}
+
+#[test]
+fn exit_unwinds_as_an_error() {
+    let (mut heap, output) = heap_with_output();
+    let result = heap.eval_source("(display 1) (exit 0) (display 2)");
+    assert!(matches!(result, Err(SError::Exit(0))));
+    assert_eq!(output.take(), "1");
+    assert!(matches!(heap.eval_source("(exit)"), Err(SError::Exit(0))));
+    assert!(matches!(heap.eval_source("(exit 3)"), Err(SError::Exit(3))));
+    assert!(matches!(
+        heap.eval_source("(exit #f)"),
+        Err(SError::Exit(1))
+    ));
+    eval(
+        &mut heap,
+        "(dynamic-wind (lambda () #f) (lambda () (exit 2)) (lambda () (display 'after)))",
+    );
+    assert_eq!(output.take(), "AFTER");
+    let result = heap.eval_source(
+        "(dynamic-wind (lambda () #f) (lambda () (emergency-exit 4)) (lambda () (display 'after)))",
+    );
+    assert!(matches!(result, Err(SError::EmergencyExit(4))));
+    assert_eq!(output.take(), "");
+}
+
+#[test]
+fn features_and_cond_expand() {
+    assert_eq!(run("(features)"), "(R7RS SCHEME)");
+    assert_eq!(
+        run("(cond-expand (no-such-feature 1) (r7rs 2) (else 3))"),
+        "2"
+    );
+    assert_eq!(
+        run("(cond-expand ((and r7rs (not no-such-feature)) 'a) (else 'b))"),
+        "A"
+    );
+    assert_eq!(
+        run("(cond-expand ((or no-such-feature other-feature) 'a) (else 'b))"),
+        "B"
+    );
+    // only the selected branch is evaluated
+    assert_eq!(run("(cond-expand (r7rs 'ok) (else (undefined)))"), "OK");
+}
+
+#[test]
+fn string_split_and_join() {
+    assert_eq!(run(r#"(string-split "a,b,c" #\,)"#), r#"("a" "b" "c")"#);
+    assert_eq!(run(r#"(string-split "a,,b," ",")"#), r#"("a" "" "b" "")"#);
+    assert_eq!(run(r#"(string-split "a::b" "::")"#), r#"("a" "b")"#);
+    assert_eq!(run(r#"(string-split "" #\,)"#), r#"("")"#);
+    assert_eq!(run(r#"(string-join '("a" "b" "c") ", ")"#), r#""a, b, c""#);
+    assert_eq!(run(r#"(string-join '() "-")"#), r#""""#);
+    assert_eq!(
+        run(r#"(string-join (string-split "x y z" #\space) "_")"#),
+        r#""x_y_z""#
+    );
+}
+
+#[test]
+fn string_searching() {
+    assert_eq!(run(r#"(string-contains "hello world" "o w")"#), "4");
+    assert_eq!(run(r#"(string-contains "héllo" "llo")"#), "2");
+    assert_eq!(run(r#"(string-contains "hello" "xyz")"#), "#f");
+    assert_eq!(run(r#"(string-contains "hello" "")"#), "0");
+    assert_eq!(run(r#"(string-index "héllo" #\l)"#), "2");
+    assert_eq!(
+        run(r#"(string-index "abc1" (lambda (c) (char->digit c)))"#),
+        "3"
+    );
+    assert_eq!(run(r#"(string-index "abc" #\z)"#), "#f");
+    assert_eq!(run(r#"(string-prefix? "he" "hello")"#), "#t");
+    assert_eq!(run(r#"(string-prefix? "lo" "hello")"#), "#f");
+    assert_eq!(run(r#"(string-suffix? "lo" "hello")"#), "#t");
+    assert_eq!(run(r#"(string-suffix? "hello!" "hello")"#), "#f");
+}
+
+#[test]
+fn string_case_and_trimming() {
+    assert_eq!(
+        run(r#"(string-upcase "Hello, World")"#),
+        r#""HELLO, WORLD""#
+    );
+    assert_eq!(
+        run(r#"(string-downcase "Hello, World")"#),
+        r#""hello, world""#
+    );
+    assert_eq!(run(r#"(define s "Ab") (string-upcase s) s"#), r#""Ab""#);
+    assert_eq!(run(r#"(string-trim "  a b  ")"#), r#""a b""#);
+    assert_eq!(run(r#"(string-trim-left "  a b  ")"#), r#""a b  ""#);
+    assert_eq!(run(r#"(string-trim-right "  a b  ")"#), r#""  a b""#);
+    assert_eq!(run("(string-trim \" \\t\\n \")"), r#""""#);
+    assert_eq!(run(r#"(string-trim-left "   ")"#), r#""""#);
+    assert_eq!(
+        run(r#"(string-trim "xxaxx" (lambda (c) (= c #\x)))"#),
+        r#""a""#
+    );
+}
+
+// steps the incremental collector through the rest of a whole cycle
+fn finish_incremental_cycle(heap: &mut Heap) {
+    heap.collect_garbage();
+    while !matches!(heap.gc.phase, Phase::Idle) {
+        heap.collect_garbage();
+    }
+}
+
+#[test]
+fn incremental_collection_matches_full_collection() {
+    let mut heap = Heap::new();
+    eval(
+        &mut heap,
+        r#"(define keep (iota 3000))
+           (define table (make-hash-table equal?))
+           (hash-table-set! table 'numbers (vector (iota 100) "s"))
+           (iota 5000)
+           (map (lambda (n) (list n n)) (iota 2000))"#,
+    );
+    let before = heap.cells.len();
+    finish_incremental_cycle(&mut heap);
+    let incremental = heap.cells.len();
+    assert!(incremental < before - 5000);
+    heap.collect();
+    assert_eq!(heap.cells.len(), incremental);
+
+    // values stored while a cycle is under way must survive it
+    eval(&mut heap, "(iota 5000)");
+    heap.collect_garbage();
+    heap.collect_garbage();
+    eval(
+        &mut heap,
+        r#"(set! keep (list 'new (iota 10)))
+           (hash-table-set! table 'other (list 1 2 3))
+           (define later (cons 'a 'b))"#,
+    );
+    finish_incremental_cycle(&mut heap);
+    assert_eq!(eval(&mut heap, "keep"), "(NEW (0 1 2 3 4 5 6 7 8 9))");
+    assert_eq!(
+        eval(&mut heap, "(hash-table-ref/default table 'other #f)"),
+        "(1 2 3)"
+    );
+    assert_eq!(
+        eval(&mut heap, "(hash-table-ref/default table 'numbers #f)"),
+        format!("#({} \"s\")", eval(&mut heap, "(iota 100)"))
+    );
+    assert_eq!(eval(&mut heap, "later"), "(A . B)");
+    // a second cycle picks up whatever the first had already marked
+    finish_incremental_cycle(&mut heap);
+    let incremental = heap.cells.len();
+    heap.collect();
+    assert_eq!(heap.cells.len(), incremental);
+}
+
+#[test]
+fn collection_during_evaluation_keeps_live_values() {
+    let mut heap = Heap::new();
+    eval(
+        &mut heap,
+        r#"(define (churn x) (cond (#t (collect-garbage) (iota 20) (list x x))))"#,
+    );
+    // the argument list, the map accumulator and the fold accumulator are only
+    // reachable from the Rust stack when collect-garbage runs
+    assert_eq!(
+        eval(&mut heap, "(list (iota 3) (churn 'a) (list 'b 'c))"),
+        "((0 1 2) (A A) (B C))"
+    );
+    assert_eq!(
+        eval(&mut heap, "(map churn '(1 2 3))"),
+        "((1 1) (2 2) (3 3))"
+    );
+    assert_eq!(
+        eval(
+            &mut heap,
+            "(fold (lambda (x acc) (cons (churn x) acc)) '() '(1 2))"
+        ),
+        "((2 2) (1 1))"
+    );
+    assert_eq!(
+        eval(
+            &mut heap,
+            "((lambda (a b) (list a b)) (list 1 2) (cond (#t (churn 0) (list 3 4))))"
+        ),
+        "((1 2) (3 4))"
+    );
+    assert_eq!(
+        eval(&mut heap, "(vector-map churn (vector (list 1) (list 2)))"),
+        "#((#0=(1) #0#) (#1=(2) #1#))"
+    );
+}
+
+// how many cells evaluating a form allocates, not counting reading it
+fn allocations(heap: &mut Heap, source: &str) -> usize {
+    let expr = heap.eval_source(&format!("'{}", source)).unwrap();
+    let before = heap.allocations;
+    heap.eval(&expr).unwrap();
+    heap.allocations - before
+}
+
+#[test]
+fn numeric_loop_allocations() {
+    let mut heap = Heap::new();
+    eval(
+        &mut heap,
+        "(define (count-to n acc) (if (= n 0) acc (count-to (- n 1) (+ acc 1))))",
+    );
+    // each iteration allocates the argument lists for =, - and + and the
+    // frame for count-to, and nothing else
+    let per_iteration = 3 * 2 + 5;
+    let base = allocations(&mut heap, "(count-to 0 0)");
+    assert_eq!(
+        allocations(&mut heap, "(count-to 1000 0)"),
+        base + 1000 * per_iteration
+    );
+    assert_eq!(allocations(&mut heap, "(+ 1 2 3)"), 3);
+    assert_eq!(allocations(&mut heap, "(- 10 (* 2 3) 1)"), 5);
+}
+
+#[test]
+fn closure_calls_allocate_only_their_frame() {
+    let mut heap = Heap::new();
+    eval(
+        &mut heap,
+        "(define (f a b c) a) (define (g . rest) rest) (define (h) 1)",
+    );
+    // the frame cell, plus a binding and a cell of the binding list per parameter
+    assert_eq!(allocations(&mut heap, "(f 1 2 3)"), 1 + 3 * 2);
+    assert_eq!(allocations(&mut heap, "(h)"), 1);
+    // a rest parameter is bound to a fresh list of the remaining arguments
+    assert_eq!(allocations(&mut heap, "(g 1 2)"), 1 + 2 + 2);
+    // primitives still get a list of their arguments
+    assert_eq!(allocations(&mut heap, "(list 1 2 3)"), 3);
+    assert_eq!(eval(&mut heap, "(f (list 1) 2 3)"), "(1)");
+    assert_eq!(
+        eval(&mut heap, "(f 1 2)"),
+        "err: wrong number of arguments: expected 3, got 2"
+    );
+}
+
+#[test]
+fn analysed_and_unanalysed_evaluation_agree() {
+    // a form at top level is evaluated as it stands, but the same form as the
+    // body of a closure is analysed first, and the two have to give the same result
+    let setup = "(define (fact n) (if (= n 0) 1 (* n (fact (- n 1)))))
+                 (define-syntax my-if (syntax-rules () ((_ c a b) (cond (c a) (else b)))))
+                 (define counter 0)";
+    let cases = [
+        "42",
+        "'(a . b)",
+        "fact",
+        "(fact 10)",
+        "(if (< 1 2) 'yes 'no)",
+        "(and 1 2 #f 3)",
+        "(and 1 2)",
+        "(or #f #f)",
+        "(and)",
+        "(or)",
+        "(cond ((= 1 2) 'a) ((= 1 1) (define z 3) (* z 2)) (else 'c))",
+        "(cond (#f 1))",
+        "(cond (7))",
+        "(cond ((rest '(1 2)) => (lambda (p) (first p))) (else 'none))",
+        "(my-if #f 1 2)",
+        "((lambda (x . rest) (list x rest)) 1 2 3)",
+        "(cond (#t (define (sq x) (* x x)) (sq 5)))",
+        "(cond (#t (set! counter (+ counter 1)) counter))",
+        "(let-values (((a b) (values 1 2))) (+ a b))",
+        "(eval 'counter (the-environment))",
+        "(cond (#t (define x 1) (set! x 2) x))",
+        "(unbound-variable 1)",
+        "(set! also-unbound 1)",
+        "(if 1 2)",
+        "(quote)",
+        "(lambda (1) 2)",
+        "(define (f . 1) 2)",
+        "(fact 'a)",
+        "((lambda (x) x))",
+        "(1 2 3)",
+        "(+ 1 . 2)",
+    ];
+    for case in cases {
+        let mut heap = Heap::new();
+        eval(&mut heap, setup);
+        let unanalysed = eval(&mut heap, case);
+        let mut heap = Heap::new();
+        eval(&mut heap, setup);
+        let analysed = eval(&mut heap, &format!("((lambda () {}))", case));
+        assert_eq!(analysed, unanalysed, "{}", case);
+    }
+}
+
+#[test]
+fn closure_bodies_are_analysed_once() {
+    let mut heap = Heap::new();
+    let f = heap
+        .eval_source("(lambda (n) (if (= n 0) 'done (later n)))")
+        .unwrap();
+    let code = heap.get_lambda_code(&f).unwrap();
+    assert!(matches!(*code.nodes[0], Node::If(..)));
+    // a macro defined after the closure is still expanded where it's used
+    eval(
+        &mut heap,
+        "(define-syntax later (syntax-rules () ((_ n) (list 'later n))))",
+    );
+    heap.root(&f);
+    let args = heap.make_list(vec![Expr::Integer(3)]).unwrap();
+    let result = heap.apply(&f, &args).unwrap();
+    assert_eq!(heap.format_expr(&result).unwrap(), "(LATER 3)");
+    assert!(Rc::ptr_eq(&code, &heap.get_lambda_code(&f).unwrap()));
+    // the code keeps the body alive however the closure is reached
+    heap.collect();
+    assert_eq!(
+        heap.format_expr(&heap.get_lambda_body(&f).unwrap())
+            .unwrap(),
+        "((IF (= N 0) 'DONE (LATER N)))"
+    );
+}
+
+#[test]
+fn syntax_rules_macros() {
+    let swap = "(define-syntax swap!
+                  (syntax-rules ()
+                    ((_ a b) ((lambda (tmp) (cond (#t (set! a b) (set! b tmp)))) a))))";
+    assert_eq!(
+        run(&format!(
+            "{} (define x 1) (define y 2) (swap! x y) (list x y)",
+            swap
+        )),
+        "(2 1)"
+    );
+    // the template's tmp can't capture a variable of the same name
+    assert_eq!(
+        run(&format!(
+            "{} (define tmp 1) (define y 2) (swap! tmp y) (list tmp y)",
+            swap
+        )),
+        "(2 1)"
+    );
+    let my_or = "(define-syntax my-or
+                   (syntax-rules ()
+                     ((_) #f)
+                     ((_ e) e)
+                     ((_ e r ...) ((lambda (t) (if t t (my-or r ...))) e))))";
+    assert_eq!(run(&format!("{} (my-or)", my_or)), "#f");
+    assert_eq!(run(&format!("{} (my-or #f 2 (error \"no\"))", my_or)), "2");
+    assert_eq!(run(&format!("{} (define t 5) (my-or #f t)", my_or)), "5");
+    // a recursive macro, where later bindings see earlier ones
+    let my_let = "(define-syntax my-let
+                    (syntax-rules ()
+                      ((_ () body) body)
+                      ((_ ((name value) more ...) body)
+                       ((lambda (name) (my-let (more ...) body)) value))))";
+    assert_eq!(
+        run(&format!(
+            "{} (my-let ((a 1) (b (+ a 1))) (list a b))",
+            my_let
+        )),
+        "(1 2)"
+    );
+    assert_eq!(run(&format!("{} (my-let () 7)", my_let)), "7");
+    // ellipses after nested patterns, in vectors and before the end of a pattern
+    assert_eq!(
+        run(
+            "(define-syntax groups (syntax-rules () ((_ (x ...) ...) '((x ...) ...))))
+             (groups (a b) (c) ())"
+        ),
+        "((A B) (C) ())"
+    );
+    assert_eq!(
+        run(
+            "(define-syntax from-vector (syntax-rules () ((_ #(a ...)) (list a ...))))
+             (from-vector #(1 2 3))"
+        ),
+        "(1 2 3)"
+    );
+    assert_eq!(
+        run("(define-syntax last-of (syntax-rules () ((_ x ... y) 'y)))
+             (last-of 1 2 3)"),
+        "3"
+    );
+    assert_eq!(
+        run("(define-syntax my-if (syntax-rules (then else)
+               ((_ c then a else b) (cond (c a) (else b)))))
+             (my-if #f then 1 else 2)"),
+        "2"
+    );
+}
+
+#[test]
+fn macro_hygiene() {
+    // free identifiers in a template mean what they meant where the macro was
+    // defined, whatever the macro's user has bound to the same names
+    let first_of = "(define-syntax first-of (syntax-rules () ((_ x) (first x))))";
+    assert_eq!(
+        run(&format!(
+            "{} ((lambda (first) (first-of (list 1 2))) (lambda (l) 42))",
+            first_of
+        )),
+        "1"
+    );
+    assert_eq!(
+        run("(define (f)
+               (cond (#t (define-syntax inc! (syntax-rules () ((_ v) (set! v (+ v 1)))))
+                         (define n 0)
+                         (inc! n)
+                         n)))
+             ((lambda (+) (f)) -)"),
+        "1"
+    );
+    // a template's bindings are only visible to the template
+    assert_eq!(
+        run("(define-syntax while
+               (syntax-rules ()
+                 ((_ c b ...)
+                  ((lambda ()
+                     (cond (#t (define (loop) (if c (cond (#t b ... (loop))) #f)) (loop))))))))
+             (define loop 10)
+             (define j 0)
+             (while (< j loop) (set! j (+ j 1)))
+             j"),
+        "10"
+    );
+    // renamed identifiers are not symbols anyone can write
+    assert_eq!(
+        run("(define |QUOTE 2| 9) (|QUOTE 2| abc)"),
+        "err: unbound symbol: ABC"
+    );
+    assert_eq!(
+        run("(define-syntax sym (syntax-rules () ((_) 'sym))) (eq? (sym) 'sym)"),
+        "#t"
+    );
+    // a quoted identifier from another expansion is still just a symbol
+    assert_eq!(
+        run("(define-syntax def-quoter
+               (syntax-rules () ((_ name v) (define-syntax name (syntax-rules () ((_) 'v))))))
+             (def-quoter get-v hello)
+             (list (get-v) (symbol? ((lambda () (get-v)))))"),
+        "(HELLO #t)"
+    );
+    // a macro keeps the environment it was defined in alive
+    let mut heap = Heap::new();
+    eval(
+        &mut heap,
+        "(define make-getter
+           (lambda (secret)
+             (cond (#t (define-syntax get (syntax-rules () ((_) secret)))
+                       (lambda () (get))))))
+         (define g (make-getter 'kept))",
+    );
+    heap.collect();
+    assert_eq!(eval(&mut heap, "(g)"), "KEPT");
+}
+
+#[test]
+fn while_and_dotimes_loops() {
+    assert_eq!(
+        run("(define sum 0) (dotimes (i 5) (set! sum (+ sum i))) sum"),
+        "10"
+    );
+    assert_eq!(run("(dotimes (i 3) i)"), "#<unspecified>");
+    assert_eq!(
+        run("(define n 0) (while (< n 10) (set! n (+ n 1))) n"),
+        "10"
+    );
+    // neither grows the stack however many times it goes round
+    assert_eq!(
+        run("(define sum 0) (dotimes (i 200000) (set! sum (+ sum 1))) sum"),
+        "200000"
+    );
+    assert_eq!(
+        run("(dotimes (i 'a) i)"),
+        "err: type error: expected integer, got symbol"
+    );
+    assert_eq!(run("(dotimes (1 2) 3)"), "err: expected a symbol");
+}
+
+#[test]
+fn cons_star_builds_lists() {
+    assert_eq!(run("(cons* 1 2 3 (list 4 5))"), "(1 2 3 4 5)");
+    assert_eq!(run("(cons* 1 2 3)"), "(1 2 . 3)");
+    assert_eq!(run("(cons* 1)"), "1");
+    assert_eq!(run("(cons* '(a))"), "(A)");
+    assert_eq!(
+        run("(cons*)"),
+        "err: wrong number of arguments: expected at least 1, got 0"
+    );
+}
+
+#[test]
+fn append_map_and_flatten() {
+    assert_eq!(
+        run("(append-map (lambda (x) (make-list x x)) '(1 2 0 3))"),
+        "(1 2 2 3 3 3)"
+    );
+    assert_eq!(run("(append-map list '(1 2) '(a b))"), "(1 A 2 B)");
+    assert_eq!(
+        run("(flatten '(1 (2 (3 (4 (5 (6))))) () ((7)) 8))"),
+        "(1 2 3 4 5 6 7 8)"
+    );
+    // a deeply nested list
+    assert_eq!(
+        run("(define (nest n l) (if (= n 0) l (nest (- n 1) (list l)))) (flatten (nest 1000 '(x y)))"),
+        "(X Y)"
+    );
+    // the same list can appear twice, but a cycle is an error
+    assert_eq!(run("(define l '(1 2)) (flatten (list l l))"), "(1 2 1 2)");
+    assert_eq!(run("(flatten '#0=(1 #0#))"), "err: improper list");
+    assert_eq!(run("(flatten '#0=(1 . #0#))"), "err: improper list");
+}
+
+#[test]
+fn set_changes_only_existing_bindings() {
+    assert_eq!(run("(define x 1) (set! x 2) x"), "2");
+    assert_eq!(run("(define x 1) (define (f) (set! x 2)) (f) x"), "2");
+    // a local binding is changed rather than the global one it shadows
+    assert_eq!(
+        run("(define x 1) (define (h x) (cond (#t (set! x 5) x))) (list (h 0) x)"),
+        "(5 1)"
+    );
+    // an unbound name is an error rather than a new global
+    assert_eq!(run("(set! nope 1)"), "err: unbound symbol: NOPE");
+    let mut heap = Heap::new();
+    assert_eq!(
+        eval(&mut heap, "(define (g) (set! nope 1)) (g)"),
+        "err: unbound symbol: NOPE"
+    );
+    assert_eq!(eval(&mut heap, "nope"), "err: unbound symbol: NOPE");
+}
+
+#[test]
+fn cond_clause_definitions_stay_local() {
+    assert_eq!(
+        run("(define x 1) (list (cond (#t (define x 2) x)) x)"),
+        "(2 1)"
+    );
+    let mut heap = Heap::new();
+    assert_eq!(eval(&mut heap, "(cond (#f 1) (else (define z 3) z))"), "3");
+    assert_eq!(eval(&mut heap, "z"), "err: unbound symbol: Z");
+    assert_eq!(
+        eval(&mut heap, "(define (f) (cond (#t (define y 2) y))) (f)"),
+        "2"
+    );
+    assert_eq!(eval(&mut heap, "y"), "err: unbound symbol: Y");
+    // a clause without definitions still sees and changes the enclosing scope
+    assert_eq!(
+        eval(&mut heap, "(define w 1) (cond (#t (set! w 2))) w"),
+        "2"
+    );
+}
+
+#[test]
+fn make_list_and_list_set() {
+    assert_eq!(run("(make-list 3 'a)"), "(A A A)");
+    assert_eq!(run("(make-list 2)"), "(#<unspecified> #<unspecified>)");
+    assert_eq!(run("(make-list 0 1)"), "()");
+    assert_eq!(run("(make-list 100000000000)"), "err: index out of range");
+    assert_eq!(
+        run("(define l (list 1 2 3)) (list-set! l 1 'x) l"),
+        "(1 X 3)"
+    );
+    assert_eq!(run("(list-set! (list 1 2) 0 'x)"), "#<unspecified>");
+    assert_eq!(
+        run("(list-set! (list 1 2) 2 'x)"),
+        "err: index out of range"
+    );
+    assert_eq!(
+        run("(list-set! (list 1 2) -1 'x)"),
+        "err: index out of range"
+    );
+}
+
+#[test]
+fn reduce_handles_empty_and_single_lists() {
+    assert_eq!(run("(reduce + 0 '())"), "0");
+    assert_eq!(run("(reduce + 0 '(5))"), "5");
+    assert_eq!(run("(reduce + 0 '(1 2 3 4))"), "10");
+    assert_eq!(
+        run("(reduce (lambda (a b) (if (> a b) a b)) 0 '(3 9 2))"),
+        "9"
+    );
+    // the first element is the seed and each later one is passed first
+    assert_eq!(run("(reduce list 0 '(1 2 3))"), "(3 (2 1))");
+}
+
+#[test]
+fn unicode_case_folding() {
+    assert_eq!(run("(char-foldcase #\\A)"), "#\\a");
+    assert_eq!(run("(char-foldcase #\\Σ)"), "#\\σ");
+    assert_eq!(run("(char-downcase #\\Σ)"), "#\\σ");
+    // a char whose case mapping is several chars is left as it is
+    assert_eq!(run("(char-upcase #\\ß)"), "#\\ß");
+    // but strings can change length
+    assert_eq!(run("(string-upcase \"straße\")"), "\"STRASSE\"");
+    assert_eq!(run("(string-foldcase \"Straße\")"), "\"strasse\"");
+    assert_eq!(run("(string-downcase \"İ\")"), "\"i\u{307}\"");
+    assert_eq!(run("(string-upcase \"ﬃ\")"), "\"FFI\"");
+}
+
+#[test]
+fn sorting_is_stable() {
+    let by_first = "(lambda (x y) (< (first x) (first y)))";
+    assert_eq!(
+        run(&format!("(sort '((1 a) (0 b) (1 c) (0 d)) {})", by_first)),
+        "((0 B) (0 D) (1 A) (1 C))"
+    );
+    assert_eq!(
+        run(&format!(
+            "(define v (vector '(1 a) '(0 b) '(1 c) '(0 d))) (vector-sort! v {}) v",
+            by_first
+        )),
+        "#((0 B) (0 D) (1 A) (1 C))"
+    );
+    assert_eq!(
+        run("(define v (vector 3 1 2)) (list (vector-sort! v <) v)"),
+        "(#<unspecified> #(1 2 3))"
+    );
+    assert_eq!(run("(sort (vector 3 1 2) <)"), "#(1 2 3)");
+    assert_eq!(run("(sort '(1 2) 5)"), "err: not callable: integer");
+}
+
+#[test]
+fn string_map_and_for_each() {
+    assert_eq!(run("(string-map char-upcase \"hello\")"), "\"HELLO\"");
+    // several strings are mapped together up to the shortest
+    assert_eq!(
+        run("(string-map (lambda (a b) b) \"adc\" \"bx\")"),
+        "\"bx\""
+    );
+    assert_eq!(
+        run("(string-map (lambda (c) 1) \"ab\")"),
+        "err: type error: expected char, got integer"
+    );
+    assert_eq!(
+        run("(with-output-to-string (lambda () (string-for-each (lambda (c) (display (char-upcase c))) \"abc\")))"),
+        "\"ABC\""
+    );
+}
+
+#[test]
+fn division_pairs_agree_with_quotient_and_remainder() {
+    let mut heap = Heap::new();
+    let expected = [
+        // n, d, floor/, truncate/, euclidean/
+        (7, 2, (3, 1), (3, 1), (3, 1)),
+        (-7, 2, (-4, 1), (-3, -1), (-4, 1)),
+        (7, -2, (-4, -1), (-3, 1), (-3, 1)),
+        (-7, -2, (3, -1), (3, -1), (4, 1)),
+    ];
+    for (n, d, floor, truncate, euclidean) in expected {
+        for (name, (q, r)) in [
+            ("floor/", floor),
+            ("truncate/", truncate),
+            ("euclidean/", euclidean),
+        ] {
+            assert_eq!(q * d + r, n);
+            let source = format!("(call-with-values (lambda () ({} {} {})) list)", name, n, d);
+            assert_eq!(eval(&mut heap, &source), format!("({} {})", q, r));
+        }
+        let source = format!("(list (quotient {0} {1}) (remainder {0} {1}))", n, d);
+        assert_eq!(
+            eval(&mut heap, &source),
+            format!("({} {})", truncate.0, truncate.1)
+        );
+        let source = format!("(list (floor-quotient {0} {1}) (modulo {0} {1}))", n, d);
+        assert_eq!(
+            eval(&mut heap, &source),
+            format!("({} {})", floor.0, floor.1)
+        );
+    }
+    assert_eq!(eval(&mut heap, "(euclidean/ 1 0)"), "err: division by zero");
+}
+
+#[test]
+fn compose_and_identity() {
+    assert_eq!(
+        run("((compose (lambda (x) (* x 2)) (lambda (x) (+ x 1))) 5)"),
+        "12"
+    );
+    assert_eq!(run("((compose first rest) '(1 2 3))"), "2");
+    assert_eq!(run("((compose) 7)"), "7");
+    assert_eq!(run("(identity 'a)"), "A");
+    // a bad argument is caught when composing rather than when calling
+    assert_eq!(
+        run("(compose first 5)"),
+        "err: type error: expected procedure, got integer"
+    );
+}
+
+#[test]
+fn save_skips_values_with_no_written_form() {
+    let mut heap = Heap::new();
+    eval(
+        &mut heap,
+        "(define head first)
+         (define second (compose first rest))
+         (define items (list 1 (delay 2)))
+         (define (double x) (* x 2))",
+    );
+    assert_eq!(
+        heap.format_definitions().unwrap(),
+        "(DEFINE HEAD FIRST)\n(DEFINE (DOUBLE X) (* X 2))\n"
+    );
+    // an alias is only saved while the primitive's own name still refers to it
+    eval(&mut heap, "(define first rest)");
+    assert_eq!(
+        heap.format_definitions().unwrap(),
+        "(DEFINE FIRST REST)\n(DEFINE (DOUBLE X) (* X 2))\n"
+    );
+}
+
+#[test]
+fn partial_application() {
+    assert_eq!(run("(map (partial + 10) '(1 2 3))"), "(11 12 13)");
+    assert_eq!(run("((partial list 'a 'b) 'c)"), "(A B C)");
+    // the captured arguments are passed as they are, not evaluated again
+    assert_eq!(run("((partial list '(x y)))"), "((X Y))");
+    assert_eq!(run("((partial +))"), "0");
+    assert_eq!(
+        run("(partial 'f 1)"),
+        "err: type error: expected procedure, got symbol"
+    );
+    let mut heap = Heap::new();
+    eval(&mut heap, "(define add10 (partial + 10))");
+    assert_eq!(heap.format_definitions().unwrap(), "");
+}
+
+#[test]
+fn handlers_run_where_the_exception_is_raised() {
+    // raise-continuable returns the handler's value to the raise
+    assert_eq!(
+        run("(+ 1 (with-exception-handler (lambda (e) 10) (lambda () (+ 2 (raise-continuable 'oops)))))"),
+        "13"
+    );
+    assert_eq!(
+        run("(with-exception-handler (lambda (e) 21) (lambda () (+ (raise-continuable 'a) (raise-continuable 'b))))"),
+        "42"
+    );
+    // the handler catches a raised object, or an error object for any other error
+    let (mut heap, output) = heap_with_output();
+    assert_eq!(
+        eval(
+            &mut heap,
+            "(with-exception-handler (lambda (e) (cond (#t (display e) 10))) (lambda () (+ 2 (raise 'oops))))"
+        ),
+        "err: exception handler returned"
+    );
+    assert_eq!(output.take(), "OOPS");
+    eval(
+        &mut heap,
+        "(with-exception-handler (lambda (e) (cond (#t (display (error-object-message e)) 10))) (lambda () (+ 2 (first 5))))",
+    );
+    assert_eq!(output.take(), "type error: expected pair, got integer");
+    // the handler runs before anything is unwound, with parameters still bound
+    // and dynamic-wind's after thunks yet to run
+    eval(
+        &mut heap,
+        "(define p (make-parameter 1))
+         (with-exception-handler
+           (lambda (e) (cond (#t (display (p)) 0)))
+           (lambda () (parameterize ((p 2)) (raise-continuable 'x))))",
+    );
+    assert_eq!(output.take(), "2");
+    eval(
+        &mut heap,
+        "(with-exception-handler
+           (lambda (e) (display 'handler))
+           (lambda () (dynamic-wind (lambda () #f) (lambda () (raise 'x)) (lambda () (display 'after)))))",
+    );
+    assert_eq!(output.take(), "HANDLERAFTER");
+    // each handler runs with only the outer ones installed, and when a handler
+    // returns from a raise that is raised to the outer handlers in turn
+    eval(
+        &mut heap,
+        "(with-exception-handler
+           (lambda (e) (cond (#t (display (list 'outer e)) 1)))
+           (lambda ()
+             (with-exception-handler
+               (lambda (e) (cond (#t (display (list 'inner e)) (raise-continuable 'again))))
+               (lambda () (raise 'x)))))",
+    );
+    assert_eq!(
+        output.take(),
+        "(INNER X)(OUTER AGAIN)(OUTER #<error \"exception handler returned\">)"
+    );
+    // an error escaping a handler unwinds past the handlers
+    assert_eq!(
+        eval(
+            &mut heap,
+            "(with-exception-handler (lambda (e) (raise (list 'wrapped e))) (lambda () (raise 'x)))"
+        ),
+        "err: uncaught exception: (WRAPPED X)"
+    );
+    assert_eq!(
+        eval(
+            &mut heap,
+            "(with-exception-handler (lambda (e) (first e)) (lambda () (raise 'x)))"
+        ),
+        "err: type error: expected pair, got symbol"
+    );
+    assert_eq!(
+        eval(&mut heap, "(with-exception-handler 5 (lambda () 1))"),
+        "err: type error: expected procedure, got integer"
+    );
+    assert_eq!(eval(&mut heap, "(raise 'x)"), "err: uncaught exception: X");
+}
+
+#[test]
+fn caught_errors_expose_message_and_irritants() {
+    let (mut heap, output) = heap_with_output();
+    eval(
+        &mut heap,
+        "(define (show e)
+           (write (list (error-object? e) (error-object-message e) (error-object-irritants e))))
+         (with-exception-handler show (lambda () (error \"bad thing:\" 1 'two \"three\")))",
+    );
+    assert_eq!(output.take(), "(#t \"bad thing:\" (1 TWO \"three\"))");
+    // other errors arrive as error objects with their message and no irritants
+    eval(
+        &mut heap,
+        "(with-exception-handler show (lambda () (first 5)))",
+    );
+    assert_eq!(
+        output.take(),
+        "(#t \"type error: expected pair, got integer\" ())"
+    );
+    assert_eq!(eval(&mut heap, "(error-object? 'x)"), "#f");
+    assert_eq!(
+        eval(&mut heap, "(error-object-message 5)"),
+        "err: type error: expected error object, got integer"
+    );
+    assert_eq!(
+        eval(&mut heap, "(error \"bad thing:\" 1 'two \"three\")"),
+        "err: bad thing: 1 TWO \"three\""
+    );
+    assert_eq!(
+        eval(&mut heap, "(error 'sym 1)"),
+        "err: type error: expected string, got symbol"
+    );
+}
+
+#[test]
+fn nested_repl_over_a_string_port() {
+    let mut heap = Heap::new();
+    assert_eq!(
+        eval(
+            &mut heap,
+            r#"(define in (open-input-string "(define x 2) (* x 21) (first 1) \"s\" ("))
+               (with-output-to-string
+                 (lambda () (parameterize ((current-input-port in)) (repl))))"#
+        ),
+        r#""42\nerr: type error: expected pair, got integer\n\"s\"\nerr: read error: unexpected end of input\n""#
+    );
+    // it evaluates in the interaction environment unless given another
+    assert_eq!(eval(&mut heap, "x"), "2");
+    assert_eq!(
+        eval(
+            &mut heap,
+            r#"(define in (open-input-string "(define y 2) y"))
+               (define (f)
+                 (cond (#t (define y 1)
+                           (parameterize ((current-input-port in)) (repl (the-environment))))))
+               (with-output-to-string f)"#
+        ),
+        r#""2\n""#
+    );
+    assert_eq!(eval(&mut heap, "y"), "err: unbound symbol: Y");
+    // exiting ends the whole program, not just the nested repl
+    assert_eq!(
+        eval(
+            &mut heap,
+            r#"(define in (open-input-string "(exit 4) 5"))
+               (parameterize ((current-input-port in)) (repl))"#
+        ),
+        "err: exit with status 4"
+    );
+}
+
+#[test]
+fn list_primitives_handle_long_lists() {
+    // long enough that building any of these by recursion would overflow the stack
+    let mut heap = Heap::new();
+    assert_eq!(
+        eval(
+            &mut heap,
+            "(define l (map (lambda (x) (* x 2)) (iota 200000))) (fold + 0 l)"
+        ),
+        "39999800000"
+    );
+    assert_eq!(
+        eval(&mut heap, "(last (filter (lambda (x) (> x 10)) l))"),
+        "399998"
+    );
+    assert_eq!(
+        eval(
+            &mut heap,
+            "(define n 0) (for-each (lambda (x) (set! n (+ n 1))) l) n"
+        ),
+        "200000"
+    );
+}
+
+#[test]
+fn iota_counts_with_start_and_step() {
+    assert_eq!(run("(iota 5)"), "(0 1 2 3 4)");
+    assert_eq!(run("(iota 3 10 -5)"), "(10 5 0)");
+    assert_eq!(run("(iota 0 1)"), "()");
+    assert_eq!(
+        run("(iota 2 9223372036854775806)"),
+        "(9223372036854775806 9223372036854775807)"
+    );
+    assert_eq!(run("(iota 3 9223372036854775806)"), "err: integer overflow");
+    assert_eq!(
+        run("(iota 2 -9223372036854775807 -1)"),
+        "(-9223372036854775807 -9223372036854775808)"
+    );
+}
+
+#[test]
+fn vector_binary_search() {
+    let mut heap = Heap::new();
+    eval(
+        &mut heap,
+        "(define v #(1 3 5 7 9)) (define (cmp a b) (- a b))",
+    );
+    assert_eq!(eval(&mut heap, "(vector-binary-search v 7 cmp)"), "3");
+    assert_eq!(eval(&mut heap, "(vector-binary-search v 1 cmp)"), "0");
+    assert_eq!(eval(&mut heap, "(vector-binary-search v 9 cmp)"), "4");
+    assert_eq!(eval(&mut heap, "(vector-binary-search v 4 cmp)"), "#f");
+    assert_eq!(eval(&mut heap, "(vector-binary-search v 10 cmp)"), "#f");
+    assert_eq!(eval(&mut heap, "(vector-binary-search #() 1 cmp)"), "#f");
+    assert_eq!(
+        eval(&mut heap, "(vector-binary-search v 1 (lambda (a b) 'x))"),
+        "err: type error: expected integer, got symbol"
+    );
+}
+
+#[test]
+fn hash_table_update_counts_words() {
+    assert_eq!(
+        run("(define t (make-hash-table))
+             (for-each
+               (lambda (w) (hash-table-update! t w (lambda (n) (+ n 1)) (lambda () 0)))
+               '(a b a c b a))
+             (sort (hash-table->alist t) (lambda (x y) (> (rest x) (rest y))))"),
+        "((A . 3) (B . 2) (C . 1))"
+    );
+    // the default thunk is only called for a missing key
+    assert_eq!(
+        run("(define t (make-hash-table))
+             (hash-table-set! t 'k 10)
+             (hash-table-update! t 'k (lambda (n) (* n 2)) (lambda () (error \"called\")))
+             (hash-table->alist t)"),
+        "((K . 20))"
+    );
+    assert_eq!(
+        run("(hash-table-update! (make-hash-table) 'k (lambda (n) n))"),
+        "err: wrong number of arguments: expected 4, got 3"
+    );
+}
+
+#[test]
+fn memoize_runs_once_per_argument_list() {
+    let mut heap = Heap::new();
+    eval(
+        &mut heap,
+        "(define calls 0)
+         (define slow-add (memoize (lambda (a b) (cond (#t (set! calls (+ calls 1)) (+ a b))))))",
+    );
+    assert_eq!(
+        eval(
+            &mut heap,
+            "(list (slow-add 1 2) (slow-add 1 2) (slow-add 2 1))"
+        ),
+        "(3 3 3)"
+    );
+    assert_eq!(eval(&mut heap, "calls"), "2");
+    // argument lists are compared with equal?
+    eval(
+        &mut heap,
+        "(define total (memoize (lambda (l) (cond (#t (set! calls (+ calls 1)) (fold + 0 l))))))",
+    );
+    assert_eq!(
+        eval(&mut heap, "(list (total (list 1 2)) (total (list 1 2)))"),
+        "(3 3)"
+    );
+    assert_eq!(eval(&mut heap, "calls"), "3");
+    assert_eq!(
+        eval(&mut heap, "(memoize 5)"),
+        "err: type error: expected procedure, got integer"
+    );
+    assert_eq!(heap.format_definitions().unwrap(), "(DEFINE CALLS '3)\n");
+}
+
+#[test]
+fn arithmetic_with_one_argument_and_overflow() {
+    assert_eq!(run("(- 5)"), "-5");
+    assert_eq!(run("(+ 5)"), "5");
+    assert_eq!(run("(* 5)"), "5");
+    assert_eq!(run("(/ 5)"), "err: result is not an integer");
+    assert_eq!(run("(/ -1)"), "-1");
+    assert_eq!(run("(/ 0)"), "err: division by zero");
+    assert_eq!(run("(list (+) (*))"), "(0 1)");
+    // results that don't fit in an integer are errors rather than wrapping around
+    assert_eq!(run("(* 4611686018427387904 2)"), "err: integer overflow");
+    assert_eq!(run("(- -9223372036854775807 1 1)"), "err: integer overflow");
+    assert_eq!(run("(+ 9223372036854775807 1)"), "err: integer overflow");
+    assert_eq!(
+        run("(- (- -9223372036854775807 1))"),
+        "err: integer overflow"
+    );
+    assert_eq!(run("(- -9223372036854775807 1)"), "-9223372036854775808");
+}
+
+#[test]
+fn numeric_predicates() {
+    let mut heap = Heap::new();
+    for (predicate, expected) in [
+        ("exact-nonnegative-integer?", "(#t #t #f #f #f #f)"),
+        ("exact-integer?", "(#t #t #t #f #f #f)"),
+        ("integer?", "(#t #t #t #f #f #f)"),
+        ("rational?", "(#t #t #t #f #f #f)"),
+        ("real?", "(#t #t #t #f #f #f)"),
+        ("complex?", "(#t #t #t #f #f #f)"),
+        ("number?", "(#t #t #t #f #f #f)"),
+        ("finite?", "(#t #t #t #f #f #f)"),
+        ("nan?", "(#f #f #f #f #f #f)"),
+        ("infinite?", "(#f #f #f #f #f #f)"),
+    ] {
+        let source = format!("(map {} (list 5 0 -3 'a \"s\" '()))", predicate);
+        assert_eq!(eval(&mut heap, &source), expected, "{}", predicate);
+    }
+}
+
+#[test]
+fn r7rs_division_names() {
+    let mut heap = Heap::new();
+    for (n, d) in [(7, 2), (-7, 2), (7, -2), (-7, -2), (6, 3), (-6, 3)] {
+        let source = format!(
+            "(list (= (floor-remainder {0} {1}) (modulo {0} {1}))
+                   (= (truncate-remainder {0} {1}) (remainder {0} {1}))
+                   (= (truncate-quotient {0} {1}) (quotient {0} {1})))",
+            n, d
+        );
+        assert_eq!(eval(&mut heap, &source), "(#t #t #t)", "{} {}", n, d);
+    }
+    assert_eq!(
+        eval(
+            &mut heap,
+            "(list (floor-quotient -7 2) (truncate-quotient -7 2))"
+        ),
+        "(-4 -3)"
+    );
+    // the most negative integer divided by -1 doesn't fit, though the remainder does
+    eval(
+        &mut heap,
+        "(define most-negative (- -9223372036854775807 1))",
+    );
+    for op in [
+        "truncate-quotient",
+        "floor-quotient",
+        "quotient",
+        "/",
+        "floor/",
+        "truncate/",
+        "euclidean/",
+    ] {
+        assert_eq!(
+            eval(&mut heap, &format!("({} most-negative -1)", op)),
+            "err: integer overflow",
+            "{}",
+            op
+        );
+    }
+    for op in [
+        "truncate-remainder",
+        "floor-remainder",
+        "remainder",
+        "modulo",
+    ] {
+        assert_eq!(
+            eval(&mut heap, &format!("({} most-negative -1)", op)),
+            "0",
+            "{}",
+            op
+        );
+    }
+    assert_eq!(
+        eval(
+            &mut heap,
+            "(list (floor-quotient most-negative 3) (modulo most-negative 3))"
+        ),
+        "(-3074457345618258603 1)"
+    );
+}
@@ -0,0 +1,97 @@
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+// runs the interpreter with the given arguments, piping input to it
+fn scheme(args: &[&str], input: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_scheme"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .unwrap();
+    child.wait_with_output().unwrap()
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8(output.stdout.clone()).unwrap()
+}
+
+#[test]
+fn piped_input_is_quiet() {
+    let output = scheme(&[], "(define x 2)\n(* x 21)\n(display \"hi\")\n");
+    assert_eq!(stdout(&output), "42\nhi");
+    let output = scheme(&["--verbose"], "(define x 2)\n(* x 21)\n");
+    assert_eq!(
+        stdout(&output),
+        "in:  (DEFINE X 2)\nin:  (* X 21)\nout: 42\n"
+    );
+    let output = scheme(&[], ":verbose\n(+ 1 2)\n:quiet\n(+ 3 4)\n:prompt >> \n5\n");
+    assert_eq!(
+        stdout(&output),
+        "ok\nin:  (+ 1 2)\nout: 3\nok\n7\nok\n>> 5\n>> "
+    );
+}
+
+// writes a program to a file of its own in the temporary directory
+fn script(name: &str, source: &str) -> String {
+    let path =
+        std::env::temp_dir().join(format!("scheme-test-{}-{}.scm", std::process::id(), name));
+    std::fs::write(&path, source).unwrap();
+    path.to_str().unwrap().to_owned()
+}
+
+#[test]
+fn runs_a_program_file() {
+    let path = script(
+        "program",
+        r#"(define (square x)
+             (* x x))
+           (display (square 7))
+           (newline)
+           (display
+             "done")"#,
+    );
+    let output = scheme(&[&path], "");
+    assert_eq!(stdout(&output), "49\ndone");
+    assert!(output.status.success());
+    std::fs::remove_file(path).unwrap();
+    let path = script("error", "(display 1)\n(car '())\n(display 2)\n");
+    let output = scheme(&[&path], "");
+    assert_eq!(stdout(&output), "1");
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "err: unbound symbol: CAR\n"
+    );
+    assert_eq!(output.status.code(), Some(1));
+    std::fs::remove_file(path).unwrap();
+    let output = scheme(&["-"], "(display (+ 1\n 2))\n(exit 3)");
+    assert_eq!(stdout(&output), "3");
+    assert_eq!(output.status.code(), Some(3));
+}
+
+#[test]
+fn command_line_arguments() {
+    let path = script("args", "(write (rest (command-line)))");
+    let output = scheme(&[&path, "one", "--two"], "");
+    assert_eq!(stdout(&output), r#"("one" "--two")"#);
+    std::fs::remove_file(path).unwrap();
+    let output = scheme(&["-", "x"], "(write (command-line))");
+    assert_eq!(stdout(&output), r#"("-" "x")"#);
+}
+
+#[test]
+fn exit_ends_a_run() {
+    let output = scheme(&["-"], "(display 1)\n(exit 0)\n(display 2)\n");
+    assert_eq!(stdout(&output), "1");
+    assert!(output.status.success());
+    let output = scheme(&[], "(display 1)\n(exit 5)\n(display 2)\n");
+    assert_eq!(stdout(&output), "1");
+    assert_eq!(output.status.code(), Some(5));
+}